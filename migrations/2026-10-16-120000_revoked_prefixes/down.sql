@@ -0,0 +1 @@
+DROP TABLE revoked_prefixes;
\ No newline at end of file
@@ -0,0 +1,4 @@
+CREATE TABLE revoked_prefixes (
+    prefix TEXT NOT NULL PRIMARY KEY,
+    revoked_at TIMESTAMP NOT NULL
+);
\ No newline at end of file
@@ -0,0 +1,2 @@
+ALTER TABLE tokens DROP COLUMN parent_id;
+ALTER TABLE tokens DROP COLUMN claims_hash;
\ No newline at end of file
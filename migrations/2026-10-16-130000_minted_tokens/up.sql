@@ -0,0 +1,2 @@
+ALTER TABLE tokens ADD COLUMN claims_hash TEXT;
+ALTER TABLE tokens ADD COLUMN parent_id TEXT;
\ No newline at end of file
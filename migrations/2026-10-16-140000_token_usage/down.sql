@@ -0,0 +1 @@
+DROP TABLE token_usage;
\ No newline at end of file
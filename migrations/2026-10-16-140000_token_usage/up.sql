@@ -0,0 +1,5 @@
+CREATE TABLE token_usage (
+    token_id TEXT NOT NULL PRIMARY KEY,
+    uploaded_bytes BIGINT NOT NULL DEFAULT 0,
+    created_builds BIGINT NOT NULL DEFAULT 0
+);
\ No newline at end of file
@@ -0,0 +1 @@
+ALTER TABLE tokens DROP COLUMN full_token;
\ No newline at end of file
@@ -0,0 +1 @@
+ALTER TABLE tokens ADD COLUMN full_token TEXT;
\ No newline at end of file
@@ -0,0 +1,13 @@
+CREATE TABLE audit_log (
+    id SERIAL PRIMARY KEY,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    token_sub TEXT,
+    token_jti TEXT,
+    scopes TEXT[] NOT NULL,
+    client_ip TEXT,
+    action TEXT NOT NULL,
+    path TEXT NOT NULL,
+    outcome SMALLINT NOT NULL
+);
+CREATE INDEX audit_log_created_at ON audit_log (created_at);
+CREATE INDEX audit_log_token_sub ON audit_log (token_sub);
@@ -0,0 +1,4 @@
+CREATE TABLE revoked_subs (
+    sub_prefix TEXT NOT NULL PRIMARY KEY,
+    revoked_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,2 @@
+ALTER TABLE tokens ADD COLUMN last_used_ip TEXT;
+ALTER TABLE tokens ADD COLUMN use_count BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+ALTER TABLE tokens ADD COLUMN consumed_at TIMESTAMP;
@@ -0,0 +1,5 @@
+CREATE TABLE prefix_owners (
+    prefix TEXT NOT NULL PRIMARY KEY,
+    owner_group TEXT NOT NULL,
+    updated_at TIMESTAMP NOT NULL DEFAULT now()
+);
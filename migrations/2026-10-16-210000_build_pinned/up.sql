@@ -0,0 +1 @@
+ALTER TABLE builds ADD COLUMN pinned BOOLEAN NOT NULL DEFAULT false;
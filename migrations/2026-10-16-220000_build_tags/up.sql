@@ -0,0 +1,7 @@
+CREATE TABLE build_tags (
+    build_id INTEGER NOT NULL REFERENCES builds (id) ON DELETE CASCADE,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (build_id, key)
+);
+CREATE INDEX build_tags_key_value ON build_tags (key, value);
@@ -0,0 +1 @@
+ALTER TABLE build_refs DROP COLUMN held;
@@ -0,0 +1 @@
+ALTER TABLE build_refs ADD COLUMN held BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1 @@
+ALTER TABLE builds DROP COLUMN uploaded_bytes;
@@ -0,0 +1 @@
+ALTER TABLE builds ADD COLUMN uploaded_bytes BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,8 @@
+ALTER TABLE builds ADD COLUMN required_approvals INTEGER NOT NULL DEFAULT 0;
+CREATE TABLE build_approvals (
+    build_id INTEGER NOT NULL REFERENCES builds (id) ON DELETE CASCADE,
+    approver TEXT NOT NULL,
+    comment TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    PRIMARY KEY (build_id, approver)
+);
@@ -0,0 +1 @@
+ALTER TABLE builds DROP COLUMN import_job_id;
@@ -0,0 +1 @@
+ALTER TABLE builds ADD COLUMN import_job_id INTEGER REFERENCES jobs (id);
@@ -0,0 +1,6 @@
+ALTER TABLE jobs ADD COLUMN priority INTEGER NOT NULL DEFAULT 0;
+
+-- The defaults of JobKind::default_priority(), for jobs that are still queued
+UPDATE jobs SET priority = 30 WHERE kind IN (1, 3);
+UPDATE jobs SET priority = 20 WHERE kind IN (0, 2, 4, 7);
+UPDATE jobs SET priority = 10 WHERE kind = 5;
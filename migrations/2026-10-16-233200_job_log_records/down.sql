@@ -0,0 +1 @@
+DROP TABLE job_log_records;
@@ -0,0 +1,9 @@
+CREATE TABLE job_log_records (
+    id SERIAL PRIMARY KEY,
+    job_id INTEGER NOT NULL REFERENCES jobs (id) ON DELETE CASCADE,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    level TEXT NOT NULL,
+    phase TEXT,
+    message TEXT NOT NULL
+);
+CREATE INDEX job_log_records_job_id ON job_log_records (job_id, id);
@@ -0,0 +1,7 @@
+CREATE TABLE job_schedules (
+    repo TEXT NOT NULL,
+    name TEXT NOT NULL,
+    next_run TIMESTAMP NOT NULL,
+    last_job_id INTEGER REFERENCES jobs (id) ON DELETE SET NULL,
+    PRIMARY KEY (repo, name)
+);
@@ -0,0 +1,6 @@
+DROP INDEX jobs_finished_at;
+ALTER TABLE jobs DROP COLUMN bytes_written;
+ALTER TABLE jobs DROP COLUMN peak_rss_kb;
+ALTER TABLE jobs DROP COLUMN cpu_time_ms;
+ALTER TABLE jobs DROP COLUMN wall_time_ms;
+ALTER TABLE jobs DROP COLUMN finished_at;
@@ -0,0 +1,6 @@
+ALTER TABLE jobs ADD COLUMN finished_at TIMESTAMP;
+ALTER TABLE jobs ADD COLUMN wall_time_ms BIGINT;
+ALTER TABLE jobs ADD COLUMN cpu_time_ms BIGINT;
+ALTER TABLE jobs ADD COLUMN peak_rss_kb BIGINT;
+ALTER TABLE jobs ADD COLUMN bytes_written BIGINT;
+CREATE INDEX jobs_finished_at ON jobs (finished_at);
@@ -0,0 +1,3 @@
+DROP INDEX jobs_worker_id;
+ALTER TABLE jobs DROP COLUMN worker_id;
+DROP TABLE job_workers;
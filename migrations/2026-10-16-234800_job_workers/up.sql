@@ -0,0 +1,9 @@
+CREATE TABLE job_workers (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    kinds SMALLINT[] NOT NULL,
+    registered_at TIMESTAMP NOT NULL DEFAULT now(),
+    last_seen TIMESTAMP NOT NULL DEFAULT now()
+);
+ALTER TABLE jobs ADD COLUMN worker_id INTEGER REFERENCES job_workers (id) ON DELETE SET NULL;
+CREATE INDEX jobs_worker_id ON jobs (worker_id);
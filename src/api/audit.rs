@@ -0,0 +1,34 @@
+use actix::prelude::*;
+use actix_web::web::{Data, Query};
+use actix_web::{HttpRequest, HttpResponse, Result};
+use futures3::TryFutureExt;
+
+use crate::db::Db;
+use crate::errors::ApiError;
+use crate::models::AuditLogFilter;
+use crate::tokens::{ClaimsValidator, Endpoint};
+
+/* At most this many entries are returned at once, page with until= for more */
+const MAX_AUDIT_ENTRIES: i64 = 1000;
+
+pub fn audit_log(
+    query: Query<AuditLogFilter>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    Box::pin(audit_log_async(query, db, req)).compat()
+}
+
+async fn audit_log_async(
+    query: Query<AuditLogFilter>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    req.has_token_for_endpoint(Endpoint::AuditLog, "")?;
+
+    let entries = db
+        .list_audit_entries(query.into_inner(), MAX_AUDIT_ENTRIES)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(entries))
+}
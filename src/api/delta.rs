@@ -1,19 +1,25 @@
 use actix::prelude::*;
 use actix_multipart::Multipart;
-use actix_web::web::{Data, Path};
+use actix_web::web::{Data, Json, Path};
 use actix_web::{web, HttpRequest, HttpResponse, ResponseError, Result};
 use actix_web_actors::ws;
 
 use futures::future::Future;
+use futures3::TryFutureExt;
 use serde::Deserialize;
+use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
 
 use crate::config::Config;
+use crate::db::Db;
 use crate::deltas::{DeltaGenerator, RemoteWorker};
 use crate::errors::ApiError;
-use crate::tokens::{ClaimsScope, ClaimsValidator};
+use crate::jobs::{JobQueue, ProcessJobs};
+use crate::models::PruneDeltasJob;
+use crate::net::request_client_ip;
+use crate::tokens::{add_authorization_trailers, ClaimsValidator, Endpoint};
 
-use super::utils::{save_file, UploadState};
+use super::utils::{respond_with_url, save_file, UploadState};
 
 #[derive(Deserialize)]
 pub struct DeltaUploadParams {
@@ -26,39 +32,185 @@ pub fn delta_upload(
     req: HttpRequest,
     config: Data<Config>,
 ) -> impl Future<Item = HttpResponse, Error = ApiError> {
-    futures::done(req.has_token_claims("delta", ClaimsScope::Generate))
+    futures::done(req.has_token_for_endpoint(Endpoint::UploadDelta, "delta"))
         .and_then(move |_| futures::done(config.get_repoconfig(&params.repo).cloned()))
         .and_then(move |repoconfig| {
             let uploadstate = Arc::new(UploadState {
                 only_deltas: true,
                 repo_path: repoconfig.get_abs_repo_path(),
+                max_bytes: None,
+                uploaded_bytes: AtomicU64::new(0),
+                require_checksums: false,
+                fsync: repoconfig.upload_fsync,
+                in_session: false,
             });
+            let sync_state = uploadstate.clone();
             multipart
                 .map_err(|e| ApiError::InternalServerError(e.to_string()))
                 .map(move |field| save_file(field, &uploadstate).into_stream())
                 .flatten()
                 .collect()
-                .map(|sizes| HttpResponse::Ok().json(sizes))
+                .and_then(move |sizes| sync_state.sync_batch().map(|_| sizes))
+                .map(move |sizes| {
+                    let mut response = HttpResponse::Ok().json(sizes);
+                    add_authorization_trailers(&req, &mut response);
+                    response
+                })
         })
 }
 
+#[derive(Deserialize)]
+pub struct PruneDeltasParams {
+    repo: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PruneDeltasArgs {
+    older_than_days: Option<u32>,
+    keep_commits: Option<u32>,
+}
+
+/* Checks the token and turns the request into the job to queue */
+fn prune_deltas_job(
+    req: &HttpRequest,
+    repo: &str,
+    args: &PruneDeltasArgs,
+) -> Result<PruneDeltasJob, ApiError> {
+    req.has_token_for_endpoint(Endpoint::PruneDeltas, "delta")?;
+    req.has_token_repo(repo)?;
+
+    if args.older_than_days.is_none() && args.keep_commits.is_none() {
+        return Err(ApiError::BadRequest(
+            "Either older_than_days or keep_commits is required".to_string(),
+        ));
+    }
+    if args.keep_commits == Some(0) {
+        return Err(ApiError::BadRequest(
+            "keep_commits must be at least 1".to_string(),
+        ));
+    }
+
+    Ok(PruneDeltasJob {
+        older_than_days: args.older_than_days,
+        keep_commits: args.keep_commits,
+    })
+}
+
+pub fn prune_deltas(
+    args: Json<PruneDeltasArgs>,
+    params: Path<PruneDeltasParams>,
+    config: Data<Config>,
+    job_queue: Data<Addr<JobQueue>>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    Box::pin(prune_deltas_async(args, params, config, job_queue, db, req)).compat()
+}
+
+async fn prune_deltas_async(
+    args: Json<PruneDeltasArgs>,
+    params: Path<PruneDeltasParams>,
+    config: Data<Config>,
+    job_queue: Data<Addr<JobQueue>>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let prune = prune_deltas_job(&req, &params.repo, &args)?;
+    config.get_repoconfig(&params.repo)?; // Ensure the repo exists
+
+    let job = db
+        .start_prune_deltas_job(params.repo.clone(), prune)
+        .await?;
+    job_queue.do_send(ProcessJobs(Some(params.repo.clone())));
+
+    respond_with_url(&job, &req, "show_job", &[job.id.to_string()])
+}
+
 pub fn ws_delta(
     req: HttpRequest,
     config: Data<Config>,
     delta_generator: Data<Addr<DeltaGenerator>>,
     stream: web::Payload,
 ) -> Result<HttpResponse, actix_web::Error> {
-    if let Err(e) = req.has_token_claims("delta", ClaimsScope::Generate) {
+    if let Err(e) = req.has_token_for_endpoint(Endpoint::DeltaWorker, "delta") {
         return Ok(e.error_response());
     }
-    let remote = req
-        .connection_info()
-        .remote()
-        .unwrap_or("Unknown")
-        .to_string();
+    let remote = request_client_ip(req.peer_addr(), req.headers(), &config.trusted_proxies)
+        .map(|ip| ip.to_string())
+        .unwrap_or_else(|| "Unknown".to_string());
     ws::start(
         RemoteWorker::new(&config, &delta_generator, remote),
         &req,
         stream,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokens::{Claims, ClaimsScope};
+    use actix_web::test::TestRequest;
+
+    fn request(scope: ClaimsScope, repos: &[&str]) -> HttpRequest {
+        let req = TestRequest::default().to_http_request();
+        req.extensions_mut().insert(Claims {
+            name: None,
+            sub: "delta".to_string(),
+            exp: i64::MAX,
+            iat: None,
+            jti: None,
+            ver: 0,
+            scope: vec![scope],
+            prefixes: vec![],
+            apps: vec![],
+            repos: repos.iter().map(|s| s.to_string()).collect(),
+            branches: vec![],
+            token_type: None,
+            max_upload_bytes: None,
+            max_requests_per_minute: None,
+            allowed_hours: vec![],
+            byte_budget: None,
+            build_budget: None,
+            jobs_metadata_only: false,
+            purpose: None,
+            allowed_ips: vec![],
+            single_use: false,
+            groups: vec![],
+        });
+        req
+    }
+
+    #[test]
+    fn test_prune_deltas_job() {
+        let args = PruneDeltasArgs {
+            older_than_days: Some(30),
+            keep_commits: Some(3),
+        };
+
+        let req = request(ClaimsScope::Generate, &["stable"]);
+        assert_eq!(
+            prune_deltas_job(&req, "stable", &args).unwrap(),
+            PruneDeltasJob {
+                older_than_days: Some(30),
+                keep_commits: Some(3),
+            }
+        );
+        assert!(prune_deltas_job(&req, "beta", &args).is_err());
+
+        let req = request(ClaimsScope::Upload, &["stable"]);
+        assert!(prune_deltas_job(&req, "stable", &args).is_err());
+
+        /* Something to prune by is needed */
+        let req = request(ClaimsScope::Generate, &[""]);
+        let nothing = PruneDeltasArgs {
+            older_than_days: None,
+            keep_commits: None,
+        };
+        assert!(prune_deltas_job(&req, "stable", &nothing).is_err());
+        let keep_none = PruneDeltasArgs {
+            older_than_days: None,
+            keep_commits: Some(0),
+        };
+        assert!(prune_deltas_job(&req, "stable", &keep_none).is_err());
+    }
+}
@@ -0,0 +1,284 @@
+use actix::prelude::*;
+use actix_web::http::header::{HeaderName, HeaderValue, CACHE_CONTROL};
+use actix_web::web::{Data, Path, Query};
+use actix_web::{HttpRequest, HttpResponse, Result};
+use bytes::Bytes;
+use futures3::compat::Future01CompatExt;
+use futures3::{TryFutureExt, TryStreamExt};
+use serde::Deserialize;
+use serde_json::json;
+use std::fmt::Write as _;
+use std::time::{Duration, Instant};
+
+use crate::db::Db;
+use crate::errors::ApiError;
+use crate::models::{JobLogRecord, JobProgress, JobStatus};
+use crate::tokens::{ClaimsValidator, Endpoint};
+
+use super::build::JobPathParams;
+
+/* At most this many records are returned at once, or sent in one event batch when following */
+const LOG_PAGE_SIZE: i64 = 1000;
+
+/* How often a followed log is checked for new records */
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/* A comment is sent after this many polls without new records, so proxies don't close the connection */
+const FOLLOW_KEEPALIVE_POLLS: u32 = 15;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct JobLogArgs {
+    /* The id of the last record already seen, like the x-next-cursor of the previous page */
+    since: Option<i32>,
+    /* Stream the log as server-sent events until the job is done */
+    #[serde(default)]
+    follow: bool,
+}
+
+/// The log records of a job, along with its progress. Without follow, a page of records after the since cursor is
+/// returned, with the cursor of the next page in x-next-cursor. With follow, records are streamed as server-sent
+/// events as they are logged, with a "progress" event whenever the progress changes, ending with an "end" event once
+/// the job is done.
+pub fn get_job_log(
+    args: Query<JobLogArgs>,
+    params: Path<JobPathParams>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    Box::pin(get_job_log_async(args, params, db, req)).compat()
+}
+
+async fn get_job_log_async(
+    args: Query<JobLogArgs>,
+    params: Path<JobPathParams>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    req.has_token_for_endpoint(Endpoint::GetJob, "build")?;
+    if let Some(claims) = req.get_claims() {
+        if claims.jobs_metadata_only {
+            return Err(ApiError::NotEnoughPermissions(
+                "Token only allows seeing the status of jobs, not their logs".to_string(),
+            ));
+        }
+    }
+
+    /* Reconnecting event sources say where they left off in a header */
+    let since = args.since.or_else(|| {
+        req.headers()
+            .get("last-event-id")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+    });
+
+    if args.follow {
+        /* Fails early for jobs that don't exist */
+        db.lookup_job_log(params.id, since, 0).await?;
+        let job_id = params.id;
+        let lookup = move |cursor| {
+            let db = db.clone();
+            async move { db.lookup_job_log(job_id, cursor, LOG_PAGE_SIZE).await }
+        };
+        return Ok(HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .header(CACHE_CONTROL, "no-cache")
+            .streaming(Box::pin(follow_job_log(lookup, since, FOLLOW_POLL_INTERVAL)).compat()));
+    }
+
+    let (status, progress, records) = db.lookup_job_log(params.id, since, LOG_PAGE_SIZE).await?;
+    let next_cursor = records.last().map(|record| record.id);
+    let progress =
+        progress.and_then(|progress| serde_json::from_str::<JobProgress>(&progress).ok());
+    let mut response = HttpResponse::Ok().json(json!({
+        "status": status,
+        "progress": progress,
+        "records": records,
+    }));
+    if let Some(next_cursor) = next_cursor {
+        response.headers_mut().insert(
+            HeaderName::from_static("x-next-cursor"),
+            HeaderValue::from(next_cursor),
+        );
+    }
+    Ok(response)
+}
+
+fn log_events(records: &[JobLogRecord]) -> String {
+    let mut events = String::new();
+    for record in records {
+        let data = serde_json::to_string(record).unwrap_or_default();
+        write!(events, "id: {}\ndata: {data}\n\n", record.id).unwrap();
+    }
+    events
+}
+
+fn progress_event(progress: &str) -> String {
+    format!("event: progress\ndata: {progress}\n\n")
+}
+
+/* The status and progress of a job, with its log records after a cursor */
+type JobLogPage = (i16, Option<String>, Vec<JobLogRecord>);
+
+struct FollowState<L> {
+    lookup: L,
+    poll_interval: Duration,
+    cursor: Option<i32>,
+    /* The progress last sent */
+    progress: Option<String>,
+    idle_polls: u32,
+    done: bool,
+}
+
+/* Streams the log records that `lookup` finds after the cursor it is given, polling for more until the job is done.
+ * The wait between polls is on the timer of the actix runtime the handlers run on. */
+fn follow_job_log<L, F>(
+    lookup: L,
+    since: Option<i32>,
+    poll_interval: Duration,
+) -> impl futures3::Stream<Item = Result<Bytes, ApiError>>
+where
+    L: Fn(Option<i32>) -> F,
+    F: std::future::Future<Output = Result<JobLogPage, ApiError>>,
+{
+    let state = FollowState {
+        lookup,
+        poll_interval,
+        cursor: since,
+        progress: None,
+        idle_polls: 0,
+        done: false,
+    };
+    futures3::stream::unfold(state, |mut state| async move {
+        if state.done {
+            return None;
+        }
+        loop {
+            let (status, progress, records) = match (state.lookup)(state.cursor).await {
+                Ok(result) => result,
+                Err(e) => {
+                    state.done = true;
+                    return Some((Err(e), state));
+                }
+            };
+
+            let mut events = log_events(&records);
+            if let Some(last) = records.last() {
+                state.cursor = Some(last.id);
+            }
+            if let Some(progress) = progress {
+                if state.progress.as_ref() != Some(&progress) {
+                    events.push_str(&progress_event(&progress));
+                    state.progress = Some(progress);
+                }
+            }
+            if !events.is_empty() {
+                state.idle_polls = 0;
+                return Some((Ok(Bytes::from(events)), state));
+            }
+
+            if status >= JobStatus::Ended as i16 {
+                state.done = true;
+                let end = format!("event: end\ndata: {}\n\n", json!({ "status": status }));
+                return Some((Ok(Bytes::from(end)), state));
+            }
+
+            let delay = actix::clock::Delay::new(Instant::now() + state.poll_interval);
+            if let Err(e) = delay.compat().await {
+                state.done = true;
+                return Some((Err(ApiError::InternalServerError(e.to_string())), state));
+            }
+            state.idle_polls += 1;
+            if state.idle_polls % FOLLOW_KEEPALIVE_POLLS == 0 {
+                return Some((Ok(Bytes::from(":\n\n")), state));
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures3::{FutureExt, StreamExt};
+    use std::cell::RefCell;
+
+    #[test]
+    fn test_log_events() {
+        let record = |id, phase: Option<&str>, message: &str| JobLogRecord {
+            id,
+            job_id: 1,
+            created_at: chrono::NaiveDate::from_ymd_opt(2026, 10, 16)
+                .unwrap()
+                .and_hms_opt(12, 0, 0)
+                .unwrap(),
+            level: "info".to_string(),
+            phase: phase.map(str::to_string),
+            message: message.to_string(),
+        };
+
+        assert_eq!(log_events(&[]), "");
+        assert_eq!(
+            log_events(&[
+                record(3, Some("import"), "Importing build to repo stable"),
+                record(4, None, "Job succeeded"),
+            ]),
+            "id: 3\ndata: {\"id\":3,\"created-at\":\"2026-10-16T12:00:00\",\"level\":\"info\",\
+             \"phase\":\"import\",\"message\":\"Importing build to repo stable\"}\n\n\
+             id: 4\ndata: {\"id\":4,\"created-at\":\"2026-10-16T12:00:00\",\"level\":\"info\",\
+             \"message\":\"Job succeeded\"}\n\n"
+        );
+        assert_eq!(
+            progress_event(r#"{"phase":"deltas","done":1,"total":4,"percent":25}"#),
+            "event: progress\ndata: {\"phase\":\"deltas\",\"done\":1,\"total\":4,\"percent\":25}\n\n"
+        );
+    }
+
+    #[test]
+    fn test_follow_job_log() {
+        let record = |id| JobLogRecord {
+            id,
+            job_id: 1,
+            created_at: chrono::NaiveDate::from_ymd_opt(2026, 10, 16)
+                .unwrap()
+                .and_hms_opt(12, 0, 0)
+                .unwrap(),
+            level: "info".to_string(),
+            phase: None,
+            message: format!("Record {id}"),
+        };
+        let running = JobStatus::Started as i16;
+        let ended = JobStatus::Ended as i16;
+
+        /* Nothing new at first, so the stream has to wait for the next poll */
+        let pages: RefCell<Vec<JobLogPage>> = RefCell::new(vec![
+            (running, None, vec![]),
+            (running, None, vec![record(3)]),
+            (ended, None, vec![]),
+        ]);
+        let cursors = RefCell::new(vec![]);
+        let lookup = |cursor| {
+            cursors.borrow_mut().push(cursor);
+            let page = pages.borrow_mut().remove(0);
+            async move { Ok::<_, ApiError>(page) }
+        };
+
+        let started = Instant::now();
+        let events = follow_job_log(lookup, Some(2), Duration::from_millis(20))
+            .collect::<Vec<_>>()
+            .map(Ok::<_, ()>);
+        let events = actix_web::test::block_on(Box::pin(events).compat()).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(20));
+
+        let events = events
+            .into_iter()
+            .map(|event| String::from_utf8(event.unwrap().to_vec()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 2);
+        assert!(events[0].starts_with("id: 3\n"));
+        assert_eq!(
+            events[1],
+            format!("event: end\ndata: {{\"status\":{ended}}}\n\n")
+        );
+        assert_eq!(*cursors.borrow(), vec![Some(2), Some(2), Some(3)]);
+    }
+}
@@ -0,0 +1,305 @@
+use actix::prelude::*;
+use actix_web::web::{Data, Json, Path, Query};
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use futures3::compat::Future01CompatExt;
+use futures3::TryFutureExt;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::db::Db;
+use crate::errors::ApiError;
+use crate::jobs::{
+    claim_job_for_worker, finish_worker_job, register_worker, remove_worker, worker_heartbeat,
+    JobQueue, ProcessJobs,
+};
+use crate::models::{JobKind, JobOutcome};
+use crate::tokens::{ClaimsValidator, Endpoint};
+
+/* The longest a claim waits for a job to show up */
+const MAX_CLAIM_WAIT_SECS: u64 = 30;
+
+/* How often a waiting claim looks for a job again */
+const CLAIM_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RegisterWorkerArgs {
+    name: String,
+    kinds: Vec<JobKind>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WorkerPathParams {
+    id: i32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WorkerJobPathParams {
+    id: i32,
+    job_id: i32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ClaimJobArgs {
+    /* How many seconds to wait for a job when there is none to claim right away */
+    wait: Option<u64>,
+}
+
+pub fn register(
+    args: Json<RegisterWorkerArgs>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    Box::pin(register_async(args, db, req)).compat()
+}
+
+/* Registers an external worker that runs jobs of the given kinds. Once registered, the executors of the server leave
+ * those kinds to workers for as long as any of them keeps sending heartbeats. */
+async fn register_async(
+    args: Json<RegisterWorkerArgs>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    req.has_token_for_endpoint(Endpoint::JobWorker, "build")?;
+    if args.kinds.is_empty() {
+        return Err(ApiError::BadRequest(
+            "A worker has to run at least one kind of job".to_string(),
+        ));
+    }
+
+    let args = args.into_inner();
+    let worker = web::block(move || {
+        let mut conn = db.0.get()?;
+        Ok::<_, ApiError>(register_worker(&mut conn, &args.name, &args.kinds)?)
+    })
+    .compat()
+    .await?;
+    Ok(HttpResponse::Ok().json(worker))
+}
+
+pub fn claim(
+    args: Query<ClaimJobArgs>,
+    params: Path<WorkerPathParams>,
+    db: Data<Db>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    Box::pin(claim_async(args, params, db, config, req)).compat()
+}
+
+/* Claims the next job for a worker. Without a job to claim, this waits for one for up to the given number of
+ * seconds, then answers 204. Claiming counts as a heartbeat. */
+async fn claim_async(
+    args: Query<ClaimJobArgs>,
+    params: Path<WorkerPathParams>,
+    db: Data<Db>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    req.has_token_for_endpoint(Endpoint::JobWorker, "build")?;
+
+    let wait = Duration::from_secs(args.wait.unwrap_or(0).min(MAX_CLAIM_WAIT_SECS));
+    let worker_id = params.id;
+    let claim = || {
+        let db = db.clone();
+        let config = config.clone();
+        async move {
+            Ok::<_, ApiError>(
+                web::block(move || {
+                    let mut conn = db.0.get()?;
+                    Ok::<_, ApiError>(claim_job_for_worker(&config, &mut conn, worker_id)?)
+                })
+                .compat()
+                .await?,
+            )
+        }
+    };
+
+    match wait_for_job(claim, wait, CLAIM_POLL_INTERVAL).await? {
+        Some(job) => Ok(HttpResponse::Ok().json(job)),
+        None => Ok(HttpResponse::NoContent().finish()),
+    }
+}
+
+/* Tries to claim a job until one is claimed or the wait is over. The wait between tries is on the timer of the actix
+ * runtime the handlers run on. */
+async fn wait_for_job<C, F, T>(
+    claim: C,
+    wait: Duration,
+    poll_interval: Duration,
+) -> Result<Option<T>, ApiError>
+where
+    C: Fn() -> F,
+    F: std::future::Future<Output = Result<Option<T>, ApiError>>,
+{
+    let started = Instant::now();
+    loop {
+        if let Some(job) = claim().await? {
+            return Ok(Some(job));
+        }
+        if started.elapsed() >= wait {
+            return Ok(None);
+        }
+        actix::clock::Delay::new(Instant::now() + poll_interval)
+            .compat()
+            .await
+            .map_err(|e| ApiError::InternalServerError(e.to_string()))?;
+    }
+}
+
+pub fn heartbeat(
+    params: Path<WorkerPathParams>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    Box::pin(heartbeat_async(params, db, req)).compat()
+}
+
+/* Workers send heartbeats while they run a job. A worker that was removed for missing them gets a 404, and has to
+ * register again. */
+async fn heartbeat_async(
+    params: Path<WorkerPathParams>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    req.has_token_for_endpoint(Endpoint::JobWorker, "build")?;
+    let worker_id = params.id;
+    let worker = web::block(move || {
+        let mut conn = db.0.get()?;
+        Ok::<_, ApiError>(worker_heartbeat(&mut conn, worker_id)?)
+    })
+    .compat()
+    .await?;
+    Ok(HttpResponse::Ok().json(worker))
+}
+
+pub fn finish_job(
+    args: Json<JobOutcome>,
+    params: Path<WorkerJobPathParams>,
+    db: Data<Db>,
+    job_queue: Data<Addr<JobQueue>>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    Box::pin(finish_job_async(args, params, db, job_queue, req)).compat()
+}
+
+/* Stores how a job that a worker ran ended, like the executors of the server do for the jobs they run */
+async fn finish_job_async(
+    args: Json<JobOutcome>,
+    params: Path<WorkerJobPathParams>,
+    db: Data<Db>,
+    job_queue: Data<Addr<JobQueue>>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    req.has_token_for_endpoint(Endpoint::JobWorker, "build")?;
+    let (worker_id, job_id) = (params.id, params.job_id);
+    let outcome = args.into_inner();
+    let job = web::block(move || {
+        let mut conn = db.0.get()?;
+        finish_worker_job(&mut conn, worker_id, job_id, &outcome)
+    })
+    .compat()
+    .await?;
+
+    /* The job may have queued others, or been queued again */
+    job_queue.do_send(ProcessJobs(None));
+    if job.repo.is_some() {
+        job_queue.do_send(ProcessJobs(job.repo.clone()));
+    }
+    Ok(HttpResponse::Ok().json(job))
+}
+
+pub fn unregister(
+    params: Path<WorkerPathParams>,
+    db: Data<Db>,
+    config: Data<Config>,
+    job_queue: Data<Addr<JobQueue>>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    Box::pin(unregister_async(params, db, config, job_queue, req)).compat()
+}
+
+/* Removes a worker that is shutting down. Any jobs it was still running are reclaimed, as if it had gone away. */
+async fn unregister_async(
+    params: Path<WorkerPathParams>,
+    db: Data<Db>,
+    config: Data<Config>,
+    job_queue: Data<Addr<JobQueue>>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    req.has_token_for_endpoint(Endpoint::JobWorker, "build")?;
+    let worker_id = params.id;
+    let repos = web::block(move || {
+        let mut conn = db.0.get()?;
+        remove_worker(&config, &mut conn, worker_id)
+    })
+    .compat()
+    .await?;
+
+    /* Kinds the worker ran may be back with the executors of the server */
+    job_queue.do_send(ProcessJobs(None));
+    for repo in repos.into_iter().flatten() {
+        job_queue.do_send(ProcessJobs(Some(repo)));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures3::FutureExt;
+    use std::cell::Cell;
+
+    fn run<T>(future: impl std::future::Future<Output = T>) -> T {
+        actix_web::test::block_on(Box::pin(future.map(Ok::<_, ()>)).compat()).unwrap()
+    }
+
+    #[test]
+    fn test_wait_for_job() {
+        /* A job that shows up on the third try is claimed after waiting twice */
+        let tries = Cell::new(0);
+        let claim = || {
+            tries.set(tries.get() + 1);
+            let job = (tries.get() == 3).then_some("job");
+            async move { Ok::<_, ApiError>(job) }
+        };
+        let started = Instant::now();
+        let job = run(wait_for_job(
+            claim,
+            Duration::from_secs(5),
+            Duration::from_millis(10),
+        ));
+        assert_eq!(job.unwrap(), Some("job"));
+        assert_eq!(tries.get(), 3);
+        assert!(started.elapsed() >= Duration::from_millis(20));
+
+        /* Without one, the claim gives up once the wait is over */
+        let tries = Cell::new(0);
+        let claim = || {
+            tries.set(tries.get() + 1);
+            async { Ok::<_, ApiError>(None::<&str>) }
+        };
+        let started = Instant::now();
+        let job = run(wait_for_job(
+            claim,
+            Duration::from_millis(50),
+            Duration::from_millis(10),
+        ));
+        assert_eq!(job.unwrap(), None);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert!(tries.get() >= 2);
+
+        /* Nor does it wait at all when not asked to */
+        let tries = Cell::new(0);
+        let claim = || {
+            tries.set(tries.get() + 1);
+            async { Ok::<_, ApiError>(None::<&str>) }
+        };
+        assert_eq!(
+            run(wait_for_job(claim, Duration::ZERO, Duration::from_secs(60))).unwrap(),
+            None
+        );
+        assert_eq!(tries.get(), 1);
+    }
+}
@@ -1,5 +1,10 @@
+pub mod audit;
 pub mod build;
 pub mod delta;
+pub mod job_log;
+pub mod job_worker;
+pub mod prefix_owners;
+pub mod quotas;
 pub mod repo;
 pub mod status;
 pub mod tokens;
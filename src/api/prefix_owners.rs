@@ -0,0 +1,100 @@
+use actix::prelude::*;
+use actix_web::web::{Data, Json};
+use actix_web::{HttpRequest, HttpResponse, Result};
+use futures3::TryFutureExt;
+use serde::Deserialize;
+
+use crate::db::Db;
+use crate::errors::ApiError;
+use crate::tokens::{ClaimsValidator, Endpoint, PrefixOwners};
+
+pub fn list_prefix_owners(
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    Box::pin(list_prefix_owners_async(db, req)).compat()
+}
+
+async fn list_prefix_owners_async(
+    db: Data<Db>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    req.has_token_for_endpoint(Endpoint::PrefixOwners, "")?;
+
+    Ok(HttpResponse::Ok().json(db.list_prefix_owners().await?))
+}
+
+#[derive(Deserialize)]
+pub struct SetPrefixOwnerArgs {
+    prefix: String,
+    group: String,
+}
+
+pub fn set_prefix_owner(
+    args: Json<SetPrefixOwnerArgs>,
+    db: Data<Db>,
+    prefix_owners: Data<PrefixOwners>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    Box::pin(set_prefix_owner_async(args, db, prefix_owners, req)).compat()
+}
+
+async fn set_prefix_owner_async(
+    args: Json<SetPrefixOwnerArgs>,
+    db: Data<Db>,
+    prefix_owners: Data<PrefixOwners>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    req.has_token_for_endpoint(Endpoint::PrefixOwners, "")?;
+
+    /* The empty prefix matches every app, which is what tokens without prefixes are for */
+    if args.prefix.is_empty() {
+        return Err(ApiError::BadRequest(
+            "Cannot give the empty prefix to a group".to_string(),
+        ));
+    }
+    if args.group.is_empty() {
+        return Err(ApiError::BadRequest("The group can't be empty".to_string()));
+    }
+
+    let owner = db
+        .set_prefix_owner(args.prefix.clone(), args.group.clone())
+        .await?;
+    prefix_owners.set(args.prefix.clone(), args.group.clone());
+
+    log::info!("Gave prefix '{}' to group '{}'", args.prefix, args.group);
+
+    Ok(HttpResponse::Ok().json(owner))
+}
+
+#[derive(Deserialize)]
+pub struct RemovePrefixOwnerArgs {
+    prefix: String,
+}
+
+pub fn remove_prefix_owner(
+    args: Json<RemovePrefixOwnerArgs>,
+    db: Data<Db>,
+    prefix_owners: Data<PrefixOwners>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    Box::pin(remove_prefix_owner_async(args, db, prefix_owners, req)).compat()
+}
+
+async fn remove_prefix_owner_async(
+    args: Json<RemovePrefixOwnerArgs>,
+    db: Data<Db>,
+    prefix_owners: Data<PrefixOwners>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    req.has_token_for_endpoint(Endpoint::PrefixOwners, "")?;
+
+    if !db.remove_prefix_owner(args.prefix.clone()).await? {
+        return Err(ApiError::NotFound);
+    }
+    prefix_owners.remove(&args.prefix);
+
+    log::info!("Removed the owner of prefix '{}'", args.prefix);
+
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,57 @@
+use actix::prelude::*;
+use actix_web::web::Data;
+use actix_web::{HttpRequest, HttpResponse, Result};
+use futures3::TryFutureExt;
+use serde::Serialize;
+
+use crate::config::{Config, QuotaKey, StorageQuota};
+use crate::db::Db;
+use crate::errors::ApiError;
+use crate::models::QuotaUsage;
+use crate::tokens::{ClaimsValidator, Endpoint};
+
+#[derive(Serialize)]
+struct QuotaInfo {
+    #[serde(flatten)]
+    quota: StorageQuota,
+    usage: QuotaUsage,
+}
+
+/// The storage quotas that apply to builds made with the token, with how much of each is used. These are the quotas
+/// of prefixes the token covers, and of its name.
+pub fn quotas(
+    config: Data<Config>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    Box::pin(quotas_async(config, db, req)).compat()
+}
+
+async fn quotas_async(
+    config: Data<Config>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    req.has_token_for_endpoint(Endpoint::QuotaUsage, "build")?;
+
+    let claims = req.get_claims();
+    let token_name = claims.as_ref().and_then(|claims| claims.name.as_deref());
+    let token_sub = claims.as_ref().map(|claims| claims.sub.as_str());
+    let mut quotas = vec![];
+    for quota in &config.storage_quotas {
+        let visible = match quota.key() {
+            Some(QuotaKey::Prefix(prefix)) => req.has_token_prefix(prefix).is_ok(),
+            Some(QuotaKey::TokenName(quota_token_name)) => token_name == Some(quota_token_name),
+            Some(QuotaKey::Sub(sub)) => token_sub == Some(sub),
+            None => false,
+        };
+        if visible {
+            quotas.push(QuotaInfo {
+                quota: quota.clone(),
+                usage: db.quota_usage(quota.clone()).await?,
+            });
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(quotas))
+}
@@ -15,7 +15,9 @@ use crate::config::{Config, RepoConfig};
 use crate::db::Db;
 use crate::errors::ApiError;
 use crate::ostree;
-use crate::tokens::{ClaimsScope, ClaimsValidator};
+use crate::tokens::{ClaimsValidator, Endpoint};
+
+use super::build::has_token_for_build;
 
 // Ensure we strip out .. and other risky things to avoid escaping out of the base dir
 fn canonicalize_path(path: &str) -> Result<PathBuf, actix_web::Error> {
@@ -48,6 +50,32 @@ fn canonicalize_path(path: &str) -> Result<PathBuf, actix_web::Error> {
     Ok(buf)
 }
 
+/* Files describing a repo rather than holding its content, which catalog clients need without
+ * being able to download the apps themselves */
+fn is_repo_metadata_path(path: &Path) -> bool {
+    let first = match path.components().next() {
+        Some(component) => component.as_os_str(),
+        None => return false,
+    };
+    let is_single_file = path.components().count() == 1;
+
+    match first.to_str() {
+        Some("summary" | "summary.sig" | "summary.idx" | "summary.idx.sig" | "config") => {
+            is_single_file
+        }
+        Some("summaries" | "appstream" | "screenshots") => true,
+        _ => false,
+    }
+}
+
+fn build_repo_endpoint(relpath: &Path) -> Endpoint {
+    if is_repo_metadata_path(relpath) {
+        Endpoint::DownloadBuildRepoMetadata
+    } else {
+        Endpoint::DownloadBuildRepo
+    }
+}
+
 #[derive(Deserialize)]
 pub struct BuildRepoParams {
     id: i32,
@@ -69,22 +97,17 @@ async fn handle_build_repo_async(
     db: Data<Db>,
     req: HttpRequest,
 ) -> Result<HttpResponse, actix_web::Error> {
+    let relpath = canonicalize_path(params.tail.trim_start_matches('/'))?;
+
     let build = db.lookup_build(params.id).await?;
     if !build.public_download {
-        req.has_token_repo(&build.repo)?;
-        req.has_token_claims(&format!("build/{}", build.id), ClaimsScope::Download)?;
-        if let Some(app_id) = build.app_id {
-            req.has_token_prefix(&app_id)
-                /* Hide the app ID of the build, since we can't access it */
-                .map_err(|_| {
-                    ApiError::NotEnoughPermissions(
-                        "Build's app ID not matching prefix in token".to_string(),
-                    )
-                })?;
-        }
+        req.has_token_for_endpoint(
+            build_repo_endpoint(&relpath),
+            &format!("build/{}", build.id),
+        )?;
+        has_token_for_build(&req, &build)?;
     }
 
-    let relpath = canonicalize_path(params.tail.trim_start_matches('/'))?;
     let realid = canonicalize_path(&params.id.to_string())?;
     let path = Path::new(&config.build_repo_base)
         .join(realid)
@@ -182,8 +205,10 @@ pub fn handle_repo(
         return Err(ErrorNotFound("Ignoring directory"));
     }
 
-    if let Some(commit) = get_commit_for_file(&path) {
-        verify_repo_token(&req, commit, repoconfig, &path)?;
+    if !repoconfig.public_download {
+        if let Some(commit) = get_commit_for_file(&path) {
+            verify_repo_token(&req, commit, repoconfig, &path)?;
+        }
     }
 
     NamedFile::open(path)
@@ -217,3 +242,44 @@ pub fn apply_extra_headers(resp: &mut actix_web::dev::ServiceResponse) {
             .insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{ClaimsScope, TestTokenBuilder};
+    use actix_web::test::TestRequest;
+
+    fn download_request(scope: ClaimsScope) -> HttpRequest {
+        let req = TestRequest::default().to_http_request();
+        req.extensions_mut()
+            .insert(TestTokenBuilder::new().scope(scope).claims());
+        req
+    }
+
+    fn may_download(req: &HttpRequest, path: &str) -> bool {
+        let relpath = canonicalize_path(path).unwrap();
+        req.has_token_for_endpoint(build_repo_endpoint(&relpath), "build/1")
+            .is_ok()
+    }
+
+    #[test]
+    fn test_metadata_only_download() {
+        let object = "objects/ab/cdef.filez";
+
+        let req = download_request(ClaimsScope::DownloadMetadata);
+        assert!(may_download(&req, "summary"));
+        assert!(may_download(&req, "/summary.sig"));
+        assert!(may_download(&req, "appstream/x86_64/appstream.xml.gz"));
+        assert!(may_download(
+            &req,
+            "screenshots/org.example.App/112x63/shot.png"
+        ));
+        assert!(!may_download(&req, object));
+        assert!(!may_download(&req, "deltas/ab/cdef/superblock"));
+        assert!(!may_download(&req, "summary/../objects/ab/cdef.filez"));
+
+        let req = download_request(ClaimsScope::Download);
+        assert!(may_download(&req, "summary"));
+        assert!(may_download(&req, object));
+    }
+}
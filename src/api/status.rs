@@ -7,6 +7,7 @@ use std::env;
 
 use crate::db::*;
 use crate::errors::ApiError;
+use crate::metrics::TokenMetrics;
 use crate::models::{Job, JobKind, JobStatus};
 use askama::Template;
 
@@ -74,3 +75,9 @@ async fn status_async(db: Data<Db>) -> Result<HttpResponse, ApiError> {
     .unwrap();
     Ok(HttpResponse::Ok().content_type("text/html").body(s))
 }
+
+pub fn metrics(token_metrics: Data<TokenMetrics>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(token_metrics.render())
+}
@@ -1,12 +1,17 @@
 use actix::prelude::*;
-use actix_web::web::{Data, Json};
+use actix_web::web::{Data, Json, Path, Query};
 use actix_web::{HttpRequest, HttpResponse, Result};
+use chrono::Utc;
 use futures3::TryFutureExt;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
+use crate::config::{Config, TokenTemplate};
 use crate::db::Db;
 use crate::errors::ApiError;
-use crate::tokens::{ClaimsScope, ClaimsValidator};
+use crate::models::{RevokedSub, Token};
+use crate::tokens::{
+    self, ClaimsScope, ClaimsValidator, Endpoint, PresentedToken, RevocationCache, RevokedPrefixes,
+};
 
 #[derive(Deserialize)]
 pub struct TokenArgs {
@@ -26,29 +31,677 @@ async fn get_tokens_async(
     db: Data<Db>,
     req: HttpRequest,
 ) -> Result<HttpResponse, ApiError> {
-    req.has_token_claims("", ClaimsScope::TokenManagement)?;
+    req.has_token_for_endpoint(Endpoint::TokenManagement, "")?;
 
     let tokens = db.get_tokens(args.token_ids.clone()).await?;
 
     Ok(HttpResponse::Ok().json(tokens))
 }
 
+#[derive(Deserialize)]
+pub struct RevokeTokensArgs {
+    #[serde(default)]
+    token_ids: Vec<String>,
+    /* Also revoke every token issued so far with a sub inside this one */
+    sub_prefix: Option<String>,
+}
+
 pub fn revoke_tokens(
-    args: Json<TokenArgs>,
+    args: Json<RevokeTokensArgs>,
     db: Data<Db>,
+    revoked_prefixes: Data<RevokedPrefixes>,
+    revocation_cache: Data<RevocationCache>,
     req: HttpRequest,
 ) -> impl Future<Item = HttpResponse, Error = ApiError> {
-    Box::pin(revoke_tokens_async(args, db, req)).compat()
+    Box::pin(revoke_tokens_async(
+        args,
+        db,
+        revoked_prefixes,
+        revocation_cache,
+        req,
+    ))
+    .compat()
 }
 
 async fn revoke_tokens_async(
-    args: Json<TokenArgs>,
+    args: Json<RevokeTokensArgs>,
     db: Data<Db>,
+    revoked_prefixes: Data<RevokedPrefixes>,
+    revocation_cache: Data<RevocationCache>,
     req: HttpRequest,
 ) -> Result<HttpResponse, ApiError> {
-    req.has_token_claims("", ClaimsScope::TokenManagement)?;
+    req.has_token_for_endpoint(Endpoint::TokenManagement, "")?;
+
+    if args.token_ids.is_empty() && args.sub_prefix.is_none() {
+        return Err(ApiError::BadRequest(
+            "Either token_ids or sub_prefix is required".to_string(),
+        ));
+    }
+    /* Like with prefixes, an empty sub would revoke every token */
+    if args.sub_prefix.as_deref() == Some("") {
+        return Err(ApiError::BadRequest(
+            "Cannot revoke the empty sub".to_string(),
+        ));
+    }
+
+    if !args.token_ids.is_empty() {
+        db.revoke_tokens(args.token_ids.clone()).await?;
+        /* Tokens minted from the revoked ones go too, so the whole cache has to go */
+        revocation_cache.clear();
+    }
 
-    db.revoke_tokens(args.token_ids.clone()).await?;
+    if let Some(sub_prefix) = &args.sub_prefix {
+        let revoked = db.revoke_sub(sub_prefix.clone()).await?;
+        revoked_prefixes.insert_sub(revoked.sub_prefix, revoked.revoked_at.timestamp());
+        log::info!("Revoked all tokens issued so far for sub '{sub_prefix}'");
+    }
 
     Ok(HttpResponse::NoContent().finish())
 }
+
+#[derive(Serialize)]
+pub struct RevocationList {
+    tokens: Vec<Token>,
+    prefixes: Vec<String>,
+    subs: Vec<RevokedSub>,
+}
+
+/// Lists everything that is currently revoked: tokens that haven't expired yet, prefixes, and subs.
+pub fn revoked_tokens(
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    Box::pin(revoked_tokens_async(db, req)).compat()
+}
+
+async fn revoked_tokens_async(db: Data<Db>, req: HttpRequest) -> Result<HttpResponse, ApiError> {
+    req.has_token_for_endpoint(Endpoint::TokenManagement, "")?;
+
+    Ok(HttpResponse::Ok().json(RevocationList {
+        tokens: db.list_revoked_tokens().await?,
+        prefixes: db.list_revoked_prefixes().await?,
+        subs: db.list_revoked_subs().await?,
+    }))
+}
+
+/* At most this many tokens are listed at a time */
+const MAX_TOKEN_USAGE_ENTRIES: i64 = 1000;
+
+#[derive(Deserialize)]
+pub struct TokenUsageArgs {
+    /* Only list tokens that haven't been used for this many days */
+    unused_days: Option<u32>,
+}
+
+/// Lists when, from where and how often the tokens that can still be used were used, least recently used first, to
+/// help find stale or leaked tokens.
+pub fn token_usage(
+    args: Query<TokenUsageArgs>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    Box::pin(token_usage_async(args, db, req)).compat()
+}
+
+async fn token_usage_async(
+    args: Query<TokenUsageArgs>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    req.has_token_for_endpoint(Endpoint::TokenManagement, "")?;
+
+    let unused_since = args
+        .unused_days
+        .map(|days| Utc::now().naive_utc() - chrono::Duration::days(days as i64));
+    let tokens = db
+        .list_token_usage(unused_since, MAX_TOKEN_USAGE_ENTRIES)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(tokens))
+}
+
+#[derive(Deserialize)]
+pub struct UnrevokeTokenParams {
+    jti: String,
+}
+
+pub fn unrevoke_token(
+    params: Path<UnrevokeTokenParams>,
+    db: Data<Db>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    Box::pin(unrevoke_token_async(params, db, config, req)).compat()
+}
+
+async fn unrevoke_token_async(
+    params: Path<UnrevokeTokenParams>,
+    db: Data<Db>,
+    config: Data<Config>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    req.has_token_for_endpoint(Endpoint::TokenManagement, "")?;
+
+    db.unrevoke_token(params.jti.clone(), config.revocation_undo_seconds)
+        .await?;
+
+    log::warn!("Undid the revocation of token '{}'", params.jti);
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Deserialize)]
+pub struct RevokePrefixArgs {
+    prefix: String,
+}
+
+pub fn revoke_prefix(
+    args: Json<RevokePrefixArgs>,
+    db: Data<Db>,
+    revoked_prefixes: Data<RevokedPrefixes>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    Box::pin(revoke_prefix_async(args, db, revoked_prefixes, req)).compat()
+}
+
+async fn revoke_prefix_async(
+    args: Json<RevokePrefixArgs>,
+    db: Data<Db>,
+    revoked_prefixes: Data<RevokedPrefixes>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    req.has_token_for_endpoint(Endpoint::TokenManagement, "")?;
+
+    /* An empty prefix would match every token, including the one needed to undo it */
+    if args.prefix.is_empty() {
+        return Err(ApiError::BadRequest(
+            "Cannot revoke the empty prefix".to_string(),
+        ));
+    }
+
+    db.revoke_prefix(args.prefix.clone()).await?;
+    revoked_prefixes.insert(args.prefix.clone());
+
+    log::info!("Revoked all tokens for prefix '{}'", args.prefix);
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Deserialize)]
+pub struct RotateTokenArgs {
+    /* The token to rotate. If not given, the token making the request is rotated. */
+    token: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct RotateTokenResponse {
+    token: String,
+}
+
+pub fn rotate_token(
+    args: Json<RotateTokenArgs>,
+    config: Data<Config>,
+    db: Data<Db>,
+    revocation_cache: Data<RevocationCache>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    Box::pin(rotate_token_async(args, config, db, revocation_cache, req)).compat()
+}
+
+async fn rotate_token_async(
+    args: Json<RotateTokenArgs>,
+    config: Data<Config>,
+    db: Data<Db>,
+    revocation_cache: Data<RevocationCache>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    /* Anyone may rotate their own token, but rotating someone else's is token management */
+    let token = match &args.token {
+        Some(token) => {
+            req.has_token_for_endpoint(Endpoint::TokenManagement, "")?;
+            token.clone()
+        }
+        None => req
+            .extensions()
+            .get::<PresentedToken>()
+            .map(|PresentedToken(token)| token.clone())
+            .ok_or_else(|| ApiError::NotEnoughPermissions("No token specified".to_string()))?,
+    };
+    /* A reference token is rotated into a full one, from the token it stands for */
+    let token = match tokens::reference_token_id(&config, &token)? {
+        Some(jti) => db.get_reference_token(jti).await?,
+        None => token,
+    };
+
+    let new_jti = tokens::new_jti();
+    let (token, claims) = tokens::rotated_token(&config, &token, new_jti.clone())?;
+    /* rotated_token refuses tokens without an ID */
+    let old_jti = claims.jti.clone().unwrap_or_default();
+    let new_claims = tokens::rotated_claims(&claims, new_jti);
+
+    db.rotate_token(
+        old_jti.clone(),
+        new_claims.jti.clone().unwrap(),
+        new_claims.exp,
+        tokens::claims_fingerprint(&new_claims),
+    )
+    .await?;
+    revocation_cache.clear();
+
+    log::info!("Rotated token '{old_jti}'");
+
+    Ok(HttpResponse::Ok().json(RotateTokenResponse { token }))
+}
+
+#[derive(Deserialize)]
+pub struct IssueTokenArgs {
+    template: String,
+    name: String,
+    /* Narrow the template's prefixes down */
+    prefixes: Option<Vec<String>>,
+    /* Expire sooner than the template's duration */
+    duration: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IssueTokenResponse {
+    token: String,
+    jti: String,
+    exp: i64,
+}
+
+/* The claims of a token issued from a template. Only the template decides what the token may do, the arguments can
+ * just narrow it down. */
+fn template_claims(
+    template: &TokenTemplate,
+    args: &IssueTokenArgs,
+    ver: u32,
+    jti: String,
+    now: i64,
+) -> Result<tokens::Claims, ApiError> {
+    let prefixes = match &args.prefixes {
+        /* No prefixes at all would allow every ID */
+        Some(prefixes) => {
+            if !template.prefixes.is_empty()
+                && (prefixes.is_empty()
+                    || !prefixes
+                        .iter()
+                        .all(|prefix| tokens::id_matches_one_prefix(prefix, &template.prefixes)))
+            {
+                return Err(ApiError::BadRequest(format!(
+                    "Prefixes aren't within those of the '{}' template",
+                    args.template
+                )));
+            }
+            prefixes.clone()
+        }
+        None => template.prefixes.clone(),
+    };
+    let duration = args
+        .duration
+        .unwrap_or(template.duration)
+        .clamp(0, template.duration);
+
+    Ok(tokens::Claims {
+        name: Some(args.name.clone()),
+        sub: template.sub.clone(),
+        exp: now.saturating_add(duration),
+        iat: Some(now),
+        jti: Some(jti),
+        ver,
+        scope: template.scope.clone(),
+        prefixes,
+        apps: template.apps.clone(),
+        repos: template.repos.clone(),
+        branches: template.branches.clone(),
+        token_type: None,
+        max_upload_bytes: None,
+        max_requests_per_minute: None,
+        allowed_hours: vec![],
+        byte_budget: None,
+        build_budget: None,
+        jobs_metadata_only: false,
+        purpose: None,
+        allowed_ips: vec![],
+        single_use: false,
+        groups: vec![],
+    })
+}
+
+/// Issues a token from one of the configured templates, with an ID so that it can be revoked.
+pub fn issue_token(
+    args: Json<IssueTokenArgs>,
+    config: Data<Config>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    Box::pin(issue_token_async(args, config, db, req)).compat()
+}
+
+async fn issue_token_async(
+    args: Json<IssueTokenArgs>,
+    config: Data<Config>,
+    db: Data<Db>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    req.has_token_for_endpoint(Endpoint::IssueToken, "")?;
+
+    let template = config.token_templates.get(&args.template).ok_or_else(|| {
+        ApiError::BadRequest(format!("No token template named '{}'", args.template))
+    })?;
+    let jti = tokens::new_jti();
+    let claims = template_claims(
+        template,
+        &args,
+        config.min_token_version,
+        jti.clone(),
+        Utc::now().timestamp(),
+    )?;
+
+    db.register_minted_token(
+        jti.clone(),
+        claims.exp,
+        tokens::claims_fingerprint(&claims),
+        None,
+    )
+    .await?;
+
+    let token = tokens::encode_token(&config, &claims)?;
+
+    log::info!(
+        "Issued token '{}' from template '{}' for '{}'",
+        jti,
+        args.template,
+        args.name
+    );
+
+    Ok(HttpResponse::Ok().json(IssueTokenResponse {
+        token,
+        jti,
+        exp: claims.exp,
+    }))
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenInfo {
+    #[serde(flatten)]
+    claims: tokens::Claims,
+    expires_in: i64,
+    /* Revoked tokens are refused by the middleware before getting here, so this is always false. It is included so
+     * that clients don't have to know that. */
+    revoked: bool,
+}
+
+fn token_info_for(claims: tokens::Claims, now: i64) -> TokenInfo {
+    TokenInfo {
+        expires_in: claims.exp.saturating_sub(now).max(0),
+        claims,
+        revoked: false,
+    }
+}
+
+/// Describes the presented token, so that a client can check it is still valid and has the access it needs before
+/// starting a long operation. Any valid token may ask about itself.
+pub fn token_info(req: HttpRequest) -> Result<HttpResponse, ApiError> {
+    let claims = req
+        .get_claims()
+        .ok_or_else(|| ApiError::NotEnoughPermissions("No token specified".to_string()))?;
+    Ok(HttpResponse::Ok().json(token_info_for(claims, Utc::now().timestamp())))
+}
+
+#[derive(Serialize)]
+pub struct TokenSelftestResponse {
+    success: bool,
+    algorithms: Vec<tokens::TokenSelftest>,
+}
+
+/// Signs and validates a throwaway token with each of the configured algorithms, so operators can check new key
+/// material before real tokens depend on it.
+pub fn token_selftest(config: Data<Config>, req: HttpRequest) -> Result<HttpResponse, ApiError> {
+    req.has_token_for_endpoint(Endpoint::TokenManagement, "")?;
+
+    /* The caller's own sub is known to pass the sub checks */
+    let sub = req
+        .get_claims()
+        .map(|claims| claims.sub)
+        .unwrap_or_default();
+    let validation = tokens::TokenValidation::new(&config, &config.secret);
+    let algorithms: Vec<tokens::TokenSelftest> = config
+        .token_algorithms
+        .iter()
+        .map(|algorithm| {
+            let key = tokens::server_signing_key(&config.secret, *algorithm);
+            tokens::token_selftest(&validation, *algorithm, key.as_ref(), &sub)
+        })
+        .collect();
+    for report in algorithms.iter().filter(|report| !report.success) {
+        log::warn!(
+            "Token selftest failed for {}: {}",
+            report.algorithm,
+            report.error.as_deref().unwrap_or_default()
+        );
+    }
+
+    Ok(HttpResponse::Ok().json(TokenSelftestResponse {
+        success: algorithms.iter().all(|report| report.success),
+        algorithms,
+    }))
+}
+
+/* Enough for a page of builds, small enough that one request can't make us do unbounded work */
+const MAX_AUTHZ_BATCH: usize = 100;
+
+#[derive(Deserialize)]
+pub struct AuthzCheckItem {
+    repo: String,
+    app: String,
+    action: ClaimsScope,
+    branch: Option<String>,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct AuthzCheckResult {
+    allowed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failing_check: Option<String>,
+}
+
+fn authz_check_items(
+    req: &HttpRequest,
+    items: &[AuthzCheckItem],
+) -> Result<Vec<AuthzCheckResult>, ApiError> {
+    if items.len() > MAX_AUTHZ_BATCH {
+        return Err(ApiError::BadRequest(format!(
+            "At most {MAX_AUTHZ_BATCH} checks can be made in one batch"
+        )));
+    }
+
+    Ok(items
+        .iter()
+        .map(|item| {
+            let failing_check = req
+                .missing_requirements(
+                    item.action.clone(),
+                    &item.repo,
+                    &item.app,
+                    item.branch.as_deref(),
+                )
+                .into_iter()
+                .next();
+            AuthzCheckResult {
+                allowed: failing_check.is_none(),
+                failing_check,
+            }
+        })
+        .collect())
+}
+
+/// Reports which of a list of operations the presented token allows, so that a UI doesn't need a request per item.
+pub fn authz_check_batch(
+    args: Json<Vec<AuthzCheckItem>>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let results = authz_check_items(&req, &args)?;
+    Ok(HttpResponse::Ok().json(results))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestTokenBuilder;
+    use actix_web::test::TestRequest;
+
+    fn item(repo: &str, app: &str, action: ClaimsScope) -> AuthzCheckItem {
+        AuthzCheckItem {
+            repo: repo.to_string(),
+            app: app.to_string(),
+            action,
+            branch: None,
+        }
+    }
+
+    #[test]
+    fn test_authz_check_batch() {
+        let req = TestRequest::default().to_http_request();
+        req.extensions_mut().insert(
+            TestTokenBuilder::new()
+                .scope(ClaimsScope::Build)
+                .scope(ClaimsScope::Upload)
+                .repos(&["stable"])
+                .prefix("org.test")
+                .claims(),
+        );
+
+        let results = authz_check_items(
+            &req,
+            &[
+                item("stable", "org.test.App", ClaimsScope::Upload),
+                item("stable", "org.test.App", ClaimsScope::Publish),
+                item("beta", "org.test.App", ClaimsScope::Build),
+                item("stable", "com.other.App", ClaimsScope::Build),
+            ],
+        )
+        .unwrap();
+
+        let allowed: Vec<bool> = results.iter().map(|result| result.allowed).collect();
+        assert_eq!(allowed, vec![true, false, false, false]);
+        assert_eq!(results[0].failing_check, None);
+        assert_eq!(
+            results[1].failing_check.as_deref(),
+            Some("Token lacks the 'publish' scope")
+        );
+        assert_eq!(
+            results[2].failing_check.as_deref(),
+            Some("Token does not allow repo 'beta'")
+        );
+        assert_eq!(
+            results[3].failing_check.as_deref(),
+            Some("Token does not allow app 'com.other.App'")
+        );
+    }
+
+    #[test]
+    fn test_template_claims() {
+        let template: TokenTemplate = serde_json::from_value(serde_json::json!({
+            "scope": ["build", "upload"],
+            "prefixes": ["org.gnome"],
+            "repos": ["stable"],
+            "duration": 3600,
+        }))
+        .unwrap();
+        let args = |prefixes: Option<&[&str]>, duration: Option<i64>| IssueTokenArgs {
+            template: "ci-upload".to_string(),
+            name: "gnome-ci".to_string(),
+            prefixes: prefixes.map(|prefixes| prefixes.iter().map(|p| p.to_string()).collect()),
+            duration,
+        };
+
+        let claims =
+            template_claims(&template, &args(None, None), 0, "abc".to_string(), 1000).unwrap();
+        assert_eq!(claims.sub, "build");
+        assert_eq!(claims.scope, vec![ClaimsScope::Build, ClaimsScope::Upload]);
+        assert_eq!(claims.prefixes, vec!["org.gnome".to_string()]);
+        assert_eq!(claims.repos, vec!["stable".to_string()]);
+        assert_eq!(claims.jti.as_deref(), Some("abc"));
+        assert_eq!(claims.exp, 4600);
+
+        /* Arguments can narrow the token down, but not widen it */
+        let claims = template_claims(
+            &template,
+            &args(Some(&["org.gnome.Maps"]), Some(60)),
+            0,
+            "abc".to_string(),
+            1000,
+        )
+        .unwrap();
+        assert_eq!(claims.prefixes, vec!["org.gnome.Maps".to_string()]);
+        assert_eq!(claims.exp, 1060);
+
+        let claims = template_claims(
+            &template,
+            &args(None, Some(100000)),
+            0,
+            "abc".to_string(),
+            1000,
+        )
+        .unwrap();
+        assert_eq!(claims.exp, 4600);
+
+        for prefixes in [&["org.kde"][..], &[][..]] {
+            assert!(template_claims(
+                &template,
+                &args(Some(prefixes), None),
+                0,
+                "abc".to_string(),
+                1000
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn test_token_info() {
+        let claims = TestTokenBuilder::new()
+            .scope(ClaimsScope::Upload)
+            .repos(&["stable"])
+            .jti("abc")
+            .exp(1000)
+            .claims();
+
+        let info = serde_json::to_value(token_info_for(claims, 400)).unwrap();
+        assert_eq!(info["sub"], "build");
+        assert_eq!(info["scope"], serde_json::json!(["upload"]));
+        assert_eq!(info["repos"], serde_json::json!(["stable"]));
+        assert_eq!(info["jti"], "abc");
+        assert_eq!(info["exp"], 1000);
+        assert_eq!(info["expires_in"], 600);
+        assert_eq!(info["revoked"], false);
+
+        let claims = TestTokenBuilder::new().exp(1000).claims();
+        assert_eq!(token_info_for(claims, 2000).expires_in, 0);
+
+        let req = TestRequest::default().to_http_request();
+        assert!(token_info(req).is_err());
+    }
+
+    #[test]
+    fn test_authz_check_batch_too_large() {
+        let req = TestRequest::default().to_http_request();
+        req.extensions_mut()
+            .insert(TestTokenBuilder::new().claims());
+
+        let items: Vec<AuthzCheckItem> = (0..=MAX_AUTHZ_BATCH)
+            .map(|_| item("stable", "org.test.App", ClaimsScope::Build))
+            .collect();
+        assert!(matches!(
+            authz_check_items(&req, &items),
+            Err(ApiError::BadRequest(_))
+        ));
+        assert_eq!(
+            authz_check_items(&req, &items[..MAX_AUTHZ_BATCH])
+                .unwrap()
+                .len(),
+            MAX_AUTHZ_BATCH
+        );
+    }
+}
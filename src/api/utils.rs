@@ -1,21 +1,31 @@
 use actix::prelude::*;
+use actix_web::error::PayloadError;
 use actix_web::{error, http};
 use actix_web::{HttpRequest, HttpResponse, Result};
+use bytes::Bytes;
 
 use futures::future;
 use futures::future::Future;
+use futures::stream::Stream;
+use futures3::compat::Future01CompatExt;
 use log::warn;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
 use std::clone::Clone;
 use std::fs;
-use std::io::Write;
+use std::io::{self, Read, Write};
 use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
 use std::path;
+use std::process::Command;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use tempfile::NamedTempFile;
+use tokio_process::CommandExt;
+use walkdir::WalkDir;
 
+use crate::config::FsyncPolicy;
 use crate::errors::ApiError;
 
 pub fn respond_with_url<T>(
@@ -112,12 +122,17 @@ fn get_upload_subpath(
     let filename = cd
         .get_filename()
         .ok_or_else(|| ApiError::BadRequest("No filename for multipart item".to_string()))?;
+    upload_subpath(filename, state.only_deltas)
+}
+
+/* Where an uploaded object or delta part goes in the repo, given its filename */
+pub fn upload_subpath(filename: &str, only_deltas: bool) -> Result<path::PathBuf, ApiError> {
     // We verify the format below, but just to make sure we never allow anything like a path
     if filename.contains('/') {
         return Err(ApiError::BadRequest("Invalid upload filename".to_string()));
     }
 
-    if !state.only_deltas {
+    if !only_deltas {
         if let Some(path) = filename_parse_object(filename) {
             return Ok(path);
         }
@@ -133,6 +148,131 @@ fn get_upload_subpath(
 pub struct UploadState {
     pub repo_path: path::PathBuf,
     pub only_deltas: bool,
+    pub max_bytes: Option<u64>,
+    pub uploaded_bytes: AtomicU64,
+    pub require_checksums: bool,
+    pub fsync: FsyncPolicy,
+    /* Uploads in a session are synced in one batch when the session is finished, rather than per request */
+    pub in_session: bool,
+}
+
+impl UploadState {
+    /* Counts bytes received over all files of the upload, failing once the limit is exceeded */
+    fn count_bytes(&self, len: u64) -> Result<(), ApiError> {
+        let total = self.uploaded_bytes.fetch_add(len, Ordering::SeqCst) + len;
+        match self.max_bytes {
+            Some(max_bytes) if total > max_bytes => Err(ApiError::PayloadTooLarge(format!(
+                "Upload exceeds the limit of {max_bytes} bytes"
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /* Called once all files of an upload request are in place */
+    pub fn sync_batch(&self) -> Result<(), ApiError> {
+        if self.fsync == FsyncPolicy::Batch && !self.in_session {
+            sync_filesystem(&self.repo_path)?;
+        }
+        Ok(())
+    }
+}
+
+/* Flushes everything written to the filesystem holding the path, which on network filesystems is much cheaper than
+ * syncing each file */
+pub fn sync_filesystem(path: &path::Path) -> io::Result<()> {
+    let dir = fs::File::open(path)?;
+    if unsafe { libc::syncfs(dir.as_raw_fd()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn sync_parent_dir(path: &path::Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) => fs::File::open(parent)?.sync_all(),
+        None => Ok(()),
+    }
+}
+
+/* Rejects an upload up front when its declared size is already over the limit, so that we don't
+ * stream it all in only to fail at the end. The Content-Length also covers the multipart framing,
+ * which is tiny next to any sensible limit. Chunked uploads don't declare a size, and are only
+ * limited by UploadState::count_bytes() while streaming. */
+pub fn check_content_length(req: &HttpRequest, max_bytes: Option<u64>) -> Result<(), ApiError> {
+    let max_bytes = match max_bytes {
+        Some(max_bytes) => max_bytes,
+        None => return Ok(()),
+    };
+
+    let content_length = req
+        .headers()
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+
+    match content_length {
+        Some(length) if length > max_bytes => Err(ApiError::PayloadTooLarge(format!(
+            "Upload of {length} bytes exceeds the limit of {max_bytes} bytes"
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/* The header giving the SHA256 of an uploaded file, or of a whole bundle */
+pub const CHECKSUM_HEADER: &str = "x-content-sha256";
+
+/* The checksum an upload is expected to have, if the client sent one */
+pub fn expected_checksum(
+    headers: &http::HeaderMap,
+    state: &UploadState,
+) -> Result<Option<String>, ApiError> {
+    match headers.get(CHECKSUM_HEADER) {
+        Some(value) => {
+            let checksum = value
+                .to_str()
+                .map(|value| value.trim().to_ascii_lowercase())
+                .unwrap_or_default();
+            if checksum.len() != 64 || !is_all_lower_hexdigits(&checksum) {
+                return Err(ApiError::BadRequest(format!(
+                    "Invalid {CHECKSUM_HEADER} header"
+                )));
+            }
+            Ok(Some(checksum))
+        }
+        None if state.require_checksums => Err(ApiError::BadRequest(format!(
+            "Uploads to this repo need a {CHECKSUM_HEADER} header"
+        ))),
+        None => Ok(None),
+    }
+}
+
+pub fn check_checksum(
+    filename: &str,
+    expected: Option<&str>,
+    digest: ring::digest::Digest,
+) -> Result<(), ApiError> {
+    let actual = hex::encode(digest.as_ref());
+    match expected {
+        Some(expected) if expected != actual => Err(ApiError::ChecksumMismatch(
+            filename.to_string(),
+            expected.to_string(),
+            actual,
+        )),
+        _ => Ok(()),
+    }
+}
+
+pub fn file_sha256(path: &path::Path) -> io::Result<ring::digest::Digest> {
+    let mut file = fs::File::open(path)?;
+    let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let len = file.read(&mut buf)?;
+        if len == 0 {
+            return Ok(context.finish());
+        }
+        context.update(&buf[..len]);
+    }
 }
 
 pub fn start_save(
@@ -152,6 +292,470 @@ pub fn start_save(
     Ok((named_file, absolute_path))
 }
 
+/* Parses a "Content-Range: bytes {first}-{last}/{total}" header into the first byte, the byte after the last one
+ * and the total size */
+pub fn parse_content_range(value: &str) -> Result<(u64, u64, u64), ApiError> {
+    let invalid = || ApiError::BadRequest(format!("Invalid Content-Range '{value}'"));
+
+    let (range, total) = value
+        .strip_prefix("bytes ")
+        .and_then(|range| range.split_once('/'))
+        .ok_or_else(invalid)?;
+    let (first, last) = range.split_once('-').ok_or_else(invalid)?;
+    let first = first.parse::<u64>().map_err(|_| invalid())?;
+    let last = last.parse::<u64>().map_err(|_| invalid())?;
+    let total = total.parse::<u64>().map_err(|_| invalid())?;
+
+    if first > last || last >= total {
+        return Err(invalid());
+    }
+
+    Ok((first, last + 1, total))
+}
+
+/* Partial uploads are kept next to the temporary files of whole uploads, under the name of the object or delta
+ * part they become. Their size is how far the upload got. */
+pub fn partial_upload_path(state: &UploadState, filename: &str) -> path::PathBuf {
+    state.repo_path.join("deltas/.partial").join(filename)
+}
+
+pub fn partial_upload_offset(partial_path: &path::Path) -> Result<u64, ApiError> {
+    match fs::metadata(partial_path) {
+        Ok(metadata) => Ok(metadata.len()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/* Appends a chunk of a partial upload. The chunk has to start where the partial upload ends, so a client that
+ * lost track after a broken connection asks for the offset and resumes from there. */
+pub async fn append_partial_upload<S>(
+    stream: S,
+    state: &Arc<UploadState>,
+    partial_path: &path::Path,
+    start: u64,
+    end: u64,
+) -> Result<u64, ApiError>
+where
+    S: Stream<Item = Bytes, Error = PayloadError>,
+{
+    if let Some(parent) = partial_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(partial_path)?;
+
+    let offset = file.metadata()?.len();
+    if offset != start {
+        return Err(ApiError::UploadOffsetMismatch(
+            format!("Upload continues at byte {offset}, not {start}"),
+            offset,
+        ));
+    }
+
+    let state = state.clone();
+    let written = stream
+        .map_err(|e| ApiError::InternalServerError(e.to_string()))
+        .fold(0u64, move |acc, bytes| {
+            let len = bytes.len() as u64;
+            let rt = if acc + len > end - start {
+                Err(ApiError::BadRequest(
+                    "Upload chunk is longer than its Content-Range".to_string(),
+                ))
+            } else {
+                state.count_bytes(len).and_then(|_| {
+                    file.write_all(bytes.as_ref())
+                        .map(|_| acc + len)
+                        .map_err(ApiError::from)
+                })
+            };
+            future::result(rt)
+        })
+        .compat()
+        .await?;
+
+    if written != end - start {
+        return Err(ApiError::UploadOffsetMismatch(
+            format!(
+                "Upload chunk ended after {written} of {} bytes",
+                end - start
+            ),
+            start + written,
+        ));
+    }
+
+    Ok(end)
+}
+
+/* Moves a completed partial upload or a file unpacked from a bundle into place */
+pub fn move_into_place(
+    upload_path: &path::Path,
+    subpath: &path::Path,
+    state: &UploadState,
+) -> Result<(), ApiError> {
+    let absolute_path = state.repo_path.join(subpath);
+    if let Some(parent) = absolute_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    if state.fsync == FsyncPolicy::Object {
+        fs::File::open(upload_path)?.sync_all()?;
+    }
+    fs::rename(upload_path, &absolute_path)?;
+    let mut perms = fs::metadata(&absolute_path)?.permissions();
+    perms.set_mode(0o644);
+    if let Err(_e) = fs::set_permissions(&absolute_path, perms) {
+        warn!("Can't change permissions on uploaded file");
+    }
+    if state.fsync == FsyncPolicy::Object {
+        sync_parent_dir(&absolute_path)?;
+    }
+    Ok(())
+}
+
+/* The upload filename of a file in a bundle, which is laid out like the objects and deltas directories of a repo */
+pub fn bundle_member_filename(member: &path::Path) -> Option<String> {
+    let parts = member
+        .components()
+        .filter(|component| *component != path::Component::CurDir)
+        .map(|component| match component {
+            path::Component::Normal(part) => part.to_str(),
+            _ => None,
+        })
+        .collect::<Option<Vec<&str>>>()?;
+
+    match parts.as_slice() {
+        ["objects", dir, rest] => Some(format!("{dir}{rest}")),
+        ["deltas", dir, rest, part] => Some(format!("{dir}{rest}.{part}.delta")),
+        _ => None,
+    }
+}
+
+/* The name of the ref a file in a bundle is, for files laid out like the refs/heads directory of a repo */
+pub fn bundle_member_ref(member: &path::Path) -> Option<String> {
+    let parts = member
+        .components()
+        .filter(|component| *component != path::Component::CurDir)
+        .map(|component| match component {
+            path::Component::Normal(part) => part.to_str(),
+            _ => None,
+        })
+        .collect::<Option<Vec<&str>>>()?;
+
+    match parts.as_slice() {
+        ["refs", "heads", ref_parts @ ..] if !ref_parts.is_empty() => Some(ref_parts.join("/")),
+        _ => None,
+    }
+}
+
+/* The commit a ref file points to, which is its checksum and maybe a newline */
+fn parse_ref_commit(ref_name: &str, contents: &str) -> Result<String, ApiError> {
+    let commit = contents.strip_suffix('\n').unwrap_or(contents);
+    if commit.len() != 64 || !is_all_lower_hexdigits(commit) {
+        return Err(ApiError::BadRequest(format!(
+            "Ref {ref_name} in bundle doesn't point to a commit"
+        )));
+    }
+    Ok(commit.to_string())
+}
+
+pub struct UnpackedBundle {
+    pub files: Vec<String>,
+    /* The refs in the bundle, with the commits they point to */
+    pub refs: Vec<(String, String)>,
+    pub skipped: usize,
+}
+
+/* Saves an uploaded bundle to a temporary file, counting it against the upload limit */
+pub async fn save_bundle<S>(stream: S, state: &Arc<UploadState>) -> Result<NamedTempFile, ApiError>
+where
+    S: Stream<Item = Bytes, Error = PayloadError>,
+{
+    let tmp_dir = state.repo_path.join("deltas/.tmp");
+    fs::create_dir_all(&tmp_dir)?;
+    let named_file = NamedTempFile::new_in(&tmp_dir)?;
+    let mut file = named_file.reopen()?;
+
+    let state = state.clone();
+    stream
+        .map_err(|e| ApiError::InternalServerError(e.to_string()))
+        .for_each(move |bytes| {
+            future::result(
+                state
+                    .count_bytes(bytes.len() as u64)
+                    .and_then(|_| file.write_all(bytes.as_ref()).map_err(ApiError::from)),
+            )
+        })
+        .compat()
+        .await?;
+
+    Ok(named_file)
+}
+
+/* A bundle never unpacks to more than this, even when the upload has no limit of its own */
+const MAX_UNPACKED_BUNDLE_BYTES: u64 = 16 * 1024 * 1024 * 1024;
+
+/* The size of the files a bundle unpacks to, from its `tar --list --verbose --numeric-owner` listing. Each line
+ * starts with the mode, the numeric owner and the size, so names in the archive can't be mistaken for sizes. Links
+ * and directories take no space. */
+fn listed_bundle_bytes(listing: &str) -> Result<u64, String> {
+    let mut total: u64 = 0;
+    for line in listing.lines() {
+        let mut fields = line.split_whitespace();
+        let size = match (fields.next(), fields.next(), fields.next()) {
+            (Some(mode), Some(_), Some(size)) if mode.starts_with('-') => size,
+            (Some(mode), Some(_), Some(_)) if mode.len() == 10 => continue,
+            _ => return Err(format!("Unexpected line in bundle listing: {line}")),
+        };
+        let size = size
+            .parse::<u64>()
+            .map_err(|_| format!("Unexpected size in bundle listing: {line}"))?;
+        total = total.saturating_add(size);
+    }
+    Ok(total)
+}
+
+/* Runs tar on a bundle, failing with the error tar gives */
+async fn run_tar(args: &[&std::ffi::OsStr]) -> Result<Vec<u8>, ApiError> {
+    let output = Command::new("tar")
+        .args(args)
+        .output_async()
+        .compat()
+        .await
+        .map_err(|e| ApiError::InternalServerError(format!("Can't run tar: {e}")))?;
+    if !output.status.success() {
+        return Err(ApiError::BadRequest(format!(
+            "Can't unpack bundle: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(output.stdout)
+}
+
+/* Unpacks a tar archive, compressed in any way tar recognizes (such as with zstd or gzip), and moves the objects and
+ * delta parts in it into place. It is unpacked into a directory of its own first, so nothing but well-formed
+ * objects and delta parts ever reach the repo. Symlinks in the archive are never followed, and tar itself doesn't
+ * create ones that point out of the directory until it is done. The refs in the archive are read and returned for
+ * the caller to create as build refs, once `check_ref` accepts each of them, which happens before anything is moved
+ * into place. Anything else in the archive is skipped. */
+pub async fn unpack_bundle<F>(
+    bundle: &path::Path,
+    state: &UploadState,
+    check_ref: F,
+) -> Result<UnpackedBundle, ApiError>
+where
+    F: Fn(&str) -> Result<(), ApiError>,
+{
+    /* Compression means the archive can unpack to far more than was uploaded, so its size is checked from the
+     * listing before anything is written */
+    let max_bytes = state
+        .max_bytes
+        .map_or(MAX_UNPACKED_BUNDLE_BYTES, |max_bytes| {
+            max_bytes.min(MAX_UNPACKED_BUNDLE_BYTES)
+        });
+    let listing = run_tar(&[
+        "--list".as_ref(),
+        "--verbose".as_ref(),
+        "--numeric-owner".as_ref(),
+        "--file".as_ref(),
+        bundle.as_os_str(),
+    ])
+    .await?;
+    let listed_bytes =
+        listed_bundle_bytes(&String::from_utf8_lossy(&listing)).map_err(ApiError::BadRequest)?;
+    if listed_bytes > max_bytes {
+        return Err(ApiError::PayloadTooLarge(format!(
+            "Bundle unpacks to {listed_bytes} bytes, over the limit of {max_bytes} bytes"
+        )));
+    }
+
+    let unpack_dir = tempfile::TempDir::new_in(state.repo_path.join("deltas/.tmp"))?;
+    run_tar(&[
+        "--extract".as_ref(),
+        "--file".as_ref(),
+        bundle.as_os_str(),
+        "--directory".as_ref(),
+        unpack_dir.path().as_os_str(),
+        "--no-same-owner".as_ref(),
+        "--no-same-permissions".as_ref(),
+    ])
+    .await?;
+
+    let mut members = Vec::new();
+    let mut refs = Vec::new();
+    let mut unpacked_bytes = 0;
+    let mut skipped = 0;
+    for entry in WalkDir::new(unpack_dir.path()).follow_links(false) {
+        let entry = entry.map_err(|e| ApiError::InternalServerError(e.to_string()))?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let member = entry
+            .path()
+            .strip_prefix(unpack_dir.path())
+            .map_err(|e| ApiError::InternalServerError(e.to_string()))?;
+        if let Some(ref_name) = bundle_member_ref(member) {
+            check_ref(&ref_name)?;
+            let contents = fs::read_to_string(entry.path()).map_err(|_| {
+                ApiError::BadRequest(format!(
+                    "Ref {ref_name} in bundle doesn't point to a commit"
+                ))
+            })?;
+            let commit = parse_ref_commit(&ref_name, &contents)?;
+            refs.push((ref_name, commit));
+            continue;
+        }
+        let filename = bundle_member_filename(member);
+        match filename.and_then(|filename| {
+            upload_subpath(&filename, state.only_deltas)
+                .ok()
+                .map(|subpath| (filename, subpath))
+        }) {
+            Some((filename, subpath)) => {
+                unpacked_bytes += entry.metadata().map(|m| m.len()).unwrap_or(0);
+                members.push((entry.path().to_path_buf(), filename, subpath));
+            }
+            None => skipped += 1,
+        }
+    }
+
+    /* Checked again in case tar wrote more than it listed */
+    if unpacked_bytes > max_bytes {
+        return Err(ApiError::PayloadTooLarge(format!(
+            "Bundle unpacks to {unpacked_bytes} bytes, over the limit of {max_bytes} bytes"
+        )));
+    }
+
+    let mut files = Vec::new();
+    for (path, filename, subpath) in members {
+        move_into_place(&path, &subpath, state)?;
+        files.push(filename);
+    }
+
+    Ok(UnpackedBundle {
+        files,
+        refs,
+        skipped,
+    })
+}
+
+/* The header naming the upload session an upload belongs to */
+pub const UPLOAD_SESSION_HEADER: &str = "x-upload-session";
+
+/* Uploads in a session are staged in a directory of their own, laid out like the upload repo, until the session is
+ * finished and they are moved into the upload repo in one go. Aborting the session drops them. */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UploadSession {
+    pub id: String,
+    pub fsync: FsyncPolicy,
+}
+
+const UPLOAD_SESSION_FILE: &str = "session.json";
+
+fn upload_sessions_path(upload_path: &path::Path) -> path::PathBuf {
+    upload_path.join("deltas/.sessions")
+}
+
+/* Session IDs come from clients, so they are checked to be one of ours before being used in a path */
+pub fn upload_session_path(upload_path: &path::Path, id: &str) -> Result<path::PathBuf, ApiError> {
+    if id.len() != 32 || !is_all_lower_hexdigits(id) {
+        return Err(ApiError::NotFound);
+    }
+    Ok(upload_sessions_path(upload_path).join(id))
+}
+
+pub fn has_upload_sessions(upload_path: &path::Path) -> Result<bool, ApiError> {
+    match fs::read_dir(upload_sessions_path(upload_path)) {
+        Ok(mut entries) => Ok(entries.next().is_some()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn create_upload_session(
+    upload_path: &path::Path,
+    fsync: FsyncPolicy,
+) -> Result<UploadSession, ApiError> {
+    let session = UploadSession {
+        id: hex::encode(rand::random::<[u8; 16]>()),
+        fsync,
+    };
+    let session_path = upload_session_path(upload_path, &session.id)?;
+    fs::create_dir_all(&session_path)?;
+    let contents =
+        serde_json::to_vec(&session).map_err(|e| ApiError::InternalServerError(e.to_string()))?;
+    fs::write(session_path.join(UPLOAD_SESSION_FILE), contents)?;
+    Ok(session)
+}
+
+pub fn load_upload_session(
+    upload_path: &path::Path,
+    id: &str,
+) -> Result<(UploadSession, path::PathBuf), ApiError> {
+    let session_path = upload_session_path(upload_path, id)?;
+    let contents = match fs::read(session_path.join(UPLOAD_SESSION_FILE)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(ApiError::NotFound),
+        Err(e) => return Err(e.into()),
+    };
+    let session = serde_json::from_slice(&contents)
+        .map_err(|e| ApiError::InternalServerError(format!("Invalid upload session {id}: {e}")))?;
+    Ok((session, session_path))
+}
+
+/* Moves the files staged in a session into the upload repo and removes the session, returning the upload filenames
+ * of the files. Temporary files and unfinished chunked uploads of the session are dropped. */
+pub fn finish_upload_session(upload_path: &path::Path, id: &str) -> Result<Vec<String>, ApiError> {
+    let (session, session_path) = load_upload_session(upload_path, id)?;
+
+    let mut staged = Vec::new();
+    for entry in WalkDir::new(&session_path).follow_links(false) {
+        let entry = entry.map_err(|e| ApiError::InternalServerError(e.to_string()))?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let member = entry
+            .path()
+            .strip_prefix(&session_path)
+            .map_err(|e| ApiError::InternalServerError(e.to_string()))?;
+        if let Some(filename) = bundle_member_filename(member) {
+            let subpath = upload_subpath(&filename, false)?;
+            staged.push((entry.path().to_path_buf(), filename, subpath));
+        }
+    }
+
+    let state = UploadState {
+        repo_path: upload_path.to_path_buf(),
+        only_deltas: false,
+        max_bytes: None,
+        uploaded_bytes: AtomicU64::new(0),
+        require_checksums: false,
+        fsync: session.fsync,
+        in_session: false,
+    };
+    /* The contents are synced before they are moved into place, and the moves after */
+    if session.fsync == FsyncPolicy::Batch {
+        sync_filesystem(&session_path)?;
+    }
+    let mut files = Vec::new();
+    for (path, filename, subpath) in staged {
+        move_into_place(&path, &subpath, &state)?;
+        files.push(filename);
+    }
+    state.sync_batch()?;
+
+    fs::remove_dir_all(&session_path)?;
+    Ok(files)
+}
+
+pub fn abort_upload_session(upload_path: &path::Path, id: &str) -> Result<(), ApiError> {
+    let (_, session_path) = load_upload_session(upload_path, id)?;
+    fs::remove_dir_all(&session_path)?;
+    Ok(())
+}
+
 pub fn save_file(
     field: actix_multipart::Field,
     state: &Arc<UploadState>,
@@ -160,6 +764,10 @@ pub fn save_file(
         Ok(subpath) => subpath,
         Err(e) => return Box::new(future::err(e)),
     };
+    let expected = match expected_checksum(field.headers(), state) {
+        Ok(expected) => expected,
+        Err(e) => return Box::new(future::err(e)),
+    };
 
     let (named_file, object_file) = match start_save(&repo_subpath, state) {
         Ok((named_file, object_file)) => (named_file, object_file),
@@ -169,23 +777,46 @@ pub fn save_file(
     // We need file in two continuations below, so put it in a Rc+RefCell
     let shared_file = Rc::new(RefCell::new(named_file));
     let shared_file2 = shared_file.clone();
+    let shared_digest = Rc::new(RefCell::new(ring::digest::Context::new(
+        &ring::digest::SHA256,
+    )));
+    let shared_digest2 = shared_digest.clone();
+    let fsync = state.fsync;
+    let state = state.clone();
     Box::new(
         field
+            .map_err(|e| ApiError::InternalServerError(e.to_string()))
             .fold(0i64, move |acc, bytes| {
-                let rt = shared_file
-                    .borrow_mut()
-                    .write_all(bytes.as_ref())
-                    .map(|_| acc + bytes.len() as i64)
-                    .map_err(|e| {
-                        actix_multipart::MultipartError::Payload(error::PayloadError::Io(e))
-                    });
+                let rt = state.count_bytes(bytes.len() as u64).and_then(|_| {
+                    shared_digest.borrow_mut().update(bytes.as_ref());
+                    shared_file
+                        .borrow_mut()
+                        .write_all(bytes.as_ref())
+                        .map(|_| acc + bytes.len() as i64)
+                        .map_err(ApiError::from)
+                });
                 future::result(rt)
             })
-            .map_err(|e| ApiError::InternalServerError(e.to_string()))
             .and_then(move |res| {
+                // A corrupted upload is dropped along with the temporary file
+                let digest = Rc::try_unwrap(shared_digest2)
+                    .unwrap()
+                    .into_inner()
+                    .finish();
+                if let Err(e) =
+                    check_checksum(&repo_subpath.to_string_lossy(), expected.as_deref(), digest)
+                {
+                    return future::err(e);
+                }
+
                 // persist consumes the named file, so we need to
                 // completely move it out of the shared Rc+RefCell
                 let named_file = Rc::try_unwrap(shared_file2).unwrap().into_inner();
+                if fsync == FsyncPolicy::Object {
+                    if let Err(e) = named_file.as_file().sync_all() {
+                        return future::err(e.into());
+                    }
+                }
                 match named_file.persist(&object_file) {
                     Ok(persisted_file) => {
                         if let Ok(metadata) = persisted_file.metadata() {
@@ -197,6 +828,11 @@ pub fn save_file(
                         } else {
                             warn!("Can't get permissions on uploaded file");
                         };
+                        if fsync == FsyncPolicy::Object {
+                            if let Err(e) = sync_parent_dir(&object_file) {
+                                return future::err(e.into());
+                            }
+                        }
                         future::result(Ok(res))
                     }
                     Err(e) => future::err(ApiError::InternalServerError(e.to_string())),
@@ -215,4 +851,266 @@ mod tests {
         assert!(!is_all_lower_hexdigits("0123456789Abcdef"));
         assert!(!is_all_lower_hexdigits("?"));
     }
+
+    #[test]
+    fn test_upload_state_max_bytes() {
+        let state = UploadState {
+            repo_path: path::PathBuf::from("repo"),
+            only_deltas: false,
+            max_bytes: Some(100),
+            uploaded_bytes: AtomicU64::new(0),
+            require_checksums: false,
+            fsync: FsyncPolicy::None,
+            in_session: false,
+        };
+        assert!(state.count_bytes(60).is_ok());
+        assert!(state.count_bytes(40).is_ok());
+        assert!(matches!(
+            state.count_bytes(1),
+            Err(ApiError::PayloadTooLarge(_))
+        ));
+
+        let unlimited = UploadState {
+            repo_path: path::PathBuf::from("repo"),
+            only_deltas: false,
+            max_bytes: None,
+            uploaded_bytes: AtomicU64::new(0),
+            require_checksums: false,
+            fsync: FsyncPolicy::None,
+            in_session: false,
+        };
+        assert!(unlimited.count_bytes(u32::MAX as u64).is_ok());
+    }
+
+    #[test]
+    fn test_check_content_length() {
+        use actix_web::test::TestRequest;
+
+        let req = TestRequest::default()
+            .header(http::header::CONTENT_LENGTH, "5000")
+            .to_http_request();
+        assert!(matches!(
+            check_content_length(&req, Some(1000)),
+            Err(ApiError::PayloadTooLarge(_))
+        ));
+        assert!(check_content_length(&req, Some(5000)).is_ok());
+        assert!(check_content_length(&req, None).is_ok());
+
+        /* Without a declared length the check is left to the streaming limit */
+        let req = TestRequest::default()
+            .header(http::header::TRANSFER_ENCODING, "chunked")
+            .to_http_request();
+        assert!(check_content_length(&req, Some(1000)).is_ok());
+        let state = UploadState {
+            repo_path: path::PathBuf::from("repo"),
+            only_deltas: false,
+            max_bytes: Some(1000),
+            uploaded_bytes: AtomicU64::new(0),
+            require_checksums: false,
+            fsync: FsyncPolicy::None,
+            in_session: false,
+        };
+        assert!(state.count_bytes(1000).is_ok());
+        assert!(state.count_bytes(1).is_err());
+    }
+
+    #[test]
+    fn test_upload_checksums() {
+        use actix_web::test::TestRequest;
+
+        let mut state = UploadState {
+            repo_path: path::PathBuf::from("repo"),
+            only_deltas: false,
+            max_bytes: None,
+            uploaded_bytes: AtomicU64::new(0),
+            require_checksums: false,
+            fsync: FsyncPolicy::None,
+            in_session: false,
+        };
+        let hello_sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+        let req = TestRequest::default()
+            .header(CHECKSUM_HEADER, hello_sha256.to_uppercase())
+            .to_http_request();
+        let expected = expected_checksum(req.headers(), &state).unwrap();
+        assert_eq!(expected.as_deref(), Some(hello_sha256));
+
+        let digest = |data: &[u8]| ring::digest::digest(&ring::digest::SHA256, data);
+        assert!(check_checksum("a.filez", expected.as_deref(), digest(b"hello")).is_ok());
+        match check_checksum("a.filez", expected.as_deref(), digest(b"hellO")) {
+            Err(ApiError::ChecksumMismatch(filename, expected, actual)) => {
+                assert_eq!(filename, "a.filez");
+                assert_eq!(expected, hello_sha256);
+                assert_ne!(actual, hello_sha256);
+            }
+            other => panic!("Unexpected result {other:?}"),
+        }
+
+        let req = TestRequest::default()
+            .header(CHECKSUM_HEADER, "nothex")
+            .to_http_request();
+        assert!(expected_checksum(req.headers(), &state).is_err());
+
+        /* Without a checksum anything goes, unless the repo requires them */
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(expected_checksum(req.headers(), &state).unwrap(), None);
+        assert!(check_checksum("a.filez", None, digest(b"hello")).is_ok());
+        state.require_checksums = true;
+        assert!(expected_checksum(req.headers(), &state).is_err());
+    }
+
+    #[test]
+    fn test_parse_content_range() {
+        assert_eq!(
+            parse_content_range("bytes 0-99/1000").unwrap(),
+            (0, 100, 1000)
+        );
+        assert_eq!(
+            parse_content_range("bytes 900-999/1000").unwrap(),
+            (900, 1000, 1000)
+        );
+
+        for invalid in &[
+            "bytes 0-1000/1000",
+            "bytes 100-99/1000",
+            "bytes 0-99/*",
+            "bytes */1000",
+            "0-99/1000",
+            "bytes -1-99/1000",
+        ] {
+            assert!(
+                matches!(parse_content_range(invalid), Err(ApiError::BadRequest(_))),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_bundle_member_filename() {
+        let checksum = "ab".repeat(32);
+        assert_eq!(
+            bundle_member_filename(
+                &path::Path::new("objects")
+                    .join("ab")
+                    .join(format!("{}.filez", &checksum[2..]))
+            ),
+            Some(format!("{checksum}.filez"))
+        );
+        assert_eq!(
+            bundle_member_filename(path::Path::new("./deltas/oS/6QiSB/superblock")),
+            Some("oS6QiSB.superblock.delta".to_string())
+        );
+        assert_eq!(
+            bundle_member_filename(path::Path::new("refs/heads/app/org.test.App/x86_64/stable")),
+            None
+        );
+        assert_eq!(
+            bundle_member_filename(path::Path::new("objects/../../config")),
+            None
+        );
+        assert_eq!(bundle_member_filename(path::Path::new("config")), None);
+    }
+
+    #[test]
+    fn test_bundle_member_ref() {
+        assert_eq!(
+            bundle_member_ref(path::Path::new(
+                "./refs/heads/app/org.test.App/x86_64/stable"
+            )),
+            Some("app/org.test.App/x86_64/stable".to_string())
+        );
+        assert_eq!(bundle_member_ref(path::Path::new("refs/heads")), None);
+        assert_eq!(
+            bundle_member_ref(path::Path::new("refs/heads/../../config")),
+            None
+        );
+        assert_eq!(
+            bundle_member_ref(path::Path::new("refs/remotes/origin/app/org.test.App")),
+            None
+        );
+
+        let commit = "ab".repeat(32);
+        assert_eq!(
+            parse_ref_commit("app/org.test.App", &format!("{commit}\n")).unwrap(),
+            commit
+        );
+        assert!(parse_ref_commit("app/org.test.App", &commit.to_uppercase()).is_err());
+        assert!(parse_ref_commit("app/org.test.App", "ab\n").is_err());
+        assert!(parse_ref_commit("app/org.test.App", &format!("{commit}\n\n")).is_err());
+    }
+
+    #[test]
+    fn test_listed_bundle_bytes() {
+        let listing = "\
+drwxr-xr-x 1000/1000         0 2024-05-01 12:00 objects/
+drwxr-xr-x 1000/1000         0 2024-05-01 12:00 objects/ab/
+-rw-r--r-- 1000/1000      1024 2024-05-01 12:00 objects/ab/cdef.filez
+-rw-r--r-- 1000/1000 5000000000 2024-05-01 12:00 objects/ab/0 1 2 3.filez
+lrwxrwxrwx 1000/1000         0 2024-05-01 12:00 objects/ab/link -> /etc/passwd
+hrw-r--r-- 1000/1000         0 2024-05-01 12:00 objects/ab/hard link to objects/ab/cdef.filez
+crw-r--r-- 0/0             1,3 2024-05-01 12:00 dev/null
+";
+        assert_eq!(listed_bundle_bytes(listing), Ok(5000001024));
+        assert_eq!(listed_bundle_bytes(""), Ok(0));
+
+        /* Anything unexpected is refused rather than counted as nothing */
+        assert!(listed_bundle_bytes("-rw-r--r-- 1000/1000 lots 2024-05-01 12:00 a.filez").is_err());
+        assert!(listed_bundle_bytes("tar: Removing leading `/'").is_err());
+    }
+
+    #[test]
+    fn test_upload_subpath() {
+        let object = format!("{}.filez", "ab".repeat(32));
+        assert_eq!(
+            upload_subpath(&object, false).unwrap(),
+            path::Path::new("objects").join("ab").join(&object[2..])
+        );
+        assert!(upload_subpath(&object, true).is_err());
+        assert!(upload_subpath("../config", false).is_err());
+    }
+
+    #[test]
+    fn test_upload_sessions() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let upload_path = dir.path();
+        let checksum = "ab".repeat(32);
+        let object = path::Path::new("objects")
+            .join("ab")
+            .join(format!("{}.filez", &checksum[2..]));
+
+        assert!(!has_upload_sessions(upload_path).unwrap());
+        let session = create_upload_session(upload_path, FsyncPolicy::Object).unwrap();
+        assert!(has_upload_sessions(upload_path).unwrap());
+        assert_eq!(
+            load_upload_session(upload_path, &session.id).unwrap().0,
+            session
+        );
+        assert!(matches!(
+            load_upload_session(upload_path, "../../../etc"),
+            Err(ApiError::NotFound)
+        ));
+
+        let session_path = upload_session_path(upload_path, &session.id).unwrap();
+        for staged in [object.clone(), path::PathBuf::from("deltas/.partial/x")] {
+            let staged = session_path.join(staged);
+            fs::create_dir_all(staged.parent().unwrap()).unwrap();
+            fs::write(staged, b"x").unwrap();
+        }
+        assert!(!upload_path.join(&object).exists());
+
+        assert_eq!(
+            finish_upload_session(upload_path, &session.id).unwrap(),
+            vec![format!("{checksum}.filez")]
+        );
+        assert!(upload_path.join(&object).exists());
+        assert!(!session_path.exists());
+        assert!(!has_upload_sessions(upload_path).unwrap());
+
+        let aborted = create_upload_session(upload_path, FsyncPolicy::None).unwrap();
+        abort_upload_session(upload_path, &aborted.id).unwrap();
+        assert!(matches!(
+            finish_upload_session(upload_path, &aborted.id),
+            Err(ApiError::NotFound)
+        ));
+    }
 }
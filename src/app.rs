@@ -8,15 +8,20 @@ use std::io;
 use std::path::Path;
 use std::process::Command;
 use std::sync::Arc;
+use std::time::Duration;
 
 use crate::api;
 use crate::api::repo::apply_extra_headers;
+use crate::audit::AuditLog;
 use crate::config::Config;
 use crate::db::Db;
 use crate::deltas::DeltaGenerator;
 use crate::jobs::JobQueue;
 use crate::logger::Logger;
-use crate::tokens::TokenParser;
+use crate::metrics::TokenMetrics;
+use crate::oidc::OidcKeys;
+use crate::ratelimit::RateLimiter;
+use crate::tokens::{PrefixOwners, RevocationCache, RevokedPrefixes, TokenParser};
 use crate::Pool;
 
 fn load_gpg_key(
@@ -49,8 +54,33 @@ pub fn load_config<P: AsRef<Path>>(path: P) -> io::Result<Config> {
 
     config_data.build_gpg_key_content =
         load_gpg_key(&config_data.gpg_homedir, &config_data.build_gpg_key)?;
+    if let Some(path) = &config_data.token_public_key {
+        let pem = std::fs::read(path)?;
+        if jwt::DecodingKey::from_rsa_pem(&pem).is_err()
+            && jwt::DecodingKey::from_ec_pem(&pem).is_err()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("{path:?} is not an RSA or EC public key"),
+            ));
+        }
+        config_data.token_public_key_content = Some(pem);
+    }
+    for quota in &config_data.storage_quotas {
+        quota
+            .validate()
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    }
     for (reponame, repoconfig) in &mut config_data.repos {
         reponame.clone_into(&mut repoconfig.name);
+        for (name, schedule) in &repoconfig.schedules {
+            schedule.validate().map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("Invalid schedule {name} of repo {reponame}: {err}"),
+                )
+            })?;
+        }
         repoconfig.gpg_key_content =
             load_gpg_key(&config_data.gpg_homedir, &config_data.build_gpg_key)?;
     }
@@ -77,20 +107,70 @@ pub fn create_app(
         .clone();
 
     let db = Db(pool);
+    let rate_limiter = Data::new(RateLimiter::new(Duration::from_secs(60)));
+    let revoked_prefixes = Data::new(RevokedPrefixes::new(Duration::from_secs(30)));
+    let prefix_owners = Data::new(PrefixOwners::new(Duration::from_secs(30)));
+    let revocation_cache = Data::new(RevocationCache::new(Duration::from_secs(
+        config.revocation_cache_secs,
+    )));
+    let token_metrics = Data::new(TokenMetrics::default());
+    let token_rate_limiter = Data::new(RateLimiter::new(Duration::from_secs(60)));
+    let public_repo_paths: Vec<String> = config
+        .repos
+        .values()
+        .filter(|repoconfig| repoconfig.public_download)
+        .map(|repoconfig| format!("/repo/{}/", repoconfig.name))
+        .collect();
+    let oidc_keys = config
+        .oidc
+        .clone()
+        .map(|oidc| Data::new(OidcKeys::new(oidc)));
 
     let http_server = HttpServer::new(move || {
         App::new()
             .data(job_queue.clone())
             .data(delta_generator.clone())
             .register_data(Data::new((*c).clone()))
+            .register_data(rate_limiter.clone())
+            .register_data(revoked_prefixes.clone())
+            .register_data(prefix_owners.clone())
+            .register_data(revocation_cache.clone())
+            .register_data(token_metrics.clone())
             .data(db.clone())
-            .wrap(Logger::default())
+            .wrap(Logger::new(c.trusted_proxies.clone()))
             .wrap(middleware::Compress::new(
                 http::header::ContentEncoding::Identity,
             ))
             .service(
                 web::scope("/api/v1")
-                    .wrap(TokenParser::new(db.clone(), &c, &secret))
+                    .wrap(
+                        TokenParser::new(
+                            db.clone(),
+                            &c,
+                            &secret,
+                            revoked_prefixes.clone(),
+                            token_metrics.clone(),
+                            token_rate_limiter.clone(),
+                        )
+                        .with_prefix_owners(prefix_owners.clone())
+                        .with_revocation_cache(revocation_cache.clone()),
+                    )
+                    .wrap(AuditLog::new(db.clone(), c.trusted_proxies.clone()))
+                    .service(
+                        web::resource("/prefix_owners")
+                            .route(web::get().to_async(api::prefix_owners::list_prefix_owners))
+                            .route(web::post().to_async(api::prefix_owners::set_prefix_owner)),
+                    )
+                    .service(
+                        web::resource("/prefix_owners/remove")
+                            .route(web::post().to_async(api::prefix_owners::remove_prefix_owner)),
+                    )
+                    .service(
+                        web::resource("/audit").route(web::get().to_async(api::audit::audit_log)),
+                    )
+                    .service(
+                        web::resource("/quotas").route(web::get().to_async(api::quotas::quotas)),
+                    )
                     .service(
                         web::resource("/tokens/get_list")
                             .route(web::post().to_async(api::tokens::get_tokens)),
@@ -99,15 +179,91 @@ pub fn create_app(
                         web::resource("/tokens/revoke")
                             .route(web::post().to_async(api::tokens::revoke_tokens)),
                     )
+                    .service(
+                        web::resource("/tokens/revoked")
+                            .route(web::get().to_async(api::tokens::revoked_tokens)),
+                    )
+                    .service(
+                        web::resource("/tokens/usage")
+                            .route(web::get().to_async(api::tokens::token_usage)),
+                    )
+                    .service(
+                        web::resource("/tokens/{jti}/unrevoke")
+                            .route(web::post().to_async(api::tokens::unrevoke_token)),
+                    )
+                    .service(
+                        web::resource("/tokens/revoke_prefix")
+                            .route(web::post().to_async(api::tokens::revoke_prefix)),
+                    )
+                    .service(web::resource("/token").route(web::get().to(api::tokens::token_info)))
+                    .service(
+                        web::resource("/token/issue")
+                            .route(web::post().to_async(api::tokens::issue_token)),
+                    )
+                    .service(
+                        web::resource("/token/rotate")
+                            .route(web::post().to_async(api::tokens::rotate_token)),
+                    )
+                    .service(
+                        web::resource("/token/subset")
+                            .route(web::post().to_async(api::build::token_subset)),
+                    )
+                    .service(
+                        web::resource("/token/selftest")
+                            .route(web::post().to(api::tokens::token_selftest)),
+                    )
+                    .service(
+                        web::resource("/authz_check/batch")
+                            .route(web::post().to(api::tokens::authz_check_batch)),
+                    )
                     .service(
                         web::resource("/token_subset")
-                            .route(web::post().to(api::build::token_subset)),
+                            .route(web::post().to_async(api::build::token_subset)),
                     )
                     .service(
                         web::resource("/job/{id}")
                             .name("show_job")
                             .route(web::get().to_async(api::build::get_job)),
                     )
+                    .service(
+                        web::resource("/job/{id}/log")
+                            .route(web::get().to_async(api::job_log::get_job_log)),
+                    )
+                    .service(
+                        web::resource("/job/{id}/cancel")
+                            .route(web::post().to_async(api::build::cancel_job)),
+                    )
+                    .service(
+                        web::resource("/job/{id}/requeue")
+                            .route(web::post().to_async(api::build::requeue_job)),
+                    )
+                    .service(
+                        web::resource("/jobs").route(web::get().to_async(api::build::list_jobs)),
+                    )
+                    .service(
+                        web::resource("/jobs/stats")
+                            .route(web::get().to_async(api::build::job_stats)),
+                    )
+                    .service(
+                        web::resource("/job_worker")
+                            .route(web::post().to_async(api::job_worker::register)),
+                    )
+                    .service(
+                        web::resource("/job_worker/{id}")
+                            .route(web::delete().to_async(api::job_worker::unregister)),
+                    )
+                    .service(
+                        web::resource("/job_worker/{id}/claim")
+                            .route(web::post().to_async(api::job_worker::claim)),
+                    )
+                    .service(
+                        web::resource("/job_worker/{id}/heartbeat")
+                            .route(web::post().to_async(api::job_worker::heartbeat)),
+                    )
+                    .service(
+                        web::resource("/job_worker/{id}/job/{job_id}/finish")
+                            .route(web::post().to_async(api::job_worker::finish_job)),
+                    )
                     .service(
                         web::resource("/job/{id}/check/review")
                             .name("review_check")
@@ -142,14 +298,49 @@ pub fn create_app(
                             .data(web::JsonConfig::default().limit(1024 * 1024 * 10))
                             .route(web::get().to_async(api::build::missing_objects)),
                     )
+                    .service(
+                        web::resource("/build/{id}/check_refs")
+                            .route(web::post().to_async(api::build::check_refs)),
+                    )
+                    .service(
+                        web::resource("/build/{id}/has_objects")
+                            .data(web::JsonConfig::default().limit(1024 * 1024 * 10))
+                            .route(web::post().to_async(api::build::has_objects)),
+                    )
                     .service(
                         web::resource("/build/{id}/add_extra_ids")
                             .route(web::post().to_async(api::build::add_extra_ids)),
                     )
+                    .service(
+                        web::resource("/build/{id}/token")
+                            .route(web::post().to_async(api::build::create_build_token)),
+                    )
                     .service(
                         web::resource("/build/{id}/upload")
                             .route(web::post().to_async(api::build::upload)),
                     )
+                    .service(
+                        web::resource("/build/{id}/upload-bundle")
+                            .route(web::post().to_async(api::build::upload_bundle)),
+                    )
+                    .service(
+                        web::resource("/build/{id}/upload-session")
+                            .route(web::post().to_async(api::build::create_upload_session)),
+                    )
+                    .service(
+                        web::resource("/build/{id}/upload-session/{session}")
+                            .route(web::post().to_async(api::build::finish_upload_session))
+                            .route(web::delete().to_async(api::build::abort_upload_session)),
+                    )
+                    .service(
+                        web::resource("/build/{id}/upload/{filename}")
+                            .route(web::get().to_async(api::build::get_upload_offset))
+                            .route(web::put().to_async(api::build::upload_chunk)),
+                    )
+                    .service(
+                        web::resource("/build/{id}/import")
+                            .route(web::get().to_async(api::build::get_import_job)),
+                    )
                     .service(
                         web::resource("/build/{id}/commit")
                             .name("show_commit_job")
@@ -162,6 +353,23 @@ pub fn create_app(
                             .route(web::post().to_async(api::build::publish))
                             .route(web::get().to_async(api::build::get_publish_job)),
                     )
+                    .service(
+                        web::resource("/build/{id}/appstream")
+                            .route(web::get().to_async(api::build::get_appstream)),
+                    )
+                    .service(
+                        web::resource("/build/{id}/comments")
+                            .route(web::get().to_async(api::build::get_comments))
+                            .route(web::post().to_async(api::build::add_comment)),
+                    )
+                    .service(
+                        web::resource("/build/{id}/approve")
+                            .route(web::post().to_async(api::build::approve)),
+                    )
+                    .service(
+                        web::resource("/build/{id}/transition")
+                            .route(web::post().to_async(api::build::transition)),
+                    )
                     .service(
                         web::resource("/build/{id}/check/{check_name}/job")
                             .name("show_check_job")
@@ -171,10 +379,31 @@ pub fn create_app(
                         web::resource("/build/{id}/purge")
                             .route(web::post().to_async(api::build::purge)),
                     )
+                    .service(
+                        web::resource("/build/{id}/tags")
+                            .route(web::get().to_async(api::build::get_tags))
+                            .route(web::post().to_async(api::build::set_tags)),
+                    )
+                    .service(
+                        web::resource("/build/{id}/diff/{other}")
+                            .route(web::get().to_async(api::build::diff)),
+                    )
+                    .service(
+                        web::resource("/build/{id}/clone")
+                            .route(web::post().to_async(api::build::clone_build)),
+                    )
+                    .service(
+                        web::resource("/build/{id}/pin")
+                            .route(web::post().to_async(api::build::pin)),
+                    )
                     .service(
                         web::resource("/repo/{repo}/republish")
                             .route(web::post().to_async(api::build::republish)),
                     )
+                    .service(
+                        web::resource("/repo/{repo}/prune_deltas")
+                            .route(web::post().to_async(api::delta::prune_deltas)),
+                    )
                     .service(
                         web::resource("/delta/worker").route(web::get().to(api::delta::ws_delta)),
                     )
@@ -185,7 +414,20 @@ pub fn create_app(
             )
             .service(
                 web::scope("/repo")
-                    .wrap(TokenParser::optional(db.clone(), &c, &repo_secret))
+                    .wrap(
+                        TokenParser::optional(
+                            db.clone(),
+                            &c,
+                            &repo_secret,
+                            revoked_prefixes.clone(),
+                            token_metrics.clone(),
+                            token_rate_limiter.clone(),
+                        )
+                        .with_oidc(oidc_keys.clone())
+                        .with_public_paths(public_repo_paths.clone())
+                        .with_prefix_owners(prefix_owners.clone())
+                        .with_revocation_cache(revocation_cache.clone()),
+                    )
                     .wrap_fn(|req, srv| {
                         srv.call(req).map(|mut resp| {
                             apply_extra_headers(&mut resp);
@@ -202,12 +444,24 @@ pub fn create_app(
             )
             .service(
                 web::resource("/build-repo/{id}/{tail:.*}")
-                    .wrap(TokenParser::optional(db.clone(), &c, &secret))
+                    .wrap(
+                        TokenParser::optional(
+                            db.clone(),
+                            &c,
+                            &secret,
+                            revoked_prefixes.clone(),
+                            token_metrics.clone(),
+                            token_rate_limiter.clone(),
+                        )
+                        .with_prefix_owners(prefix_owners.clone())
+                        .with_revocation_cache(revocation_cache.clone()),
+                    )
                     .route(web::get().to_async(api::repo::handle_build_repo))
                     .route(web::head().to_async(api::repo::handle_build_repo))
                     .to(HttpResponse::MethodNotAllowed),
             )
             .service(web::resource("/status").route(web::get().to_async(api::status::status)))
+            .service(web::resource("/metrics").route(web::get().to(api::status::metrics)))
             .service(
                 web::resource("/status/{id}").route(web::get().to_async(api::status::job_status)),
             )
@@ -0,0 +1,170 @@
+use elementtree::Element;
+use flate2::read::GzDecoder;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+/// An icon of a component. Cached icons are shipped next to the appstream data, in
+/// `icons/{width}x{height}/{name}`.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct AppstreamIcon {
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub name: String,
+}
+
+/// The parts of an appstream component that are useful to show what a build contains.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct AppstreamComponent {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub name: Option<String>,
+    pub summary: Option<String>,
+    pub icons: Vec<AppstreamIcon>,
+    /* The URLs of the source images of the screenshots */
+    pub screenshots: Vec<String>,
+}
+
+fn has_lang(element: &Element) -> bool {
+    element.attrs().any(|(name, _)| name.name() == "lang")
+}
+
+/* The text of the first child with the given tag that isn't a translation */
+fn untranslated_text(element: &Element, tag: &str) -> Option<String> {
+    element
+        .children()
+        .find(|child| child.tag().name() == tag && !has_lang(child))
+        .map(|child| child.text().trim().to_string())
+}
+
+fn parse_screenshots(component: &Element) -> Vec<String> {
+    let mut urls = vec![];
+    for screenshots in component.find_all("screenshots") {
+        for screenshot in screenshots.find_all("screenshot") {
+            let images: Vec<&Element> = screenshot.find_all("image").collect();
+            /* Older data may only have one image per screenshot, without a type */
+            let image = images
+                .iter()
+                .find(|image| image.get_attr("type") == Some("source"))
+                .or_else(|| images.first());
+            if let Some(image) = image {
+                urls.push(image.text().trim().to_string());
+            }
+        }
+    }
+    urls
+}
+
+fn parse_component(component: &Element) -> Option<AppstreamComponent> {
+    let id = untranslated_text(component, "id")?;
+    Some(AppstreamComponent {
+        id,
+        kind: component.get_attr("type").map(str::to_string),
+        name: untranslated_text(component, "name"),
+        summary: untranslated_text(component, "summary"),
+        icons: component
+            .find_all("icon")
+            .map(|icon| AppstreamIcon {
+                kind: icon.get_attr("type").map(str::to_string),
+                width: icon.get_attr("width").and_then(|w| w.parse().ok()),
+                height: icon.get_attr("height").and_then(|h| h.parse().ok()),
+                name: icon.text().trim().to_string(),
+            })
+            .collect(),
+        screenshots: parse_screenshots(component),
+    })
+}
+
+pub fn parse_appstream<R: Read>(reader: R) -> Result<Vec<AppstreamComponent>, String> {
+    let root = Element::from_reader(reader).map_err(|e| format!("Invalid appstream data: {e}"))?;
+    Ok(root
+        .find_all("component")
+        .filter_map(parse_component)
+        .collect())
+}
+
+/// Loads the components of a gzipped appstream file, as found in the appstream branches.
+pub fn load_appstream_file(path: &Path) -> Result<Vec<AppstreamComponent>, String> {
+    let file = File::open(path).map_err(|e| format!("Can't open {path:?}: {e}"))?;
+    parse_appstream(GzDecoder::new(BufReader::new(file)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_appstream() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<components version="0.8" origin="flatpak">
+  <component type="desktop-application">
+    <id>org.example.App</id>
+    <name>Example</name>
+    <name xml:lang="de">Beispiel</name>
+    <summary xml:lang="de">Ein Beispiel</summary>
+    <summary>An example</summary>
+    <icon type="cached" height="64" width="64">org.example.App.png</icon>
+    <icon type="stock">org.example.App</icon>
+    <screenshots>
+      <screenshot type="default">
+        <image type="thumbnail" width="224" height="126">https://example.org/thumb.png</image>
+        <image type="source">https://example.org/main.png</image>
+      </screenshot>
+      <screenshot>
+        <image>https://example.org/other.png</image>
+      </screenshot>
+    </screenshots>
+  </component>
+  <component type="runtime">
+    <id>org.example.Platform</id>
+  </component>
+  <component>
+    <name>No ID</name>
+  </component>
+</components>"#;
+
+        let components = parse_appstream(xml.as_bytes()).unwrap();
+        assert_eq!(components.len(), 2);
+
+        let app = &components[0];
+        assert_eq!(app.id, "org.example.App");
+        assert_eq!(app.kind.as_deref(), Some("desktop-application"));
+        assert_eq!(app.name.as_deref(), Some("Example"));
+        assert_eq!(app.summary.as_deref(), Some("An example"));
+        assert_eq!(
+            app.icons,
+            vec![
+                AppstreamIcon {
+                    kind: Some("cached".to_string()),
+                    width: Some(64),
+                    height: Some(64),
+                    name: "org.example.App.png".to_string(),
+                },
+                AppstreamIcon {
+                    kind: Some("stock".to_string()),
+                    width: None,
+                    height: None,
+                    name: "org.example.App".to_string(),
+                },
+            ]
+        );
+        assert_eq!(
+            app.screenshots,
+            vec![
+                "https://example.org/main.png".to_string(),
+                "https://example.org/other.png".to_string(),
+            ]
+        );
+
+        let runtime = &components[1];
+        assert_eq!(runtime.id, "org.example.Platform");
+        assert_eq!(runtime.name, None);
+        assert!(runtime.icons.is_empty());
+
+        assert!(parse_appstream("<components>".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,201 @@
+//! Audit logging middleware
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::Error;
+use actix_web::http::Method;
+use futures::future::{ok, FutureResult};
+use futures::{Future, Poll};
+use futures3::TryFutureExt;
+use std::rc::Rc;
+
+use crate::db::Db;
+use crate::models::NewAuditEntry;
+use crate::net::{request_client_ip, IpNet};
+use crate::tokens::ClaimsValidator;
+
+/// Records the state-changing API calls in the audit_log table, along with who made them and how they went. This
+/// has to wrap the token parser, so that the claims it finds are there by the time the response is.
+pub struct AuditLog(Rc<Inner>);
+
+struct Inner {
+    db: Db,
+    trusted_proxies: Vec<IpNet>,
+}
+
+impl AuditLog {
+    pub fn new(db: Db, trusted_proxies: Vec<IpNet>) -> AuditLog {
+        AuditLog(Rc::new(Inner {
+            db,
+            trusted_proxies,
+        }))
+    }
+}
+
+/// The name of the audited action a request to the API makes, if it makes one.
+pub fn audited_action(method: &Method, path: &str) -> Option<&'static str> {
+    if *method != Method::POST {
+        return None;
+    }
+
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let segments = match segments.as_slice() {
+        ["api", "v1", rest @ ..] => rest,
+        _ => return None,
+    };
+    match segments {
+        ["build"] => Some("create-build"),
+        ["build", _, "commit"] => Some("commit"),
+        ["build", _, "publish"] => Some("publish"),
+        ["build", _, "purge"] => Some("purge"),
+        ["build", _, "pin"] => Some("pin-build"),
+        ["build", _, "clone"] => Some("clone-build"),
+        ["build", _, "approve"] => Some("approve-build"),
+        ["build", _, "transition"] => Some("transition-build"),
+        ["repo", _, "republish"] => Some("republish"),
+        ["job", _, "cancel"] => Some("cancel-job"),
+        ["job", _, "requeue"] => Some("requeue-job"),
+        ["job", _, "check", "review"] => Some("review-check"),
+        ["tokens", "revoke"] => Some("revoke-tokens"),
+        ["tokens", "revoke_prefix"] => Some("revoke-prefix"),
+        ["tokens", _, "unrevoke"] => Some("unrevoke-token"),
+        _ => None,
+    }
+}
+
+impl Inner {
+    fn entry<B>(&self, action: &str, resp: &ServiceResponse<B>) -> NewAuditEntry {
+        let req = resp.request();
+        let claims = req.get_claims();
+        NewAuditEntry {
+            token_sub: claims.as_ref().map(|claims| claims.sub.clone()),
+            token_jti: claims.as_ref().and_then(|claims| claims.jti.clone()),
+            scopes: claims
+                .map(|claims| claims.scope.iter().map(|s| s.to_string()).collect())
+                .unwrap_or_default(),
+            client_ip: request_client_ip(req.peer_addr(), req.headers(), &self.trusted_proxies)
+                .map(|ip| ip.to_string()),
+            action: action.to_string(),
+            path: req.path().to_string(),
+            outcome: resp.status().as_u16() as i16,
+        }
+    }
+}
+
+/* A failure to record is logged rather than failing the request, which has already happened */
+async fn record(db: Db, entry: NewAuditEntry) -> Result<(), ()> {
+    if let Err(e) = db.add_audit_entry(entry).await {
+        log::warn!("Failed to record audit log entry: {e}");
+    }
+    Ok(())
+}
+
+impl<S: 'static, B> Transform<S> for AuditLog
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AuditLogMiddleware<S>;
+    type Future = FutureResult<Self::Transform, Self::InitError>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AuditLogMiddleware {
+            service,
+            inner: self.0.clone(),
+        })
+    }
+}
+
+/// AuditLog middleware
+pub struct AuditLogMiddleware<S> {
+    service: S,
+    inner: Rc<Inner>,
+}
+
+impl<S, B> Service for AuditLogMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.service.poll_ready()
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let action = match audited_action(req.method(), req.path()) {
+            Some(action) => action,
+            None => return Box::new(self.service.call(req)),
+        };
+
+        let inner = self.inner.clone();
+        Box::new(self.service.call(req).and_then(move |resp| {
+            let entry = inner.entry(action, &resp);
+            Box::pin(record(inner.db.clone(), entry))
+                .compat()
+                .then(move |_| Ok(resp))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audited_action() {
+        assert_eq!(
+            audited_action(&Method::POST, "/api/v1/build"),
+            Some("create-build")
+        );
+        assert_eq!(
+            audited_action(&Method::POST, "/api/v1/build/12/publish"),
+            Some("publish")
+        );
+        assert_eq!(
+            audited_action(&Method::POST, "/api/v1/build/12/purge"),
+            Some("purge")
+        );
+        assert_eq!(
+            audited_action(&Method::POST, "/api/v1/tokens/revoke"),
+            Some("revoke-tokens")
+        );
+        assert_eq!(
+            audited_action(&Method::POST, "/api/v1/tokens/abc/unrevoke"),
+            Some("unrevoke-token")
+        );
+        assert_eq!(
+            audited_action(&Method::POST, "/api/v1/job/7/cancel"),
+            Some("cancel-job")
+        );
+        assert_eq!(
+            audited_action(&Method::POST, "/api/v1/job/7/requeue"),
+            Some("requeue-job")
+        );
+
+        /* Reads and uploads aren't audited */
+        assert_eq!(
+            audited_action(&Method::GET, "/api/v1/build/12/publish"),
+            None
+        );
+        assert_eq!(audited_action(&Method::GET, "/api/v1/build"), None);
+        assert_eq!(
+            audited_action(&Method::POST, "/api/v1/build/12/upload"),
+            None
+        );
+        assert_eq!(
+            audited_action(&Method::POST, "/api/v1/build/12/publish/x"),
+            None
+        );
+        assert_eq!(audited_action(&Method::POST, "/build/12/publish"), None);
+    }
+}
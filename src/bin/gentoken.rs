@@ -6,6 +6,7 @@ use std::io::prelude::*;
 use std::process;
 
 use argparse::{ArgumentParser, List, Store, StoreOption, StoreTrue};
+use flatmanager::curl_example;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -16,6 +17,7 @@ struct Claims {
     prefixes: Vec<String>,
     repos: Vec<String>,
     exp: i64,
+    iat: i64,
     token_type: String,
     branches: Vec<String>,
 }
@@ -44,6 +46,8 @@ fn main() {
     let mut repos: Vec<String> = vec![];
     let mut token_type: String = "app".to_string();
     let mut branches: Vec<String> = vec![];
+    let mut token_prefix: Option<String> = None;
+    let mut curl_url: Option<String> = None;
     {
         let mut ap = ArgumentParser::new();
         ap.set_description("Generate token for flat-manager.");
@@ -89,6 +93,16 @@ fn main() {
             List,
             "Add branch (default if none: ['stable']",
         );
+        ap.refer(&mut token_prefix).add_option(
+            &["--token-prefix"],
+            StoreOption,
+            "Token prefix configured on the server, used in the curl example",
+        );
+        ap.refer(&mut curl_url).add_option(
+            &["--curl-example"],
+            StoreOption,
+            "Also print a curl command calling this URL with the token",
+        );
         ap.parse_args_or_exit();
     }
 
@@ -144,6 +158,7 @@ fn main() {
         repos,
         name: name.clone(),
         exp: Utc::now().timestamp() + duration,
+        iat: Utc::now().timestamp(),
         token_type,
         branches,
     };
@@ -154,4 +169,8 @@ fn main() {
 
     let token = encode(&Header::default(), &claims, &key).unwrap();
     println!("{token}");
+
+    if let Some(url) = curl_url {
+        println!("{}", curl_example(&url, token_prefix.as_deref(), &token));
+    }
 }
@@ -0,0 +1,48 @@
+use dotenv::dotenv;
+use std::env;
+use std::path::PathBuf;
+
+use flatmanager::JobWorkerSettings;
+
+#[tokio::main]
+async fn main() {
+    if env::var("RUST_LOG").is_err() {
+        env::set_var("RUST_LOG", "info");
+    }
+    env_logger::init();
+    let sys = actix::System::new("job-worker");
+
+    dotenv().ok();
+
+    let config_path =
+        PathBuf::from(env::var("REPO_CONFIG").unwrap_or_else(|_| "config.json".to_string()));
+    let token = env::var("REPO_TOKEN").expect("No token, set REPO_TOKEN in env or .env");
+    let url = env::var("MANAGER_URL").unwrap_or_else(|_| "http://127.0.0.1:8080".to_string());
+    let name = env::var("WORKER_NAME")
+        .or_else(|_| env::var("HOSTNAME"))
+        .unwrap_or_else(|_| "job-worker".to_string());
+    let kinds = env::var("WORKER_KINDS")
+        .map(|kinds| {
+            kinds
+                .split(',')
+                .map(str::trim)
+                .filter(|kind| !kind.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let config = flatmanager::load_config(&config_path);
+
+    flatmanager::start_job_worker(
+        &config,
+        JobWorkerSettings {
+            url,
+            token,
+            name,
+            kinds,
+        },
+    );
+
+    let _ = sys.run();
+}
@@ -1,11 +1,15 @@
 use base64::{engine::general_purpose, Engine as _};
-use serde::Deserialize;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::Command;
 
 use crate::errors::ApiError;
+use crate::models::JobKind;
+use crate::net::IpNet;
+use crate::tokens::{id_matches_prefix, ClaimsScope, Endpoint};
 
 #[derive(Deserialize, Debug, Clone)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
@@ -34,6 +38,31 @@ pub struct SubsetConfig {
     pub base_url: Option<String>,
 }
 
+/// A job queued for a repo at a regular interval.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ScheduleConfig {
+    pub job: ScheduledJob,
+    pub interval_secs: u64,
+    /* Each run is delayed by a random number of seconds up to this, so schedules with the same interval don't all
+     * queue their jobs at once */
+    #[serde(default)]
+    pub jitter_secs: u64,
+}
+
+/// The kinds of jobs that can be scheduled, with their arguments. Updating the repo regenerates its summary and
+/// any missing deltas.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", tag = "kind", deny_unknown_fields)]
+pub enum ScheduledJob {
+    UpdateRepo,
+    #[serde(rename_all = "kebab-case")]
+    PruneDeltas {
+        older_than_days: Option<u32>,
+        keep_commits: Option<u32>,
+    },
+}
+
 /// A command to run during the build/publish process. Given as an array of arguments, with the first argument being
 /// the path to the program. Arguments are not processed by a shell; they are passed directly to the program.
 #[derive(Deserialize, Debug, Default, Clone)]
@@ -63,6 +92,180 @@ pub struct ConfigHooks {
     pub checks: HashMap<String, CheckHook>,
 }
 
+/// What a client certificate may do, see `client-certs`. These are the claims it stands in for.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ClientCertIdentity {
+    #[serde(default = "default_sub")]
+    pub sub: String,
+    pub scope: Vec<ClaimsScope>,
+    #[serde(default)]
+    pub prefixes: Vec<String>,
+    #[serde(default)]
+    pub apps: Vec<String>,
+    #[serde(default)]
+    pub repos: Vec<String>,
+    #[serde(default)]
+    pub branches: Vec<String>,
+}
+
+/// A kind of token that can be issued through the API, see `token-templates`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct TokenTemplate {
+    #[serde(default = "default_sub")]
+    pub sub: String,
+    pub scope: Vec<ClaimsScope>,
+    #[serde(default)]
+    pub prefixes: Vec<String>,
+    #[serde(default)]
+    pub apps: Vec<String>,
+    #[serde(default)]
+    pub repos: Vec<String>,
+    #[serde(default)]
+    pub branches: Vec<String>,
+    /* How long issued tokens last, in seconds. They may be asked to expire sooner. */
+    pub duration: i64,
+}
+
+/// Changes the scopes an endpoint accepts, see `endpoint-scopes`.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct EndpointScopes {
+    /* Replaces the scopes the endpoint accepts, any one of which is enough */
+    pub any_of: Option<Vec<ClaimsScope>>,
+    /* Scopes a token must also all have */
+    #[serde(default)]
+    pub all_of: Vec<ClaimsScope>,
+}
+
+/// When files uploaded to builds are flushed to disk, see `upload-fsync`.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FsyncPolicy {
+    /* Each file is synced before it is moved into place, and its directory after */
+    Object,
+    /* The filesystem is synced once at the end of each upload request or upload session */
+    Batch,
+    /* Flushing is left to the OS. The commit job still syncs the repo it commits to. */
+    #[default]
+    None,
+}
+
+/// A limit on the storage taken up by builds, see `storage-quotas`. It applies either to the builds of apps with an
+/// ID prefix, to the builds created by tokens with a name, or to the builds created by tokens with a sub. Builds only
+/// count while they have a build repo, that is until they are published or purged.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct StorageQuota {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    /* The bytes that may be uploaded to the builds */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_builds: Option<u64>,
+}
+
+/// What a storage quota applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaKey<'a> {
+    Prefix(&'a str),
+    TokenName(&'a str),
+    Sub(&'a str),
+}
+
+impl StorageQuota {
+    /// What the quota applies to, or None if it doesn't have exactly one key, which the config is refused for.
+    pub fn key(&self) -> Option<QuotaKey<'_>> {
+        match (&self.prefix, &self.token_name, &self.sub) {
+            (Some(prefix), None, None) => Some(QuotaKey::Prefix(prefix)),
+            (None, Some(token_name), None) => Some(QuotaKey::TokenName(token_name)),
+            (None, None, Some(sub)) => Some(QuotaKey::Sub(sub)),
+            _ => None,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self.key() {
+            Some(_) => Ok(()),
+            None => {
+                Err("Storage quotas need exactly one of prefix, token-name and sub".to_string())
+            }
+        }
+    }
+
+    pub fn applies_to(
+        &self,
+        app_id: Option<&str>,
+        token_name: Option<&str>,
+        token_sub: Option<&str>,
+    ) -> bool {
+        match self.key() {
+            Some(QuotaKey::Prefix(prefix)) => {
+                app_id.map_or(false, |app_id| id_matches_prefix(app_id, prefix))
+            }
+            Some(QuotaKey::TokenName(quota_token_name)) => token_name == Some(quota_token_name),
+            Some(QuotaKey::Sub(sub)) => token_sub == Some(sub),
+            None => false,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self.key() {
+            Some(QuotaKey::Prefix(prefix)) => format!("the storage quota of prefix '{prefix}'"),
+            Some(QuotaKey::TokenName(token_name)) => {
+                format!("the storage quota of token '{token_name}'")
+            }
+            Some(QuotaKey::Sub(sub)) => format!("the storage quota of sub '{sub}'"),
+            None => "a storage quota".to_string(),
+        }
+    }
+}
+
+/* The number of approvals needed by a build with the given IDs, see `required-approvals` */
+pub fn required_approvals<'a>(
+    required: &HashMap<String, u32>,
+    ids: impl IntoIterator<Item = &'a str>,
+) -> u32 {
+    ids.into_iter()
+        .flat_map(|id| {
+            required
+                .iter()
+                .filter(move |(prefix, _)| id_matches_prefix(id, prefix))
+                .map(|(_, count)| *count)
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// An external OpenID Connect issuer whose tokens may be used to download from the repos.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct OidcConfig {
+    /* The issuer URL, which must match the iss claim and serve the discovery document */
+    pub issuer: String,
+    /* The aud claim that tokens must have, usually the client ID registered for flat-manager */
+    pub audience: String,
+    /* The app prefixes tokens from the issuer may download, or all if empty */
+    #[serde(default)]
+    pub prefixes: Vec<String>,
+    #[serde(default = "default_jwks_refresh_secs")]
+    pub jwks_refresh_secs: u64,
+}
+
+fn default_jwks_refresh_secs() -> u64 {
+    60 * 60
+}
+
+fn default_sub() -> String {
+    "build".to_string()
+}
+
 fn default_depth() -> u32 {
     5
 }
@@ -94,6 +297,34 @@ pub struct RepoConfig {
     pub deltas: Vec<DeltaConfig>,
     #[serde(default = "default_depth")]
     pub appstream_delta_depth: u32,
+    /// The maximum number of bytes a single upload request may send to a build of this repo.
+    pub max_upload_bytes: Option<u64>,
+    /// The maximum number of build creation and upload requests per minute a single token may make against this repo.
+    pub max_requests_per_minute: Option<u32>,
+    /// Lets anyone download everything in the repo, whatever the token type of its commits. Tokens sent along with
+    /// downloads are ignored, so expired ones don't break them. The API still needs tokens.
+    #[serde(default)]
+    pub public_download: bool,
+    /// Builds that were never published are purged this many days after they were created, unless they are pinned.
+    pub build_retention_days: Option<u32>,
+    /// Refuses uploads to builds of this repo that don't come with the SHA256 of each uploaded file.
+    #[serde(default)]
+    pub require_upload_checksums: bool,
+    /// How many approvals builds of the apps and runtimes inside each prefix need before they can be published. A
+    /// build needs the most approvals of any prefix matching one of its IDs.
+    #[serde(default)]
+    pub required_approvals: HashMap<String, u32>,
+    /// How uploads to builds of this repo are flushed to disk, unless an upload session asks for another policy.
+    #[serde(default)]
+    pub upload_fsync: FsyncPolicy,
+    /// Overrides the priorities of the kinds of jobs that run for this repo, such as `publish`, `update-repo` and
+    /// `prune-deltas`. Jobs with a higher priority are run first.
+    #[serde(default)]
+    pub job_priorities: HashMap<JobKind, i32>,
+    /// Jobs queued for this repo at regular intervals, by name. When each schedule runs next is kept in the database,
+    /// so restarts don't delay or repeat runs.
+    #[serde(default)]
+    pub schedules: HashMap<String, ScheduleConfig>,
 }
 
 fn default_host() -> String {
@@ -104,10 +335,34 @@ fn default_port() -> i32 {
     8080
 }
 
+fn default_token_algorithms() -> Vec<jwt::Algorithm> {
+    vec![jwt::Algorithm::HS256]
+}
+
 fn default_numcpu() -> u32 {
     num_cpus::get() as u32
 }
 
+fn default_build_job_workers() -> usize {
+    1
+}
+
+fn default_job_max_attempts() -> i32 {
+    3
+}
+
+fn default_job_retry_delay_secs() -> u64 {
+    30
+}
+
+fn default_claims_cache_size() -> usize {
+    1024
+}
+
+fn default_expired_token_log_level() -> log::Level {
+    log::Level::Warn
+}
+
 #[derive(Deserialize, Debug, Clone)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct Config {
@@ -122,10 +377,142 @@ pub struct Config {
 
     #[serde(deserialize_with = "from_base64")]
     pub secret: Vec<u8>,
+    /* Secrets that tokens were signed with before the current one, which are still accepted so
+     * that rotating the secret doesn't break every token in use. New tokens are only ever signed
+     * with the current secret. */
+    #[serde(default, deserialize_with = "from_base64_list")]
+    pub previous_secrets: Vec<Vec<u8>>,
     #[serde(default, deserialize_with = "from_opt_base64")]
     pub repo_secret: Option<Vec<u8>>,
     /* If token_prefix is set, auth tokens may optionally be prefixed with it. */
     pub token_prefix: Option<String>,
+    /* If claims-cache-ttl-secs is set, verified token claims are cached for that long, so that
+     * clients sending the same token many times don't pay for decoding it each time. */
+    #[serde(default)]
+    pub claims_cache_ttl_secs: u64,
+    #[serde(default = "default_claims_cache_size")]
+    pub claims_cache_size: usize,
+    /* If set, tokens found not to be revoked aren't checked against the database again for this
+     * many seconds. Revocations through this instance's API apply at once anyway, but those made
+     * through other instances sharing the database can take this long to. */
+    #[serde(default)]
+    pub revocation_cache_secs: u64,
+    /* Tokens whose "ver" claim is below this are rejected, to retire old claim schemas */
+    #[serde(default)]
+    pub min_token_version: u32,
+    /* Peers in these networks are proxies whose X-Forwarded-* headers are believed */
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+    /* Tokens with the token-management, generate or republish scope are normally only accepted
+     * over HTTPS. This allows them over plain HTTP, for local development. */
+    #[serde(default)]
+    pub allow_insecure_privileged_tokens: bool,
+    /* Hex SHA-256 of a static admin token for disaster recovery. When presented, it is accepted
+     * with the token-management scope without any database checks, so leave it unset unless the
+     * database is down and you need a way back in. */
+    #[serde(default, deserialize_with = "from_opt_sha256")]
+    pub break_glass_token_sha256: Option<Vec<u8>>,
+    /* For this many seconds after a token is revoked, it may still be used to upload to and commit
+     * builds that were created before the revocation, so that uploads in progress can finish. This
+     * only applies to builds the token created, or that the token is a build token of. */
+    #[serde(default)]
+    pub revocation_grace_seconds: u64,
+    /* For this many seconds after a token is revoked, the revocation can be undone with the
+     * unrevoke endpoint. Afterwards it is final, so that a token that really was compromised can't
+     * be turned back on by mistake. */
+    #[serde(default)]
+    pub revocation_undo_seconds: u64,
+    /* Tokens with an allowed_hours claim are always refused for writes outside those hours. Reads
+     * outside them are only logged, unless this is set. */
+    #[serde(default)]
+    pub enforce_allowed_hours_for_reads: bool,
+    /* Level at which tokens rejected for being expired are logged, e.g. "info" for setups where
+     * CI jobs routinely outlive their tokens. Other rejected tokens are always logged as warnings. */
+    #[serde(
+        default = "default_expired_token_log_level",
+        deserialize_with = "from_log_level"
+    )]
+    pub expired_token_log_level: log::Level,
+    /* Scopes given to old tokens that were minted without a scope claim at all. Tokens that
+     * explicitly have an empty scope are left alone. */
+    #[serde(default)]
+    pub legacy_scope_default: Vec<ClaimsScope>,
+    /* Scopes for tokens from identity providers that carry a "groups" claim instead of scopes,
+     * e.g. {"flatpak-publishers": ["build", "upload", "publish"]}. Only used for tokens without
+     * any scope of their own. */
+    #[serde(default)]
+    pub group_scopes: HashMap<String, Vec<ClaimsScope>>,
+    /* If set, tokens whose sub doesn't fully match one of these regexes are rejected, e.g.
+     * ["build", "build/[0-9]+"]. Management tokens with an empty sub need "" in the list. */
+    #[serde(default, deserialize_with = "from_sub_patterns")]
+    pub allowed_sub_patterns: Vec<Regex>,
+    /* Some identity providers nest custom claims under a namespace key, such as
+     * "https://flathub.org/claims". If set, the claims under this key are read as if they were at
+     * the top level of the token. */
+    pub claims_namespace: Option<String>,
+    /* If set, once this many requests in a worker are waiting on revocation checks, further ones
+     * are turned away with a 429 instead of queuing up behind a slow database. */
+    pub revocation_queue_reject_after: Option<usize>,
+    /* Prefixes that tokens with the given scopes must be presented with, so that privileged tokens
+     * stand out, e.g. {"tokenmanagement": "mgmt_"} for "Bearer mgmt_eyJ...". */
+    #[serde(default)]
+    pub scope_token_prefixes: HashMap<ClaimsScope, String>,
+    /* Percent-decode bearer tokens, for proxies that URL-encode the Authorization header */
+    #[serde(default)]
+    pub decode_url_encoded_token: bool,
+    /* App tokens (token_type "app") with neither prefixes nor apps would be allowed every app,
+     * which is almost always a minting mistake, so they are rejected unless this is set. */
+    #[serde(default)]
+    pub allow_unscoped_app_tokens: bool,
+    /* If set, creating a build also returns a token that lasts this many seconds and is limited to
+     * uploading to and publishing the new build, so CI doesn't have to subset its token itself */
+    pub build_token_seconds: Option<i64>,
+    /* Endpoints that tokens with a purpose claim may use, by purpose, e.g.
+     * {"nightly-publish": ["commit", "get-commit-job", "publish", "get-publish-job"]}. Tokens
+     * with a purpose not listed here are refused, unless nothing is listed at all. */
+    #[serde(default)]
+    pub token_purposes: HashMap<String, Vec<Endpoint>>,
+    /* Requests per minute allowed for each token (by jti, or sub without one), by scope, e.g.
+     * {"upload": 600, "publish": 60}. A token gets the largest budget of its scopes, and tokens
+     * with none of the listed scopes aren't limited. */
+    #[serde(default)]
+    pub scope_rate_limits: HashMap<ClaimsScope, u32>,
+    /* Overrides the scopes endpoints require, by endpoint, e.g.
+     * {"purge": {"all-of": ["tokenmanagement"]}} to also require the tokenmanagement scope for
+     * purging, or {"republish": {"any-of": ["build"]}} to accept build tokens instead. */
+    #[serde(default)]
+    pub endpoint_scopes: HashMap<Endpoint, EndpointScopes>,
+    /* Named kinds of tokens that tokenmanagement tokens can issue with POST /api/v1/token/issue,
+     * e.g. {"ci-upload": {"scope": ["build", "upload"], "repos": ["stable"], "duration": 86400}} */
+    #[serde(default)]
+    pub token_templates: HashMap<String, TokenTemplate>,
+    /* Limits on the storage taken up by builds, e.g.
+     * [{"prefix": "org.example", "max-bytes": 10000000000, "max-builds": 20}] or
+     * [{"token-name": "nightly-ci", "max-builds": 5}]. Each needs exactly one of prefix and
+     * token-name, and every quota that applies to a build is enforced. */
+    #[serde(default)]
+    pub storage_quotas: Vec<StorageQuota>,
+    /* Builds can be created by pulling refs from ostree repositories at URLs inside one of these,
+     * e.g. "https://staging.example.org/repo/". Importing is disabled if empty. */
+    #[serde(default)]
+    pub import_url_prefixes: Vec<String>,
+    /* For deployments behind a TLS-terminating proxy that verifies client certificates: the
+     * header the proxy puts the verified subject CN in, e.g. "X-SSL-Client-CN". Requests from
+     * trusted proxies without an Authorization header then get the claims listed for the CN in
+     * client-certs. The header is ignored from anyone else. */
+    pub client_cert_header: Option<String>,
+    #[serde(default)]
+    pub client_certs: HashMap<String, ClientCertIdentity>,
+    /* Also accept repo download tokens from this OpenID Connect issuer */
+    pub oidc: Option<OidcConfig>,
+    /* PEM file with the RSA or EC public key of an external token issuer, so that it doesn't need
+     * the secret. Tokens signed with it must also have their algorithm in token-algorithms. */
+    pub token_public_key: Option<PathBuf>,
+    #[serde(skip)]
+    pub token_public_key_content: Option<Vec<u8>>,
+    /* Signing algorithms accepted in token headers, e.g. ["HS256", "ES256"] */
+    #[serde(default = "default_token_algorithms")]
+    pub token_algorithms: Vec<jwt::Algorithm>,
 
     pub repos: HashMap<String, RepoConfig>,
     pub build_repo_base: PathBuf,
@@ -136,6 +523,22 @@ pub struct Config {
     pub delay_update_secs: u64,
     #[serde(default = "default_numcpu")]
     pub local_delta_threads: u32,
+    /* How many build jobs, such as commits, checks and imports, may run at once. The jobs of each
+     * repo, such as publishing and updating it, always run one at a time, but alongside those of
+     * the other repos. */
+    #[serde(default = "default_build_job_workers")]
+    pub build_job_workers: usize,
+    /* Jobs failing with a transient error, such as a network timeout or a locked repo, are queued
+     * again until they have been tried this many times. The first retry waits
+     * job-retry-delay-secs, and every following one twice as long as the one before. */
+    #[serde(default = "default_job_max_attempts")]
+    pub job_max_attempts: i32,
+    #[serde(default = "default_job_retry_delay_secs")]
+    pub job_retry_delay_secs: u64,
+    /* How many seconds jobs of each kind may run before they are stopped, e.g.
+     * {"update-repo": 7200, "commit": 3600}. Kinds not listed can run for as long as they take. */
+    #[serde(default)]
+    pub job_timeouts: HashMap<JobKind, u64>,
     pub storefront_info_endpoint: Option<String>,
 }
 
@@ -150,6 +553,28 @@ impl ConfigHook {
     }
 }
 
+impl ScheduleConfig {
+    /* Catches what the API would refuse when queueing the job by hand */
+    pub fn validate(&self) -> Result<(), String> {
+        if self.interval_secs == 0 {
+            return Err("interval-secs must be at least 1".to_string());
+        }
+        if let ScheduledJob::PruneDeltas {
+            older_than_days,
+            keep_commits,
+        } = &self.job
+        {
+            if older_than_days.is_none() && keep_commits.is_none() {
+                return Err("Either older-than-days or keep-commits is required".to_string());
+            }
+            if *keep_commits == Some(0) {
+                return Err("keep-commits must be at least 1".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
 impl RepoConfig {
     pub fn get_abs_repo_path(&self) -> PathBuf {
         let mut repo_path = std::env::current_dir().unwrap_or_else(|_e| PathBuf::from("/"));
@@ -253,6 +678,23 @@ where
     })
 }
 
+fn from_base64_list<'de, D>(deserializer: D) -> Result<Vec<Vec<u8>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+    Vec::<String>::deserialize(deserializer).and_then(|strings| {
+        strings
+            .iter()
+            .map(|string| {
+                general_purpose::STANDARD
+                    .decode(string)
+                    .map_err(|err| Error::custom(err.to_string()))
+            })
+            .collect()
+    })
+}
+
 fn from_opt_base64<'de, D>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error>
 where
     D: serde::Deserializer<'de>,
@@ -267,6 +709,49 @@ where
         .map(Some)
 }
 
+fn from_log_level<'de, D>(deserializer: D) -> Result<log::Level, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+    String::deserialize(deserializer).and_then(|string| {
+        string
+            .parse()
+            .map_err(|_| Error::custom("invalid log level"))
+    })
+}
+
+fn from_opt_sha256<'de, D>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+    String::deserialize(deserializer)
+        .and_then(|string| hex::decode(string.trim()).map_err(|err| Error::custom(err.to_string())))
+        .and_then(|hash| {
+            if hash.len() == 32 {
+                Ok(Some(hash))
+            } else {
+                Err(Error::custom("expected a SHA-256 hash in hex"))
+            }
+        })
+}
+
+fn from_sub_patterns<'de, D>(deserializer: D) -> Result<Vec<Regex>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+    Vec::<String>::deserialize(deserializer).and_then(|patterns| {
+        patterns
+            .iter()
+            /* Anchored, so that "build" doesn't also allow "rebuild/1" */
+            .map(|pattern| Regex::new(&format!("^(?:{pattern})$")))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| Error::custom(err.to_string()))
+    })
+}
+
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
@@ -294,4 +779,126 @@ mod tests {
         assert!(match_glob("foo*gazonk*test", "foobargazonkWOOtest"));
         assert!(!match_glob("foo*gazonk*test", "foobargazonkWOOtestXX"));
     }
+
+    #[test]
+    fn test_storage_quota_applies_to() {
+        let quota: StorageQuota =
+            serde_json::from_str(r#"{"prefix": "org.example", "max-builds": 2}"#).unwrap();
+        assert!(quota.validate().is_ok());
+        assert!(quota.applies_to(Some("org.example.App"), None, None));
+        assert!(quota.applies_to(Some("org.example"), Some("ci"), Some("build")));
+        assert!(!quota.applies_to(Some("org.examples.App"), None, None));
+        assert!(!quota.applies_to(None, None, None));
+
+        let quota: StorageQuota =
+            serde_json::from_str(r#"{"token-name": "ci", "max-bytes": 1000}"#).unwrap();
+        assert!(quota.validate().is_ok());
+        assert!(quota.applies_to(None, Some("ci"), None));
+        assert!(quota.applies_to(Some("org.example.App"), Some("ci"), None));
+        assert!(!quota.applies_to(Some("org.example.App"), Some("other"), Some("ci")));
+
+        let quota: StorageQuota = serde_json::from_str(r#"{"sub": "ci"}"#).unwrap();
+        assert!(quota.validate().is_ok());
+        assert_eq!(quota.key(), Some(QuotaKey::Sub("ci")));
+        assert!(quota.applies_to(None, Some("other"), Some("ci")));
+        assert!(!quota.applies_to(None, Some("ci"), Some("build")));
+        assert_eq!(quota.describe(), "the storage quota of sub 'ci'");
+
+        /* A quota applies to exactly one thing */
+        for invalid in [
+            r#"{"prefix": "org.example", "token-name": "ci"}"#,
+            r#"{"token-name": "ci", "sub": "ci"}"#,
+            r#"{"max-bytes": 1000}"#,
+        ] {
+            let quota: StorageQuota = serde_json::from_str(invalid).unwrap();
+            assert!(quota.validate().is_err(), "{invalid}");
+            assert!(!quota.applies_to(Some("org.example.App"), Some("ci"), Some("ci")));
+        }
+    }
+
+    #[test]
+    fn test_required_approvals() {
+        let required = HashMap::from([
+            ("org.example".to_string(), 1),
+            ("org.example.Critical".to_string(), 2),
+        ]);
+        assert_eq!(required_approvals(&required, ["org.example.App"]), 1);
+        assert_eq!(
+            required_approvals(&required, ["org.example.App", "org.example.Critical"]),
+            2
+        );
+        assert_eq!(
+            required_approvals(&required, ["org.example.Critical.Locale"]),
+            2
+        );
+        assert_eq!(required_approvals(&required, ["org.other.App"]), 0);
+        assert_eq!(required_approvals(&required, []), 0);
+    }
+
+    #[test]
+    fn test_job_priorities() {
+        let repoconfig: RepoConfig = serde_json::from_str(
+            r#"{"path": "repo", "subsets": {}, "job-priorities": {"update-repo": 40, "prune-deltas": 0}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            repoconfig.job_priorities.get(&JobKind::UpdateRepo),
+            Some(&40)
+        );
+        assert_eq!(repoconfig.job_priorities.get(&JobKind::Publish), None);
+
+        assert!(serde_json::from_str::<RepoConfig>(
+            r#"{"path": "repo", "subsets": {}, "job-priorities": {"deltas": 1}}"#
+        )
+        .is_err());
+
+        assert!(JobKind::Publish.default_priority() > JobKind::UpdateRepo.default_priority());
+        assert!(JobKind::UpdateRepo.default_priority() > JobKind::PruneDeltas.default_priority());
+    }
+
+    #[test]
+    fn test_schedules() {
+        let repoconfig: RepoConfig = serde_json::from_str(
+            r#"{"path": "repo", "subsets": {}, "schedules": {
+                "nightly-prune": {"job": {"kind": "prune-deltas", "older-than-days": 30}, "interval-secs": 86400,
+                                  "jitter-secs": 3600},
+                "summary": {"job": {"kind": "update-repo"}, "interval-secs": 3600}
+            }}"#,
+        )
+        .unwrap();
+        let prune = &repoconfig.schedules["nightly-prune"];
+        assert_eq!(
+            prune.job,
+            ScheduledJob::PruneDeltas {
+                older_than_days: Some(30),
+                keep_commits: None
+            }
+        );
+        assert_eq!(prune.interval_secs, 86400);
+        assert_eq!(prune.jitter_secs, 3600);
+        let summary = &repoconfig.schedules["summary"];
+        assert_eq!(summary.job, ScheduledJob::UpdateRepo);
+        assert_eq!(summary.jitter_secs, 0);
+        assert!(prune.validate().is_ok());
+        assert!(summary.validate().is_ok());
+
+        let invalid: ScheduleConfig = serde_json::from_str(
+            r#"{"job": {"kind": "prune-deltas", "keep-commits": 0}, "interval-secs": 60}"#,
+        )
+        .unwrap();
+        assert!(invalid.validate().is_err());
+        let invalid: ScheduleConfig =
+            serde_json::from_str(r#"{"job": {"kind": "prune-deltas"}, "interval-secs": 60}"#)
+                .unwrap();
+        assert!(invalid.validate().is_err());
+        let invalid: ScheduleConfig =
+            serde_json::from_str(r#"{"job": {"kind": "update-repo"}, "interval-secs": 0}"#)
+                .unwrap();
+        assert!(invalid.validate().is_err());
+
+        assert!(serde_json::from_str::<RepoConfig>(
+            r#"{"path": "repo", "subsets": {}, "schedules": {"x": {"job": {"kind": "publish"}, "interval-secs": 60}}}"#
+        )
+        .is_err());
+    }
 }
@@ -1,15 +1,21 @@
 use actix::prelude::*;
 use actix_web::*;
 use chrono::Utc;
+use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel::sql_types::Nullable;
 use diesel::sql_types::Timestamp;
 use futures3::compat::Compat01As03;
 use serde_json::json;
 
+use crate::config::{QuotaKey, StorageQuota};
 use crate::errors::ApiError;
 use crate::models::*;
 use crate::schema;
+use crate::tokens::{
+    budget_root, check_minted_jti, check_unrevoke, revoked_in_chain, within_revocation_grace,
+    GraceBuild, PendingTokenUse, RevocationGrace,
+};
 use crate::Pool;
 
 #[derive(Clone)]
@@ -55,11 +61,44 @@ impl Db {
         log_offset: Option<usize>,
     ) -> Result<Job, ApiError> {
         self.run(move |conn| {
-            use schema::jobs::dsl::*;
-            Ok(jobs
-                .filter(id.eq(job_id))
-                .get_result::<Job>(conn)?
-                .apply_log_offset(log_offset))
+            let mut job = schema::jobs::table
+                .filter(schema::jobs::id.eq(job_id))
+                .get_result::<Job>(conn)?;
+            /* Jobs from before the log records have their whole log in the job itself */
+            let messages = schema::job_log_records::table
+                .select(schema::job_log_records::message)
+                .filter(schema::job_log_records::job_id.eq(job_id))
+                .order(schema::job_log_records::id)
+                .get_results::<String>(conn)?;
+            for message in messages {
+                job.log.push_str(&message);
+                job.log.push('\n');
+            }
+            Ok(job.apply_log_offset(log_offset))
+        })
+        .await
+    }
+
+    /* The status of a job, with at most limit of its log records after the since cursor. The status is read first,
+     * so once it is finished the records are complete. */
+    pub async fn lookup_job_log(
+        &self,
+        job_id: i32,
+        since: Option<i32>,
+        limit: i64,
+    ) -> Result<(i16, Option<String>, Vec<JobLogRecord>), ApiError> {
+        self.run(move |conn| {
+            let (status, progress) = schema::jobs::table
+                .select((schema::jobs::status, schema::jobs::progress))
+                .filter(schema::jobs::id.eq(job_id))
+                .get_result::<(i16, Option<String>)>(conn)?;
+            let records = schema::job_log_records::table
+                .filter(schema::job_log_records::job_id.eq(job_id))
+                .filter(schema::job_log_records::id.gt(since.unwrap_or(0)))
+                .order(schema::job_log_records::id)
+                .limit(limit)
+                .get_results::<JobLogRecord>(conn)?;
+            Ok((status, progress, records))
         })
         .await
     }
@@ -75,12 +114,155 @@ impl Db {
         .await
     }
 
+    pub async fn list_jobs(&self, filter: JobFilter) -> Result<Vec<Job>, ApiError> {
+        self.run(move |conn| {
+            use schema::jobs::dsl::*;
+            let mut query = jobs.into_boxed();
+            if let Some(statuses) = filter.statuses {
+                query = query.filter(status.eq_any(statuses));
+            }
+            if let Some(the_kind) = filter.kind {
+                query = query.filter(kind.eq(the_kind));
+            }
+            if let Some(after) = filter.after {
+                query = query.filter(id.gt(after));
+            }
+            if let Some(limit) = filter.limit {
+                query = query.limit(limit);
+            }
+            Ok(query.order(id).get_results::<Job>(conn)?)
+        })
+        .await
+    }
+
+    /* The usage of the jobs that finished since the given time, added up by kind */
+    pub async fn job_usage_stats(
+        &self,
+        since: chrono::NaiveDateTime,
+    ) -> Result<std::collections::BTreeMap<JobKind, JobUsageStats>, ApiError> {
+        self.run(move |conn| {
+            use schema::jobs::dsl::*;
+            let rows = jobs
+                .select((kind, wall_time_ms, cpu_time_ms, peak_rss_kb, bytes_written))
+                .filter(finished_at.ge(since))
+                .get_results::<(i16, Option<i64>, Option<i64>, Option<i64>, Option<i64>)>(conn)?;
+
+            let mut stats = std::collections::BTreeMap::<JobKind, JobUsageStats>::new();
+            for (job_kind, wall_time, cpu_time, peak_rss, written) in rows {
+                if let Some(job_kind) = JobKind::from_db(job_kind) {
+                    stats.entry(job_kind).or_default().add(&JobUsage {
+                        wall_time_ms: wall_time.unwrap_or(0),
+                        cpu_time_ms: cpu_time.unwrap_or(0),
+                        peak_rss_kb: peak_rss.unwrap_or(0),
+                        bytes_written: written.unwrap_or(0),
+                    });
+                }
+            }
+            Ok(stats)
+        })
+        .await
+    }
+
+    /* Queues a job from the dead-letter queue again, with a clean slate, after putting its build back in the state
+     * the job expects */
+    pub async fn requeue_job(&self, job_id: i32) -> Result<Job, ApiError> {
+        self.run_in_transaction(move |conn| {
+            let job = schema::jobs::table
+                .filter(schema::jobs::id.eq(job_id))
+                .for_update()
+                .get_result::<Job>(conn)?;
+            if !matches!(JobStatus::from_db(job.status), Some(status) if status.is_dead()) {
+                return Err(ApiError::BadRequest(
+                    "Only jobs that failed can be requeued".to_string(),
+                ));
+            }
+            prepare_requeued_job(conn, &job)?;
+            diesel::insert_into(schema::job_log_records::table)
+                .values(NewJobLogRecord {
+                    job_id,
+                    level: "info",
+                    phase: None,
+                    message: "Requeued",
+                })
+                .execute(conn)?;
+            Ok(diesel::update(schema::jobs::table)
+                .filter(schema::jobs::id.eq(job_id))
+                .set((
+                    schema::jobs::status.eq(JobStatus::New as i16),
+                    schema::jobs::results.eq(None::<String>),
+                    schema::jobs::start_after.eq(None::<std::time::SystemTime>),
+                    schema::jobs::attempts.eq(0),
+                    schema::jobs::cancel_requested.eq(false),
+                    schema::jobs::progress.eq(None::<String>),
+                    schema::jobs::finished_at.eq(None::<chrono::NaiveDateTime>),
+                    schema::jobs::wall_time_ms.eq(None::<i64>),
+                    schema::jobs::cpu_time_ms.eq(None::<i64>),
+                    schema::jobs::peak_rss_kb.eq(None::<i64>),
+                    schema::jobs::bytes_written.eq(None::<i64>),
+                    schema::jobs::worker_id.eq(None::<i32>),
+                ))
+                .get_result::<Job>(conn)?)
+        })
+        .await
+    }
+
+    /* Cancels a queued job right away, undoing the state changes made when it was queued. A running job is only
+     * asked to stop, which it does at its next checkpoint. */
+    pub async fn cancel_job(&self, job_id: i32) -> Result<Job, ApiError> {
+        self.run_in_transaction(move |conn| {
+            let job = schema::jobs::table
+                .filter(schema::jobs::id.eq(job_id))
+                .for_update()
+                .get_result::<Job>(conn)?;
+            match JobStatus::from_db(job.status) {
+                Some(JobStatus::New) => {
+                    revert_queued_job(conn, &job)?;
+                    Ok(diesel::update(schema::jobs::table)
+                        .filter(schema::jobs::id.eq(job_id))
+                        .set((
+                            schema::jobs::status.eq(JobStatus::Cancelled as i16),
+                            schema::jobs::cancel_requested.eq(true),
+                        ))
+                        .get_result::<Job>(conn)?)
+                }
+                Some(JobStatus::Started) => Ok(diesel::update(schema::jobs::table)
+                    .filter(schema::jobs::id.eq(job_id))
+                    .set(schema::jobs::cancel_requested.eq(true))
+                    .get_result::<Job>(conn)?),
+                _ => Err(ApiError::BadRequest("Job has already finished".to_string())),
+            }
+        })
+        .await
+    }
+
+    pub async fn start_import_job(&self, import: ImportJob) -> Result<Job, ApiError> {
+        self.run_in_transaction(move |conn| {
+            let job = insert_job(
+                conn,
+                NewJob {
+                    kind: JobKind::Import.to_db(),
+                    priority: JobKind::Import.default_priority(),
+                    start_after: None,
+                    repo: None,
+                    contents: json!(import).to_string(),
+                },
+            )?;
+            diesel::update(schema::builds::table)
+                .filter(schema::builds::id.eq(import.build))
+                .set(schema::builds::import_job_id.eq(job.id))
+                .execute(conn)?;
+            Ok(job)
+        })
+        .await
+    }
+
     pub async fn start_commit_job(
         &self,
         build_id: i32,
         endoflife: Option<String>,
         endoflife_rebase: Option<String>,
         token_type: Option<i32>,
+        required_approvals: i32,
     ) -> Result<Job, ApiError> {
         self.run_in_transaction(move |conn| {
             let current_build = schema::builds::table
@@ -97,7 +279,7 @@ impl Db {
                         "committing".to_string(),
                     ))
                 }
-                RepoState::Ready | RepoState::Validating => {
+                RepoState::Ready | RepoState::Validating | RepoState::AwaitingApproval => {
                     return Err(ApiError::WrongRepoState(
                         "Build is already commited".to_string(),
                         "uploading".to_string(),
@@ -119,10 +301,23 @@ impl Db {
                     ))
                 }
             }
+            if let Some(import_job_id) = current_build.import_job_id {
+                let import_status = schema::jobs::table
+                    .select(schema::jobs::status)
+                    .filter(schema::jobs::id.eq(import_job_id))
+                    .get_result::<i16>(conn)?;
+                if import_status <= JobStatus::Started as i16 {
+                    return Err(ApiError::BadRequest(
+                        "Build is still being imported".to_string(),
+                    ));
+                }
+            }
             let (val, reason) = RepoState::to_db(&RepoState::Committing);
-            let job = diesel::insert_into(schema::jobs::table)
-                .values(NewJob {
+            let job = insert_job(
+                conn,
+                NewJob {
                     kind: JobKind::Commit.to_db(),
+                    priority: JobKind::Commit.default_priority(),
                     start_after: None,
                     repo: None,
                     contents: json!(CommitJob {
@@ -132,14 +327,15 @@ impl Db {
                         token_type,
                     })
                     .to_string(),
-                })
-                .get_result::<Job>(conn)?;
+                },
+            )?;
             diesel::update(schema::builds::table)
                 .filter(schema::builds::id.eq(build_id))
                 .set((
                     schema::builds::commit_job_id.eq(job.id),
                     schema::builds::repo_state.eq(val),
                     schema::builds::repo_state_reason.eq(reason),
+                    schema::builds::required_approvals.eq(required_approvals),
                 ))
                 .get_result::<Build>(conn)?;
             Ok(job)
@@ -147,7 +343,14 @@ impl Db {
         .await
     }
 
-    pub async fn start_publish_job(&self, build_id: i32, repo: String) -> Result<Job, ApiError> {
+    /* Publishes the given refs of the build, or all of them. The refs left out are held, and can be
+     * published by publishing the build again. */
+    pub async fn start_publish_job(
+        &self,
+        build_id: i32,
+        repo: String,
+        refs: Option<Vec<String>>,
+    ) -> Result<Job, ApiError> {
         self.run_in_transaction(move |conn| {
             let current_build = schema::builds::table
                 .filter(schema::builds::id.eq(build_id))
@@ -156,9 +359,18 @@ impl Db {
                 current_build.published_state,
                 &current_build.published_state_reason,
             );
+            let current_build_refs = schema::build_refs::table
+                .filter(schema::build_refs::build_id.eq(build_id))
+                .get_results::<BuildRef>(conn)?;
+            let held_refs: Vec<&BuildRef> = current_build_refs
+                .iter()
+                .filter(|build_ref| build_ref.held)
+                .collect();
 
-            match current_published_state {
-                PublishedState::Unpublished => (),
+            /* Which refs are left to publish */
+            let candidates: Vec<&BuildRef> = match current_published_state {
+                PublishedState::Unpublished => current_build_refs.iter().collect(),
+                PublishedState::Published if !held_refs.is_empty() => held_refs,
                 PublishedState::Publishing => {
                     return Err(ApiError::WrongPublishedState(
                         "Build is currently being published".to_string(),
@@ -180,7 +392,7 @@ impl Db {
                         "failed".to_string(),
                     ))
                 }
-            }
+            };
 
             let current_repo_state =
                 RepoState::from_db(current_build.repo_state, &current_build.repo_state_reason);
@@ -206,6 +418,16 @@ impl Db {
                         "validating".to_string(),
                     ))
                 }
+                RepoState::AwaitingApproval => {
+                    return Err(ApiError::WrongRepoState(
+                        format!(
+                            "Build needs {} approvals before it can be published",
+                            current_build.required_approvals
+                        ),
+                        "ready".to_string(),
+                        "awaiting-approval".to_string(),
+                    ))
+                }
                 RepoState::Ready => (),
                 RepoState::Failed(s) => {
                     return Err(ApiError::WrongRepoState(
@@ -223,15 +445,61 @@ impl Db {
                 }
             }
 
+            let selected = match &refs {
+                Some(refs) => {
+                    if refs.is_empty() {
+                        return Err(ApiError::BadRequest("No refs to publish".to_string()));
+                    }
+                    for ref_name in refs {
+                        if !candidates
+                            .iter()
+                            .any(|build_ref| &build_ref.ref_name == ref_name)
+                        {
+                            return Err(ApiError::BadRequest(format!(
+                                "Ref {ref_name} is not in the build or has already been published"
+                            )));
+                        }
+                    }
+                    let mut refs = refs.clone();
+                    refs.sort();
+                    refs.dedup();
+                    refs
+                }
+                None => candidates
+                    .iter()
+                    .map(|build_ref| build_ref.ref_name.clone())
+                    .collect(),
+            };
+
+            for build_ref in &candidates {
+                diesel::update(schema::build_refs::table)
+                    .filter(schema::build_refs::id.eq(build_ref.id))
+                    .set(schema::build_refs::held.eq(!selected.contains(&build_ref.ref_name)))
+                    .execute(conn)?;
+            }
+
+            /* Publishing everything in one go works like it always has */
+            let job_refs = if selected.len() == current_build_refs.len() {
+                None
+            } else {
+                Some(selected)
+            };
+
             let (val, reason) = PublishedState::to_db(&PublishedState::Publishing);
-            let job = diesel::insert_into(schema::jobs::table)
-                .values(NewJob {
+            let job = insert_job(
+                conn,
+                NewJob {
                     kind: JobKind::Publish.to_db(),
+                    priority: JobKind::Publish.default_priority(),
                     start_after: None,
                     repo: Some(repo),
-                    contents: json!(PublishJob { build: build_id }).to_string(),
-                })
-                .get_result::<Job>(conn)?;
+                    contents: json!(PublishJob {
+                        build: build_id,
+                        refs: job_refs,
+                    })
+                    .to_string(),
+                },
+            )?;
             diesel::update(schema::builds::table)
                 .filter(schema::builds::id.eq(build_id))
                 .set((
@@ -253,9 +521,11 @@ impl Db {
         endoflife_rebase: Option<String>,
     ) -> Result<Job, ApiError> {
         self.run_in_transaction(move |conn| {
-            let job = diesel::insert_into(schema::jobs::table)
-                .values(NewJob {
+            let job = insert_job(
+                conn,
+                NewJob {
                     kind: JobKind::Republish.to_db(),
+                    priority: JobKind::Republish.default_priority(),
                     start_after: None,
                     repo: Some(repo.clone()),
                     contents: json!(RepublishJob {
@@ -264,14 +534,34 @@ impl Db {
                         endoflife_rebase
                     })
                     .to_string(),
-                })
-                .get_result::<Job>(conn)?;
+                },
+            )?;
 
             Ok(job)
         })
         .await
     }
 
+    pub async fn start_prune_deltas_job(
+        &self,
+        repo: String,
+        prune: PruneDeltasJob,
+    ) -> Result<Job, ApiError> {
+        self.run_in_transaction(move |conn| {
+            Ok(insert_job(
+                conn,
+                NewJob {
+                    kind: JobKind::PruneDeltas.to_db(),
+                    priority: JobKind::PruneDeltas.default_priority(),
+                    start_after: None,
+                    repo: Some(repo),
+                    contents: json!(prune).to_string(),
+                },
+            )?)
+        })
+        .await
+    }
+
     /* Checks */
 
     pub async fn get_check_by_job_id(&self, job: i32) -> Result<Check, ApiError> {
@@ -335,26 +625,58 @@ impl Db {
         .await
     }
 
-    pub async fn list_builds(&self) -> Result<Vec<Build>, ApiError> {
+    /// Lists the builds matching the filter, oldest first.
+    pub async fn search_builds(&self, filter: BuildFilter) -> Result<Vec<Build>, ApiError> {
         self.run(move |conn| {
             use schema::builds::dsl::*;
-            let (val, _) = RepoState::Purged.to_db();
-            Ok(builds
-                .filter(repo_state.ne(val))
-                .filter(app_id.is_null())
-                .get_results::<Build>(conn)?)
-        })
-        .await
-    }
 
-    pub async fn list_builds_for_app(&self, for_app_id: String) -> Result<Vec<Build>, ApiError> {
-        self.run(move |conn| {
-            use schema::builds::dsl::*;
-            let (val, _) = RepoState::Purged.to_db();
-            Ok(builds
-                .filter(repo_state.ne(val))
-                .filter(app_id.eq(for_app_id))
-                .get_results::<Build>(conn)?)
+            let mut query = builds.into_boxed();
+            match filter.app_id {
+                AppIdFilter::None => query = query.filter(app_id.is_null()),
+                AppIdFilter::Is(filter_app_id) => query = query.filter(app_id.eq(filter_app_id)),
+                AppIdFilter::Any => (),
+            }
+            match filter.repo_state {
+                Some(state) => query = query.filter(repo_state.eq(state)),
+                None => {
+                    let (purged, _) = RepoState::Purged.to_db();
+                    query = query.filter(repo_state.ne(purged));
+                }
+            }
+            if let Some(filter_repo) = filter.repo {
+                query = query.filter(repo.eq(filter_repo));
+            }
+            if let Some(state) = filter.published_state {
+                query = query.filter(published_state.eq(state));
+            }
+            if let Some(since) = filter.created_since {
+                query = query.filter(created_at.ge(since));
+            }
+            if let Some(until) = filter.created_until {
+                query = query.filter(created_at.lt(until));
+            }
+            if let Some(filter_token_name) = filter.token_name {
+                query = query.filter(token_name.eq(filter_token_name));
+            }
+            if let Some((tag_key, tag_value)) = filter.tag {
+                use schema::build_tags;
+                let mut tagged = build_tags::table
+                    .select(build_tags::build_id)
+                    .filter(build_tags::key.eq(tag_key))
+                    .into_boxed();
+                if let Some(tag_value) = tag_value {
+                    tagged = tagged.filter(build_tags::value.eq(tag_value));
+                }
+                query = query.filter(id.eq_any(tagged));
+            }
+            if let Some(after) = filter.after {
+                query = query.filter(id.gt(after));
+            }
+            if let Some(limit) = filter.limit {
+                query = query.limit(limit);
+            }
+
+            Ok(query.order(id).get_results::<Build>(conn)?)
         })
         .await
     }
@@ -380,62 +702,84 @@ impl Db {
     }
 
     pub async fn init_purge(&self, build_id: i32) -> Result<(), ApiError> {
-        self.run_in_transaction(move |conn| {
+        self.run_in_transaction(move |conn| init_build_purge(conn, build_id))
+            .await
+    }
+
+    pub async fn finish_purge(
+        &self,
+        build_id: i32,
+        error: Option<String>,
+    ) -> Result<Build, ApiError> {
+        self.run_in_transaction(move |conn| finish_build_purge(conn, build_id, error))
+            .await
+    }
+
+    pub async fn set_build_pinned(&self, build_id: i32, pin: bool) -> Result<Build, ApiError> {
+        self.run(move |conn| {
+            use schema::builds::dsl::*;
+            Ok(diesel::update(builds)
+                .filter(id.eq(build_id))
+                .set(pinned.eq(pin))
+                .get_result::<Build>(conn)?)
+        })
+        .await
+    }
+
+    pub async fn add_build_uploaded_bytes(
+        &self,
+        build_id: i32,
+        bytes: u64,
+    ) -> Result<(), ApiError> {
+        self.run(move |conn| {
             use schema::builds::dsl::*;
-            let current_build = builds.filter(id.eq(build_id)).get_result::<Build>(conn)?;
-            let current_repo_state =
-                RepoState::from_db(current_build.repo_state, &current_build.repo_state_reason);
-            let current_published_state = PublishedState::from_db(
-                current_build.published_state,
-                &current_build.published_state_reason,
-            );
-            if matches!(
-                current_repo_state,
-                RepoState::Committing | RepoState::Purging | RepoState::Validating
-            ) || matches!(current_published_state, PublishedState::Publishing)
-            {
-                /* Only allow pruning when we're not working on the build repo */
-                return Err(ApiError::BadRequest(
-                    "Can't prune build while in use".to_string(),
-                ));
-            };
-            let (val, reason) = RepoState::to_db(&RepoState::Purging);
             diesel::update(builds)
                 .filter(id.eq(build_id))
-                .set((repo_state.eq(val), repo_state_reason.eq(reason)))
+                .set(uploaded_bytes.eq(uploaded_bytes + bytes as i64))
                 .execute(conn)?;
             Ok(())
         })
         .await
     }
 
-    pub async fn finish_purge(
-        &self,
-        build_id: i32,
-        error: Option<String>,
-    ) -> Result<Build, ApiError> {
-        self.run_in_transaction(move |conn| {
+    /// The builds that still have a build repo and that the quota applies to.
+    pub async fn quota_usage(&self, quota: StorageQuota) -> Result<QuotaUsage, ApiError> {
+        self.run(move |conn| {
             use schema::builds::dsl::*;
-            let current_build = builds.filter(id.eq(build_id)).get_result::<Build>(conn)?;
-            let current_repo_state =
-                RepoState::from_db(current_build.repo_state, &current_build.repo_state_reason);
-            if !current_repo_state.same_state_as(&RepoState::Purging) {
-                return Err(ApiError::BadRequest(
-                    "Unexpected repo state, was not purging".to_string(),
-                ));
-            };
-            let new_state = match error {
-                None => RepoState::Purged,
-                Some(err_string) => {
-                    RepoState::Failed(format!("Failed to Purge build: {err_string}"))
+
+            let (purged, _) = RepoState::Purged.to_db();
+            let (published, _) = PublishedState::Published.to_db();
+            let mut query = builds
+                .select(uploaded_bytes)
+                .filter(repo_state.ne(purged))
+                .filter(published_state.ne(published))
+                .into_boxed();
+            match quota.key() {
+                Some(QuotaKey::Prefix("")) => query = query.filter(app_id.is_not_null()),
+                Some(QuotaKey::Prefix(prefix)) => {
+                    /* Like id_matches_prefix(), with LIKE's wildcards escaped */
+                    let escaped = prefix
+                        .replace('\\', "\\\\")
+                        .replace('%', "\\%")
+                        .replace('_', "\\_");
+                    query = query.filter(
+                        app_id
+                            .eq(prefix.to_string())
+                            .or(app_id.like(format!("{escaped}.%"))),
+                    )
                 }
-            };
-            let (val, reason) = RepoState::to_db(&new_state);
-            let new_build = diesel::update(builds)
-                .filter(id.eq(build_id))
-                .set((repo_state.eq(val), repo_state_reason.eq(reason)))
-                .get_result::<Build>(conn)?;
-            Ok(new_build)
+                Some(QuotaKey::TokenName(quota_token_name)) => {
+                    query = query.filter(token_name.eq(quota_token_name.to_string()))
+                }
+                Some(QuotaKey::Sub(sub)) => query = query.filter(token_sub.eq(sub.to_string())),
+                None => return Ok(QuotaUsage::default()),
+            }
+
+            let bytes = query.get_results::<i64>(conn)?;
+            Ok(QuotaUsage {
+                builds: bytes.len() as u64,
+                bytes: bytes.iter().map(|bytes| *bytes as u64).sum(),
+            })
         })
         .await
     }
@@ -477,8 +821,75 @@ impl Db {
         .await
     }
 
-    /// Checks whether the given token has been revoked. If it hasn't, update its last used time.
-    pub async fn check_token(&self, jti: String, expires_at: i64) -> Result<(), ApiError> {
+    pub async fn lookup_build_tags(&self, the_build_id: i32) -> Result<Vec<BuildTag>, ApiError> {
+        self.run(move |conn| {
+            use schema::build_tags::dsl::*;
+            Ok(build_tags
+                .filter(build_id.eq(the_build_id))
+                .order(key)
+                .get_results::<BuildTag>(conn)?)
+        })
+        .await
+    }
+
+    /// Sets the given tags on a build, removing those without a value, and returns all of the build's tags. Fails
+    /// without changing anything if the build would end up with more than max_tags tags.
+    pub async fn set_build_tags(
+        &self,
+        the_build_id: i32,
+        tags: Vec<(String, Option<String>)>,
+        max_tags: usize,
+    ) -> Result<Vec<BuildTag>, ApiError> {
+        self.run_in_transaction(move |conn| {
+            use schema::build_tags::dsl::*;
+            for (tag_key, tag_value) in tags {
+                match tag_value {
+                    Some(tag_value) => {
+                        diesel::insert_into(build_tags)
+                            .values(BuildTag {
+                                build_id: the_build_id,
+                                key: tag_key,
+                                value: tag_value.clone(),
+                            })
+                            .on_conflict((build_id, key))
+                            .do_update()
+                            .set(value.eq(tag_value))
+                            .execute(conn)?;
+                    }
+                    None => {
+                        diesel::delete(build_tags)
+                            .filter(build_id.eq(the_build_id))
+                            .filter(key.eq(tag_key))
+                            .execute(conn)?;
+                    }
+                }
+            }
+
+            let new_tags = build_tags
+                .filter(build_id.eq(the_build_id))
+                .order(key)
+                .get_results::<BuildTag>(conn)?;
+            if new_tags.len() > max_tags {
+                return Err(ApiError::BadRequest(format!(
+                    "Builds may have at most {max_tags} tags"
+                )));
+            }
+            Ok(new_tags)
+        })
+        .await
+    }
+
+    /// Checks whether the given token has been revoked. If it hasn't, or it may still be used within the revocation
+    /// grace period, record that it was used, and from where. Single-use tokens are marked as consumed, and refused
+    /// if they already were. Returns whether the token was only let through thanks to the grace period.
+    pub async fn check_token(
+        &self,
+        jti: String,
+        expires_at: i64,
+        grace: Option<RevocationGrace>,
+        client_ip: Option<String>,
+        single_use: bool,
+    ) -> Result<bool, ApiError> {
         self.run_in_transaction(move |conn| {
             use schema::tokens::dsl::*;
 
@@ -495,64 +906,965 @@ impl Db {
                     log::warn!("Token expiry mismatch (old: {:?}, new: {expires_at_datetime}) for token '{jti}'. Have multiple tokens been issued with the same ID?", token.expires);
                 }
 
-                if token.revoked_at.is_some() {
-                    return Err(ApiError::InvalidToken("Token has been revoked".to_string()));
-                } else {
+                /* Tokens minted from another one go away with it, and with the ones it was minted from */
+                let chain_revoked_at = revoked_in_chain(token.revoked_at, token.parent_id.clone(), |parent| {
+                    tokens
+                        .find(parent)
+                        .select((revoked_at, parent_id))
+                        .get_result::<(Option<chrono::NaiveDateTime>, Option<String>)>(conn)
+                        .optional()
+                })?;
+
+                let in_grace = match chain_revoked_at {
+                    Some(revoked) => {
+                        let in_grace = match &grace {
+                            Some(grace) => {
+                                let build = schema::builds::table
+                                    .find(grace.build_id)
+                                    .select((schema::builds::created_at, schema::builds::token_id))
+                                    .get_result::<(chrono::NaiveDateTime, Option<String>)>(conn)
+                                    .optional()?
+                                    .map(|(created_at, build_token_id)| GraceBuild {
+                                        created_at,
+                                        token_id: build_token_id,
+                                    });
+                                within_revocation_grace(revoked, grace, &jti, build.as_ref(), Utc::now().naive_utc())
+                            }
+                            None => false,
+                        };
+                        if !in_grace {
+                            return Err(ApiError::InvalidToken("Token has been revoked".to_string()));
+                        }
+                        log::info!("Allowing revoked token '{jti}' to finish work on an earlier build");
+                        true
+                    }
+                    None => false,
+                };
+
+                /* The row is locked, so concurrent requests with the same token can't both get here */
+                if single_use {
+                    if let Some(consumed) = token.consumed_at {
+                        return Err(ApiError::InvalidToken(format!(
+                            "Single-use token was already used at {consumed}"
+                        )));
+                    }
                     diesel::update(tokens)
-                        .filter(token_id.eq(jti))
-                        .set(last_used.eq(diesel::dsl::now))
+                        .filter(token_id.eq(&jti))
+                        .set(consumed_at.eq(diesel::dsl::now))
                         .execute(conn)?;
                 }
+
+                diesel::update(tokens)
+                    .filter(token_id.eq(jti))
+                    .set((
+                        last_used.eq(diesel::dsl::now),
+                        last_used_ip.eq(client_ip),
+                        use_count.eq(use_count + 1),
+                    ))
+                    .execute(conn)?;
+
+                Ok(in_grace)
             } else {
                 diesel::insert_into(tokens)
                     .values(NewToken {
                         token_id: jti,
                         expires: expires_at_datetime,
                         last_used: Utc::now().naive_utc(),
+                        last_used_ip: client_ip,
+                        use_count: 1,
+                        consumed_at: single_use.then(|| Utc::now().naive_utc()),
                     })
                     .execute(conn)?;
+
+                Ok(false)
             }
+        })
+        .await
+    }
+
+    /// Writes the uses of tokens that were let through from the revocation cache, see
+    /// `RevocationCache::record_use`.
+    pub async fn record_token_uses(
+        &self,
+        uses: Vec<(String, PendingTokenUse)>,
+    ) -> Result<(), ApiError> {
+        self.run_in_transaction(move |conn| {
+            use schema::tokens::dsl::*;
 
+            for (jti, pending) in uses {
+                diesel::update(tokens)
+                    .filter(token_id.eq(jti))
+                    .set((
+                        last_used.eq(pending.last_used),
+                        last_used_ip.eq(pending.last_used_ip),
+                        use_count.eq(use_count + pending.count),
+                    ))
+                    .execute(conn)?;
+            }
             Ok(())
         })
         .await
     }
 
-    /// Gets the tokens with the given IDs. If a token is not found, it is ignored.
-    pub async fn get_tokens(&self, jtis: Vec<String>) -> Result<Vec<Token>, ApiError> {
-        self.run(move |conn| {
+    /// Records a token minted with a caller-chosen ID. Minting again with the same ID and claims is a retry and
+    /// succeeds, but an ID already used by any other token is refused.
+    pub async fn register_minted_token(
+        &self,
+        jti: String,
+        expires_at: i64,
+        fingerprint: String,
+        parent: Option<String>,
+    ) -> Result<(), ApiError> {
+        self.run_in_transaction(move |conn| {
             use schema::tokens::dsl::*;
 
-            Ok(tokens
-                .filter(token_id.eq_any(jtis))
-                .get_results::<Token>(conn)?)
+            let existing = tokens
+                .find(&jti)
+                .select(claims_hash)
+                .for_update()
+                .get_result::<Option<String>>(conn)
+                .optional()?;
+
+            check_minted_jti(&jti, existing.as_ref().map(Option::as_deref), &fingerprint)?;
+
+            if existing.is_none() {
+                diesel::insert_into(tokens)
+                    .values(NewMintedToken {
+                        token_id: jti,
+                        expires: chrono::NaiveDateTime::from_timestamp_opt(expires_at, 0).unwrap(),
+                        claims_hash: fingerprint,
+                        parent_id: parent,
+                    })
+                    .execute(conn)?;
+            }
+
+            Ok(())
         })
         .await
     }
 
-    /// Revokes the given tokens.
-    pub async fn revoke_tokens(&self, jtis: Vec<String>) -> Result<(), ApiError> {
+    /// Stores the full token that a reference token with the same ID stands for.
+    pub async fn store_reference_token(&self, jti: String, token: String) -> Result<(), ApiError> {
         self.run(move |conn| {
             use schema::tokens::dsl::*;
 
-            sql_function! { fn coalesce(x: Nullable<Timestamp>, y: Timestamp) -> Timestamp; }
-
-            diesel::insert_into(tokens)
-                .values(
-                    jtis.into_iter()
-                        .map(|jti| NewRevokedToken {
-                            token_id: jti,
-                            revoked_at: Utc::now().naive_utc(),
-                        })
-                        .collect::<Vec<_>>(),
-                )
-                .on_conflict(token_id)
-                .do_update()
-                .set(revoked_at.eq(coalesce(revoked_at, diesel::dsl::now).nullable()))
+            diesel::update(tokens)
+                .filter(token_id.eq(jti))
+                .set(full_token.eq(token))
                 .execute(conn)?;
 
             Ok(())
         })
         .await
     }
+
+    /// Gets the full token stored for a reference token.
+    pub async fn get_reference_token(&self, jti: String) -> Result<String, ApiError> {
+        self.run(move |conn| {
+            use schema::tokens::dsl::*;
+
+            tokens
+                .find(&jti)
+                .select(full_token)
+                .get_result::<Option<String>>(conn)
+                .optional()?
+                .flatten()
+                .ok_or_else(|| ApiError::InvalidToken("Unknown reference token".to_string()))
+        })
+        .await
+    }
+
+    /// Revokes the token `old_jti` and records `new_jti` as its replacement, in one transaction so that there is
+    /// never a moment where both or neither work. The new token keeps the old one's parent, if any.
+    pub async fn rotate_token(
+        &self,
+        old_jti: String,
+        new_jti: String,
+        expires_at: i64,
+        fingerprint: String,
+    ) -> Result<(), ApiError> {
+        self.run_in_transaction(move |conn| {
+            use schema::tokens::dsl::*;
+
+            sql_function! { fn coalesce(x: Nullable<Timestamp>, y: Timestamp) -> Timestamp; }
+
+            let parent = tokens
+                .find(&old_jti)
+                .select(parent_id)
+                .get_result::<Option<String>>(conn)
+                .optional()?
+                .flatten();
+
+            diesel::insert_into(tokens)
+                .values(NewRevokedToken {
+                    token_id: old_jti,
+                    revoked_at: Utc::now().naive_utc(),
+                })
+                .on_conflict(token_id)
+                .do_update()
+                .set(revoked_at.eq(coalesce(revoked_at, diesel::dsl::now).nullable()))
+                .execute(conn)?;
+
+            diesel::insert_into(tokens)
+                .values(NewMintedToken {
+                    token_id: new_jti,
+                    expires: chrono::NaiveDateTime::from_timestamp_opt(expires_at, 0).unwrap(),
+                    claims_hash: fingerprint,
+                    parent_id: parent,
+                })
+                .execute(conn)?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Gets what a token has used of its budgets so far, together with the tokens it was minted from or that were
+    /// minted from it.
+    pub async fn get_token_usage(&self, jti: String) -> Result<TokenUsage, ApiError> {
+        self.run(move |conn| {
+            use schema::token_usage::dsl::*;
+
+            let jti = budget_root_of(conn, jti)?;
+            Ok(token_usage
+                .find(&jti)
+                .get_result::<TokenUsage>(conn)
+                .optional()?
+                .unwrap_or(TokenUsage {
+                    token_id: jti,
+                    ..Default::default()
+                }))
+        })
+        .await
+    }
+
+    /// Adds to a token's usage and returns the new totals. Like `get_token_usage`, the usage is shared along the
+    /// token's parent chain.
+    pub async fn add_token_usage(
+        &self,
+        jti: String,
+        bytes: i64,
+        builds: i64,
+    ) -> Result<TokenUsage, ApiError> {
+        self.run(move |conn| {
+            use schema::token_usage::dsl::*;
+
+            let jti = budget_root_of(conn, jti)?;
+            Ok(diesel::insert_into(token_usage)
+                .values(TokenUsage {
+                    token_id: jti,
+                    uploaded_bytes: bytes,
+                    created_builds: builds,
+                })
+                .on_conflict(token_id)
+                .do_update()
+                .set((
+                    uploaded_bytes.eq(uploaded_bytes + bytes),
+                    created_builds.eq(created_builds + builds),
+                ))
+                .get_result::<TokenUsage>(conn)?)
+        })
+        .await
+    }
+
+    /// Gets the tokens with the given IDs. If a token is not found, it is ignored.
+    pub async fn get_tokens(&self, jtis: Vec<String>) -> Result<Vec<Token>, ApiError> {
+        self.run(move |conn| {
+            use schema::tokens::dsl::*;
+
+            Ok(tokens
+                .filter(token_id.eq_any(jtis))
+                .get_results::<Token>(conn)?)
+        })
+        .await
+    }
+
+    /* Audit log */
+
+    pub async fn add_audit_entry(&self, entry: NewAuditEntry) -> Result<(), ApiError> {
+        self.run(move |conn| {
+            diesel::insert_into(schema::audit_log::table)
+                .values(entry)
+                .execute(conn)?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn list_audit_entries(
+        &self,
+        filter: AuditLogFilter,
+        max_entries: i64,
+    ) -> Result<Vec<AuditEntry>, ApiError> {
+        self.run(move |conn| {
+            use schema::audit_log::dsl::*;
+
+            let mut query = audit_log.into_boxed();
+            if let Some(sub) = filter.sub {
+                query = query.filter(token_sub.eq(sub));
+            }
+            if let Some(jti) = filter.jti {
+                query = query.filter(token_jti.eq(jti));
+            }
+            if let Some(filter_action) = filter.action {
+                query = query.filter(action.eq(filter_action));
+            }
+            if let Some(filter_path) = filter.path {
+                query = query.filter(path.eq(filter_path));
+            }
+            if let Some(since) = filter.since {
+                query = query.filter(created_at.ge(since));
+            }
+            if let Some(until) = filter.until {
+                query = query.filter(created_at.lt(until));
+            }
+
+            let limit = filter.limit.unwrap_or(max_entries).clamp(0, max_entries);
+            Ok(query
+                .order(id.desc())
+                .limit(limit)
+                .get_results::<AuditEntry>(conn)?)
+        })
+        .await
+    }
+
+    /// Revokes the given tokens.
+    pub async fn revoke_tokens(&self, jtis: Vec<String>) -> Result<(), ApiError> {
+        self.run(move |conn| {
+            use schema::tokens::dsl::*;
+
+            sql_function! { fn coalesce(x: Nullable<Timestamp>, y: Timestamp) -> Timestamp; }
+
+            diesel::insert_into(tokens)
+                .values(
+                    jtis.into_iter()
+                        .map(|jti| NewRevokedToken {
+                            token_id: jti,
+                            revoked_at: Utc::now().naive_utc(),
+                        })
+                        .collect::<Vec<_>>(),
+                )
+                .on_conflict(token_id)
+                .do_update()
+                .set(revoked_at.eq(coalesce(revoked_at, diesel::dsl::now).nullable()))
+                .execute(conn)?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Undoes the revocation of a token, if it was revoked less than `undo_seconds` ago.
+    pub async fn unrevoke_token(&self, jti: String, undo_seconds: u64) -> Result<(), ApiError> {
+        self.run_in_transaction(move |conn| {
+            use schema::tokens::dsl::*;
+
+            let revoked = tokens
+                .find(&jti)
+                .select(revoked_at)
+                .for_update()
+                .get_result::<Option<chrono::NaiveDateTime>>(conn)?;
+
+            check_unrevoke(&jti, revoked, undo_seconds, Utc::now().naive_utc())?;
+
+            diesel::update(tokens)
+                .filter(token_id.eq(jti))
+                .set(revoked_at.eq(None::<chrono::NaiveDateTime>))
+                .execute(conn)?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Revokes every token granting a prefix that overlaps the given one. Revoking an already revoked prefix keeps
+    /// the original revocation time.
+    pub async fn revoke_prefix(&self, the_prefix: String) -> Result<(), ApiError> {
+        self.run(move |conn| {
+            use schema::revoked_prefixes::dsl::*;
+
+            diesel::insert_into(revoked_prefixes)
+                .values(RevokedPrefix {
+                    prefix: the_prefix,
+                    revoked_at: Utc::now().naive_utc(),
+                })
+                .on_conflict(prefix)
+                .do_nothing()
+                .execute(conn)?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Revokes every token issued so far with a sub inside the given one. Revoking it again later also revokes the
+    /// tokens issued in between.
+    pub async fn revoke_sub(&self, the_sub_prefix: String) -> Result<RevokedSub, ApiError> {
+        self.run(move |conn| {
+            use schema::revoked_subs::dsl::*;
+
+            let now = Utc::now().naive_utc();
+            Ok(diesel::insert_into(revoked_subs)
+                .values(RevokedSub {
+                    sub_prefix: the_sub_prefix,
+                    revoked_at: now,
+                })
+                .on_conflict(sub_prefix)
+                .do_update()
+                .set(revoked_at.eq(now))
+                .get_result::<RevokedSub>(conn)?)
+        })
+        .await
+    }
+
+    /* Prefix owners */
+
+    /// Gives the prefix to the group, taking it from any group that owned it before.
+    pub async fn set_prefix_owner(
+        &self,
+        the_prefix: String,
+        group: String,
+    ) -> Result<PrefixOwner, ApiError> {
+        self.run(move |conn| {
+            use schema::prefix_owners::dsl::*;
+
+            let now = Utc::now().naive_utc();
+            Ok(diesel::insert_into(prefix_owners)
+                .values(PrefixOwner {
+                    prefix: the_prefix,
+                    owner_group: group.clone(),
+                    updated_at: now,
+                })
+                .on_conflict(prefix)
+                .do_update()
+                .set((owner_group.eq(group), updated_at.eq(now)))
+                .get_result::<PrefixOwner>(conn)?)
+        })
+        .await
+    }
+
+    /// Returns whether the prefix had an owner.
+    pub async fn remove_prefix_owner(&self, the_prefix: String) -> Result<bool, ApiError> {
+        self.run(move |conn| {
+            use schema::prefix_owners::dsl::*;
+
+            let removed = diesel::delete(prefix_owners)
+                .filter(prefix.eq(the_prefix))
+                .execute(conn)?;
+            Ok(removed > 0)
+        })
+        .await
+    }
+
+    pub async fn list_prefix_owners(&self) -> Result<Vec<PrefixOwner>, ApiError> {
+        self.run(move |conn| {
+            use schema::prefix_owners::dsl::*;
+
+            Ok(prefix_owners
+                .order(prefix)
+                .get_results::<PrefixOwner>(conn)?)
+        })
+        .await
+    }
+
+    pub async fn list_revoked_subs(&self) -> Result<Vec<RevokedSub>, ApiError> {
+        self.run(move |conn| Ok(schema::revoked_subs::table.get_results::<RevokedSub>(conn)?))
+            .await
+    }
+
+    /// The revoked tokens that haven't expired yet, and so are still worth refusing.
+    pub async fn list_revoked_tokens(&self) -> Result<Vec<Token>, ApiError> {
+        self.run(move |conn| {
+            use schema::tokens::dsl::*;
+
+            Ok(tokens
+                .filter(revoked_at.is_not_null())
+                .filter(expires.is_null().or(expires.gt(diesel::dsl::now)))
+                .get_results::<Token>(conn)?)
+        })
+        .await
+    }
+
+    /// Lists the tokens that can still be used, least recently used first. With `unused_since`, only those that
+    /// haven't been used since then are listed.
+    pub async fn list_token_usage(
+        &self,
+        unused_since: Option<chrono::NaiveDateTime>,
+        limit: i64,
+    ) -> Result<Vec<Token>, ApiError> {
+        self.run(move |conn| {
+            use schema::tokens::dsl::*;
+
+            let mut query = tokens
+                .filter(revoked_at.is_null())
+                .filter(expires.is_null().or(expires.gt(diesel::dsl::now)))
+                .into_boxed();
+            if let Some(since) = unused_since {
+                query = query.filter(last_used.is_null().or(last_used.lt(since)));
+            }
+            Ok(query
+                .order(last_used.asc().nulls_first())
+                .limit(limit)
+                .get_results::<Token>(conn)?)
+        })
+        .await
+    }
+
+    pub async fn lookup_build_approvals(
+        &self,
+        the_build_id: i32,
+    ) -> Result<Vec<BuildApproval>, ApiError> {
+        self.run(move |conn| {
+            use schema::build_approvals::dsl::*;
+            Ok(build_approvals
+                .filter(build_id.eq(the_build_id))
+                .order(created_at)
+                .get_results::<BuildApproval>(conn)?)
+        })
+        .await
+    }
+
+    pub async fn lookup_build_comments(
+        &self,
+        the_build_id: i32,
+    ) -> Result<Vec<BuildComment>, ApiError> {
+        self.run(move |conn| {
+            use schema::build_comments::dsl::*;
+            Ok(build_comments
+                .filter(build_id.eq(the_build_id))
+                .order(id)
+                .get_results::<BuildComment>(conn)?)
+        })
+        .await
+    }
+
+    pub async fn add_build_comment(
+        &self,
+        new_comment: NewBuildComment,
+    ) -> Result<BuildComment, ApiError> {
+        self.run(move |conn| {
+            Ok(diesel::insert_into(schema::build_comments::table)
+                .values(&new_comment)
+                .get_result::<BuildComment>(conn)?)
+        })
+        .await
+    }
+
+    /// Records an approval of a build that is waiting for them, moving it to the ready state once it has enough. An
+    /// approver approving again only updates their comment.
+    pub async fn approve_build(
+        &self,
+        the_build_id: i32,
+        the_approver: String,
+        the_comment: Option<String>,
+    ) -> Result<Vec<BuildApproval>, ApiError> {
+        self.run_in_transaction(move |conn| {
+            use schema::build_approvals::dsl::*;
+            let current_build = schema::builds::table
+                .filter(schema::builds::id.eq(the_build_id))
+                .for_update()
+                .get_result::<Build>(conn)?;
+            let state =
+                RepoState::from_db(current_build.repo_state, &current_build.repo_state_reason);
+            if !state.same_state_as(&RepoState::AwaitingApproval) {
+                return Err(ApiError::WrongRepoState(
+                    "Build is not waiting for approval".to_string(),
+                    "awaiting-approval".to_string(),
+                    state.name().to_string(),
+                ));
+            }
+            if current_build.token_name.as_deref() == Some(the_approver.as_str()) {
+                return Err(ApiError::NotEnoughPermissions(
+                    "Builds can't be approved by the token that created them".to_string(),
+                ));
+            }
+
+            diesel::insert_into(build_approvals)
+                .values((
+                    build_id.eq(the_build_id),
+                    approver.eq(&the_approver),
+                    comment.eq(&the_comment),
+                ))
+                .on_conflict((build_id, approver))
+                .do_update()
+                .set(comment.eq(&the_comment))
+                .execute(conn)?;
+
+            let (val, reason) = ready_or_awaiting_approval(conn, &current_build)?.to_db();
+            diesel::update(schema::builds::table)
+                .filter(schema::builds::id.eq(the_build_id))
+                .set((
+                    schema::builds::repo_state.eq(val),
+                    schema::builds::repo_state_reason.eq(reason),
+                ))
+                .execute(conn)?;
+
+            Ok(build_approvals
+                .filter(build_id.eq(the_build_id))
+                .order(created_at)
+                .get_results::<BuildApproval>(conn)?)
+        })
+        .await
+    }
+
+    pub async fn list_revoked_prefixes(&self) -> Result<Vec<String>, ApiError> {
+        self.run(move |conn| {
+            use schema::revoked_prefixes::dsl::*;
+
+            Ok(revoked_prefixes
+                .select(prefix)
+                .get_results::<String>(conn)?)
+        })
+        .await
+    }
+}
+
+/* The state of a build whose commit and checks are done: ready if it has as many approvals as it needs */
+/* The token whose usage a token's budgets are counted against */
+fn budget_root_of(conn: &mut PgConnection, jti: String) -> Result<String, diesel::result::Error> {
+    use schema::tokens::dsl::*;
+
+    budget_root(jti, |token| {
+        Ok(tokens
+            .find(token)
+            .select(parent_id)
+            .get_result::<Option<String>>(conn)
+            .optional()?
+            .flatten())
+    })
+}
+
+pub fn ready_or_awaiting_approval(
+    conn: &mut PgConnection,
+    build: &Build,
+) -> Result<RepoState, diesel::result::Error> {
+    if build.required_approvals <= 0 {
+        return Ok(RepoState::Ready);
+    }
+    let approvals = schema::build_approvals::table
+        .filter(schema::build_approvals::build_id.eq(build.id))
+        .count()
+        .get_result::<i64>(conn)?;
+    Ok(if approvals >= build.required_approvals as i64 {
+        RepoState::Ready
+    } else {
+        RepoState::AwaitingApproval
+    })
+}
+
+/* Queues a job, depending on the unfinished jobs it has to wait for, see JobKind::dependencies(). Dependencies are
+ * always on older jobs, so they can't form cycles. Call in a transaction. */
+pub fn insert_job(conn: &mut PgConnection, new_job: NewJob) -> Result<Job, diesel::result::Error> {
+    let dependency_kinds: Vec<i16> = JobKind::from_db(new_job.kind)
+        .map(|kind| kind.dependencies().iter().map(JobKind::to_db).collect())
+        .unwrap_or_default();
+    let repo = new_job.repo.clone();
+
+    let job = diesel::insert_into(schema::jobs::table)
+        .values(new_job)
+        .get_result::<Job>(conn)?;
+
+    if !dependency_kinds.is_empty() {
+        let mut query = schema::jobs::table
+            .select(schema::jobs::id)
+            .filter(schema::jobs::kind.eq_any(dependency_kinds))
+            .filter(schema::jobs::status.le(JobStatus::Started as i16))
+            .filter(schema::jobs::id.lt(job.id))
+            .into_boxed();
+        query = match repo {
+            Some(repo) => query.filter(schema::jobs::repo.eq(repo)),
+            None => query.filter(schema::jobs::repo.is_null()),
+        };
+        let dependencies: Vec<JobDependency> = query
+            .get_results::<i32>(conn)?
+            .into_iter()
+            .map(|depends_on| JobDependency {
+                job_id: job.id,
+                depends_on,
+            })
+            .collect();
+        if !dependencies.is_empty() {
+            diesel::insert_into(schema::job_dependencies::table)
+                .values(dependencies)
+                .execute(conn)?;
+        }
+    }
+
+    Ok(job)
+}
+
+fn parse_job_contents<T: serde::de::DeserializeOwned>(job: &Job) -> Result<T, ApiError> {
+    serde_json::from_str(&job.contents)
+        .map_err(|_| ApiError::InternalServerError(format!("Can't parse job {}", job.id)))
+}
+
+/* Puts a build back the way it was before a job that never started was queued for it. A job waiting to be retried
+ * may have got part of the way, so its build fails instead. */
+fn revert_queued_job(conn: &mut PgConnection, job: &Job) -> Result<(), ApiError> {
+    let retried = job.attempts > 0;
+    match JobKind::from_db(job.kind) {
+        Some(JobKind::Commit) => {
+            let commit: CommitJob = parse_job_contents(job)?;
+            let new_state = if retried {
+                RepoState::Failed("Commit was cancelled".to_string())
+            } else {
+                RepoState::Uploading
+            };
+            let (val, reason) = RepoState::to_db(&new_state);
+            diesel::update(schema::builds::table)
+                .filter(schema::builds::id.eq(commit.build))
+                .set((
+                    schema::builds::repo_state.eq(val),
+                    schema::builds::repo_state_reason.eq(reason),
+                ))
+                .execute(conn)?;
+        }
+        Some(JobKind::Publish) => {
+            let publish: PublishJob = parse_job_contents(job)?;
+            let build_refs = schema::build_refs::table
+                .filter(schema::build_refs::build_id.eq(publish.build))
+                .get_results::<BuildRef>(conn)?;
+            let queued = |build_ref: &BuildRef| match &publish.refs {
+                Some(refs) => refs.contains(&build_ref.ref_name),
+                None => true,
+            };
+            /* Refs that are neither held nor part of this publish went out with an earlier one */
+            let published_before = build_refs
+                .iter()
+                .any(|build_ref| !build_ref.held && !queued(build_ref));
+            let new_state = if retried {
+                PublishedState::Failed("Publish was cancelled".to_string())
+            } else if published_before {
+                PublishedState::Published
+            } else {
+                PublishedState::Unpublished
+            };
+            if !retried {
+                for build_ref in &build_refs {
+                    let held = published_before && (build_ref.held || queued(build_ref));
+                    diesel::update(schema::build_refs::table)
+                        .filter(schema::build_refs::id.eq(build_ref.id))
+                        .set(schema::build_refs::held.eq(held))
+                        .execute(conn)?;
+                }
+            }
+            let (val, reason) = PublishedState::to_db(&new_state);
+            diesel::update(schema::builds::table)
+                .filter(schema::builds::id.eq(publish.build))
+                .set((
+                    schema::builds::published_state.eq(val),
+                    schema::builds::published_state_reason.eq(reason),
+                ))
+                .execute(conn)?;
+        }
+        Some(JobKind::Check) => {
+            let (val, reason) = CheckStatus::Failed("Check was cancelled".to_string()).to_db();
+            diesel::update(schema::checks::table)
+                .filter(schema::checks::job_id.eq(job.id))
+                .set((
+                    schema::checks::status.eq(val),
+                    schema::checks::status_reason.eq(reason),
+                ))
+                .execute(conn)?;
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/* Fails the build of a job that was abandoned while it ran, e.g. because the worker running it went away. Checks
+ * fail too, the caller updates the status of their build. */
+pub fn fail_abandoned_job(
+    conn: &mut PgConnection,
+    job: &Job,
+    reason: &str,
+) -> Result<(), ApiError> {
+    match JobKind::from_db(job.kind) {
+        Some(JobKind::Commit) => {
+            let commit: CommitJob = parse_job_contents(job)?;
+            let (val, reason) = RepoState::to_db(&RepoState::Failed(reason.to_string()));
+            diesel::update(schema::builds::table)
+                .filter(schema::builds::id.eq(commit.build))
+                .set((
+                    schema::builds::repo_state.eq(val),
+                    schema::builds::repo_state_reason.eq(reason),
+                ))
+                .execute(conn)?;
+        }
+        Some(JobKind::Publish) => {
+            let publish: PublishJob = parse_job_contents(job)?;
+            let (val, reason) = PublishedState::to_db(&PublishedState::Failed(reason.to_string()));
+            diesel::update(schema::builds::table)
+                .filter(schema::builds::id.eq(publish.build))
+                .set((
+                    schema::builds::published_state.eq(val),
+                    schema::builds::published_state_reason.eq(reason),
+                ))
+                .execute(conn)?;
+        }
+        Some(JobKind::Check) => {
+            let (val, reason) = CheckStatus::Failed(reason.to_string()).to_db();
+            diesel::update(schema::checks::table)
+                .filter(schema::checks::job_id.eq(job.id))
+                .set((
+                    schema::checks::status.eq(val),
+                    schema::checks::status_reason.eq(reason),
+                ))
+                .execute(conn)?;
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/* The build a failed job worked on has to still be where the job left it, or the job would run against a build
+ * that has moved on, e.g. been committed again or purged */
+fn requeue_mismatch(job: &Job, build_id: i32) -> ApiError {
+    ApiError::BadRequest(format!(
+        "Build {build_id} has changed since job {} failed",
+        job.id
+    ))
+}
+
+/* Puts the build of a failed job back in the state the job was queued in */
+fn prepare_requeued_job(conn: &mut PgConnection, job: &Job) -> Result<(), ApiError> {
+    let lock_build = |conn: &mut PgConnection, build_id: i32| {
+        schema::builds::table
+            .filter(schema::builds::id.eq(build_id))
+            .for_update()
+            .get_result::<Build>(conn)
+    };
+    match JobKind::from_db(job.kind) {
+        Some(JobKind::Commit) => {
+            let commit: CommitJob = parse_job_contents(job)?;
+            let build = lock_build(conn, commit.build)?;
+            let repo_state = RepoState::from_db(build.repo_state, &build.repo_state_reason);
+            if build.commit_job_id != Some(job.id) || !matches!(repo_state, RepoState::Failed(_)) {
+                return Err(requeue_mismatch(job, build.id));
+            }
+            let (val, reason) = RepoState::to_db(&RepoState::Committing);
+            diesel::update(schema::builds::table)
+                .filter(schema::builds::id.eq(build.id))
+                .set((
+                    schema::builds::repo_state.eq(val),
+                    schema::builds::repo_state_reason.eq(reason),
+                ))
+                .execute(conn)?;
+        }
+        Some(JobKind::Publish) => {
+            let publish: PublishJob = parse_job_contents(job)?;
+            let build = lock_build(conn, publish.build)?;
+            let published_state =
+                PublishedState::from_db(build.published_state, &build.published_state_reason);
+            if build.publish_job_id != Some(job.id)
+                || !matches!(published_state, PublishedState::Failed(_))
+            {
+                return Err(requeue_mismatch(job, build.id));
+            }
+            let (val, reason) = PublishedState::to_db(&PublishedState::Publishing);
+            diesel::update(schema::builds::table)
+                .filter(schema::builds::id.eq(build.id))
+                .set((
+                    schema::builds::published_state.eq(val),
+                    schema::builds::published_state_reason.eq(reason),
+                ))
+                .execute(conn)?;
+        }
+        Some(JobKind::Check) => {
+            let check: CheckJob = parse_job_contents(job)?;
+            let build = lock_build(conn, check.build)?;
+            let repo_state = RepoState::from_db(build.repo_state, &build.repo_state_reason);
+            match repo_state {
+                RepoState::Validating => (),
+                /* Failed by this or another check, the build waits for the checks again */
+                RepoState::Failed(_) => {
+                    let (val, reason) = RepoState::to_db(&RepoState::Validating);
+                    diesel::update(schema::builds::table)
+                        .filter(schema::builds::id.eq(build.id))
+                        .set((
+                            schema::builds::repo_state.eq(val),
+                            schema::builds::repo_state_reason.eq(reason),
+                        ))
+                        .execute(conn)?;
+                }
+                _ => return Err(requeue_mismatch(job, build.id)),
+            }
+            let (val, reason) = CheckStatus::Pending.to_db();
+            diesel::update(schema::checks::table)
+                .filter(schema::checks::job_id.eq(job.id))
+                .set((
+                    schema::checks::status.eq(val),
+                    schema::checks::status_reason.eq(reason),
+                ))
+                .execute(conn)?;
+        }
+        Some(JobKind::Import) => {
+            let import: ImportJob = parse_job_contents(job)?;
+            let build = lock_build(conn, import.build)?;
+            let repo_state = RepoState::from_db(build.repo_state, &build.repo_state_reason);
+            if build.import_job_id != Some(job.id)
+                || !repo_state.same_state_as(&RepoState::Uploading)
+            {
+                return Err(requeue_mismatch(job, build.id));
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/* Marks a build as being purged, unless something is still working on its repo. Call in a transaction. */
+pub fn init_build_purge(conn: &mut PgConnection, build_id: i32) -> Result<(), ApiError> {
+    use schema::builds::dsl::*;
+    let current_build = builds.filter(id.eq(build_id)).get_result::<Build>(conn)?;
+    let current_repo_state =
+        RepoState::from_db(current_build.repo_state, &current_build.repo_state_reason);
+    let current_published_state = PublishedState::from_db(
+        current_build.published_state,
+        &current_build.published_state_reason,
+    );
+    if matches!(
+        current_repo_state,
+        RepoState::Committing | RepoState::Purging | RepoState::Validating
+    ) || matches!(current_published_state, PublishedState::Publishing)
+    {
+        /* Only allow pruning when we're not working on the build repo */
+        return Err(ApiError::BadRequest(
+            "Can't prune build while in use".to_string(),
+        ));
+    };
+    let (val, reason) = RepoState::to_db(&RepoState::Purging);
+    diesel::update(builds)
+        .filter(id.eq(build_id))
+        .set((repo_state.eq(val), repo_state_reason.eq(reason)))
+        .execute(conn)?;
+    Ok(())
+}
+
+/* Marks a build that was being purged as purged, or as failed if removing its repo failed. Call in a
+ * transaction. */
+pub fn finish_build_purge(
+    conn: &mut PgConnection,
+    build_id: i32,
+    error: Option<String>,
+) -> Result<Build, ApiError> {
+    use schema::builds::dsl::*;
+    let current_build = builds.filter(id.eq(build_id)).get_result::<Build>(conn)?;
+    let current_repo_state =
+        RepoState::from_db(current_build.repo_state, &current_build.repo_state_reason);
+    if !current_repo_state.same_state_as(&RepoState::Purging) {
+        return Err(ApiError::BadRequest(
+            "Unexpected repo state, was not purging".to_string(),
+        ));
+    };
+    let new_state = match error {
+        None => RepoState::Purged,
+        Some(err_string) => RepoState::Failed(format!("Failed to Purge build: {err_string}")),
+    };
+    let (val, reason) = RepoState::to_db(&new_state);
+    let new_build = diesel::update(builds)
+        .filter(id.eq(build_id))
+        .set((repo_state.eq(val), repo_state_reason.eq(reason)))
+        .get_result::<Build>(conn)?;
+    Ok(new_build)
 }
@@ -1,8 +1,11 @@
+use crate::models::BuildState;
 use crate::ostree::OstreeError;
 use actix_web::error::BlockingError;
+use actix_web::http::header::RETRY_AFTER;
 use actix_web::http::StatusCode;
 use actix_web::{error::ResponseError, HttpResponse};
 use diesel::result::Error as DieselError;
+use serde::Serialize;
 use serde_json::json;
 use std::io;
 use thiserror::Error;
@@ -38,12 +41,48 @@ pub enum JobError {
 
     #[error("DbError: {0}")]
     DBError(String),
+
+    #[error("Job was cancelled")]
+    Cancelled,
+
+    #[error("Job timed out after {0} seconds")]
+    TimedOut(u64),
 }
 
+/* Error messages, lowercased, that point to a failure that may well go away on its own, like a network hiccup or a
+ * lock held by someone else */
+const TRANSIENT_ERRORS: &[&str] = &[
+    "timed out",
+    "timeout",
+    "connection reset",
+    "connection refused",
+    "temporary failure",
+    "could not resolve",
+    "network is unreachable",
+    "service unavailable",
+    "slow down",
+    "resource temporarily unavailable",
+    "locking repo",
+    "deadlock detected",
+    "could not serialize access",
+];
+
 impl JobError {
     pub fn new(s: &str) -> Self {
         JobError::InternalError(s.to_string())
     }
+
+    /// Whether trying the job again later might succeed. Everything else is assumed to fail the same way again.
+    pub fn is_transient(&self) -> bool {
+        let message = match self {
+            JobError::InternalError(message) | JobError::DBError(message) => message,
+            JobError::Cancelled | JobError::TimedOut(_) => return false,
+        };
+        let message = message.to_lowercase();
+        TRANSIENT_ERRORS
+            .iter()
+            .any(|pattern| message.contains(pattern))
+    }
 }
 
 pub type JobResult<T> = Result<T, JobError>;
@@ -95,6 +134,24 @@ impl From<io::Error> for JobError {
     }
 }
 
+/// Something wrong with the commit a build ref points to, see ApiError::InvalidCommits
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CommitProblem {
+    #[serde(rename = "ref")]
+    pub ref_name: String,
+    pub commit: String,
+    pub message: String,
+}
+
+/// Something wrong with the refs of a build, see ApiError::InvalidRefs. Problems with the build as a whole, rather
+/// than one of its refs, have no ref.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RefProblem {
+    #[serde(rename = "ref", skip_serializing_if = "Option::is_none")]
+    pub ref_name: Option<String>,
+    pub message: String,
+}
+
 #[derive(Error, Debug)]
 pub enum ApiError {
     #[error("Internal Server Error ({0})")]
@@ -117,6 +174,33 @@ pub enum ApiError {
 
     #[error("NotEnoughPermissions")]
     NotEnoughPermissions(String),
+
+    #[error("PayloadTooLarge: {0}")]
+    PayloadTooLarge(String),
+
+    #[error("TooManyRequests: {0}")]
+    TooManyRequests(String, u64),
+
+    #[error("RevocationStoreOverloaded")]
+    RevocationStoreOverloaded(u64),
+
+    #[error("UploadOffsetMismatch: {0}")]
+    UploadOffsetMismatch(String, u64),
+
+    #[error("InvalidCommits: {0:?}")]
+    InvalidCommits(Vec<CommitProblem>),
+
+    #[error("ChecksumMismatch: {0}")]
+    ChecksumMismatch(String, String, String),
+
+    #[error("QuotaExceeded: {0}")]
+    QuotaExceeded(String),
+
+    #[error("InvalidRefs: {0:?}")]
+    InvalidRefs(Vec<RefProblem>),
+
+    #[error("InvalidTransition({1}): {0}")]
+    InvalidTransition(String, String, BuildState),
 }
 
 impl From<DieselError> for ApiError {
@@ -182,6 +266,63 @@ impl ApiError {
                 "error-type": "token-insufficient",
                 "message": format!("Not enough permissions: {message}"),
             }),
+            ApiError::PayloadTooLarge(ref message) => json!({
+                "status": 413,
+                "error-type": "payload-too-large",
+                "message": message,
+            }),
+            ApiError::TooManyRequests(ref message, retry_after) => json!({
+                "status": 429,
+                "error-type": "too-many-requests",
+                "message": message,
+                "retry-after": retry_after,
+            }),
+            ApiError::RevocationStoreOverloaded(retry_after) => json!({
+                "status": 429,
+                "error-type": "revocation-store-overloaded",
+                "message": "Too many requests are waiting on token revocation checks, try again later",
+                "retry-after": retry_after,
+            }),
+            ApiError::UploadOffsetMismatch(ref message, offset) => json!({
+                "status": 409,
+                "error-type": "upload-offset-mismatch",
+                "message": message,
+                "offset": offset,
+            }),
+            ApiError::InvalidCommits(ref problems) => json!({
+                "status": 400,
+                "error-type": "invalid-commits",
+                "message": format!("{} of the build's commits are invalid", problems.len()),
+                "problems": problems,
+            }),
+            ApiError::ChecksumMismatch(ref filename, ref expected, ref actual) => json!({
+                "status": 400,
+                "error-type": "checksum-mismatch",
+                "message": format!("The upload of {filename} was corrupted"),
+                "filename": filename,
+                "expected": expected,
+                "actual": actual,
+            }),
+            ApiError::QuotaExceeded(ref message) => json!({
+                "status": 403,
+                "error-type": "quota-exceeded",
+                "message": message,
+            }),
+            ApiError::InvalidRefs(ref problems) => json!({
+                "status": 400,
+                "error-type": "invalid-refs",
+                "message": format!("{} problems with the build's refs", problems.len()),
+                "problems": problems,
+            }),
+            ApiError::InvalidTransition(ref message, ref action, ref state) => json!({
+                "status": 409,
+                "error-type": "invalid-transition",
+                "message": message,
+                "action": action,
+                "repo-state": state.repo_state,
+                "published-state": state.published_state,
+                "allowed-actions": state.allowed_actions,
+            }),
         }
     }
 
@@ -196,6 +337,15 @@ impl ApiError {
             ApiError::WrongPublishedState(_, _, _) => StatusCode::BAD_REQUEST,
             ApiError::InvalidToken(_) => StatusCode::UNAUTHORIZED,
             ApiError::NotEnoughPermissions(ref _message) => StatusCode::FORBIDDEN,
+            ApiError::PayloadTooLarge(ref _message) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::TooManyRequests(_, _) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::RevocationStoreOverloaded(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::UploadOffsetMismatch(_, _) => StatusCode::CONFLICT,
+            ApiError::InvalidCommits(_) => StatusCode::BAD_REQUEST,
+            ApiError::ChecksumMismatch(_, _, _) => StatusCode::BAD_REQUEST,
+            ApiError::QuotaExceeded(_) => StatusCode::FORBIDDEN,
+            ApiError::InvalidRefs(_) => StatusCode::BAD_REQUEST,
+            ApiError::InvalidTransition(_, _, _) => StatusCode::CONFLICT,
         }
     }
 }
@@ -211,10 +361,41 @@ impl ResponseError for ApiError {
                 internal_message
             );
         }
-        HttpResponse::build(self.status_code()).json(self.to_json())
+        let mut response = HttpResponse::build(self.status_code());
+        if let ApiError::TooManyRequests(_, retry_after)
+        | ApiError::RevocationStoreOverloaded(retry_after) = self
+        {
+            response.header(RETRY_AFTER, retry_after.to_string());
+        }
+        response.json(self.to_json())
     }
 
     fn render_response(&self) -> HttpResponse {
         self.error_response()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_job_error_is_transient() {
+        assert!(JobError::new(
+            "Command failed: error: While pulling app/org.example.App: Timeout was reached"
+        )
+        .is_transient());
+        assert!(
+            JobError::new("Locking repo exclusive failed: Resource temporarily unavailable")
+                .is_transient()
+        );
+        assert!(JobError::DBError(
+            "could not serialize access due to concurrent update".to_string()
+        )
+        .is_transient());
+        assert!(!JobError::new("No refs in build").is_transient());
+        assert!(!JobError::new("Can't parse commit job").is_transient());
+        assert!(!JobError::Cancelled.is_transient());
+        assert!(!JobError::TimedOut(3600).is_transient());
+    }
+}
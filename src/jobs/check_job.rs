@@ -3,6 +3,7 @@ use diesel::prelude::*;
 use log::info;
 use serde_json::json;
 
+use crate::db::ready_or_awaiting_approval;
 use crate::errors::{JobError, JobResult};
 use crate::models::{self, Build, Check, CheckJob, CheckStatus, Job, RepoState};
 use crate::schema::{builds, checks};
@@ -38,10 +39,6 @@ impl JobInstance for CheckJobInstance {
         self.job_id
     }
 
-    fn order(&self) -> i32 {
-        1 /* Same priority as regular publish jobs */
-    }
-
     fn handle_job(
         &mut self,
         executor: &JobExecutor,
@@ -157,7 +154,7 @@ impl JobInstance for CheckJobInstance {
 }
 
 /// When all checks have completed, the build should be moved to the Ready state if the checks all passed or Failed
-/// if any did not.
+/// if any did not. Builds that need more approvals are moved to the AwaitingApproval state instead of Ready.
 pub fn update_build_status_after_check(
     build_id: i32,
     conn: &mut PgConnection,
@@ -202,9 +199,9 @@ pub fn update_build_status_after_check(
             .filter(|(_name, status)| status.is_failed())
             .collect::<Vec<_>>();
 
-        // If any check failed, then the build fails, otherwise it is now ready for publishing
+        // If any check failed, then the build fails, otherwise it is now ready for publishing, or for approval
         let (new_state, new_state_reason) = if failing.is_empty() {
-            RepoState::Ready
+            ready_or_awaiting_approval(conn, &build)?
         } else {
             RepoState::Failed(format!(
                 "{} out of {} checks failed ({})",
@@ -10,6 +10,7 @@ use std::process::Command;
 use std::str;
 
 use crate::config::{Config, RepoConfig};
+use crate::db::ready_or_awaiting_approval;
 use crate::errors::{JobError, JobResult};
 use crate::models::{
     self, Check, CheckJob, CheckStatus, CommitJob, Job, JobKind, NewJob, RepoState,
@@ -19,7 +20,10 @@ use crate::schema::*;
 
 use super::job_executor::JobExecutor;
 use super::job_instance::{InvalidJobInstance, JobInstance};
-use super::utils::{add_gpg_args, do_command, generate_flatpakref};
+use super::utils::{
+    add_gpg_args, check_cancelled, do_command, generate_flatpakref, job_progress, retry_delay,
+    set_job_phase,
+};
 
 #[derive(Debug)]
 pub struct CommitJobInstance {
@@ -61,6 +65,7 @@ impl CommitJobInstance {
 
         let mut commits = HashMap::new();
 
+        set_job_phase(Some("commit"));
         let endoflife_rebase_arg = if let Some(endoflife_rebase) = &self.endoflife_rebase {
             build_refs
                 .iter()
@@ -76,7 +81,8 @@ impl CommitJobInstance {
             None
         };
 
-        for build_ref in build_refs.iter() {
+        for (i, build_ref) in build_refs.iter().enumerate() {
+            job_progress(self.job_id, conn, i as u64, build_refs.len() as u64);
             let mut cmd = Command::new("flatpak");
             cmd.arg("build-commit-from")
                 .arg("--timestamp=NOW") // All builds have the same timestamp, not when the individual builds finished
@@ -106,6 +112,7 @@ impl CommitJobInstance {
                 .arg(&build_repo_path)
                 .arg(&build_ref.ref_name);
 
+            check_cancelled(self.job_id, conn)?;
             job_log_and_info!(
                 self.job_id,
                 conn,
@@ -138,15 +145,24 @@ impl CommitJobInstance {
                 File::create(path)?.write_all(contents.as_bytes())?;
             }
         }
+        job_progress(
+            self.job_id,
+            conn,
+            build_refs.len() as u64,
+            build_refs.len() as u64,
+        );
 
         let mut cmd = Command::new("flatpak");
         cmd.arg("build-update-repo").arg(&build_repo_path);
 
         add_gpg_args(&mut cmd, &config.build_gpg_key, &config.gpg_homedir);
 
+        check_cancelled(self.job_id, conn)?;
+        set_job_phase(Some("update-repo"));
         job_log_and_info!(self.job_id, conn, "running build-update-repo");
         do_command(cmd)?;
 
+        set_job_phase(Some("appstream"));
         job_log_and_info!(self.job_id, conn, "extracting appstream");
         let appstream_dir = build_repo_path.join("appstream");
         fs::create_dir_all(&appstream_dir)?;
@@ -164,6 +180,28 @@ impl CommitJobInstance {
             do_command(cmd)?;
         }
 
+        /* Unpacked like at publish time, so that the screenshots can be looked at before that */
+        let screenshots_dir = build_repo_path.join("screenshots");
+        for build_ref in build_refs.iter() {
+            if build_ref.ref_name.starts_with("screenshots/") {
+                job_log_and_info!(
+                    self.job_id,
+                    conn,
+                    &format!("extracting {}", build_ref.ref_name),
+                );
+                let mut cmd = Command::new("ostree");
+                cmd.arg(format!("--repo={}", &build_repo_path.to_str().unwrap()))
+                    .arg("checkout")
+                    .arg("--user-mode")
+                    .arg("--bareuseronly-dirs")
+                    .arg("--union")
+                    .arg(&build_ref.ref_name)
+                    .arg(&screenshots_dir);
+                do_command(cmd)?;
+            }
+        }
+
+        set_job_phase(Some("cleanup"));
         job_log_and_info!(self.job_id, conn, "Removing upload directory");
         fs::remove_dir_all(&upload_path)?;
 
@@ -210,6 +248,13 @@ impl JobInstance for CommitJobInstance {
         // Do the actual work
         let res = self.do_commit_build_refs(&build_refs, config, repoconfig, conn);
 
+        // The build stays in the committing state if the job is going to be retried
+        if let Err(e) = &res {
+            if retry_delay(config, self.job_id, conn, e).is_some() {
+                return res;
+            }
+        }
+
         // Update the build repo state in db
         conn.transaction::<_, JobError, _>(|conn| {
             let current_build = builds::table
@@ -231,7 +276,7 @@ impl JobInstance for CommitJobInstance {
             let new_repo_state = match &res {
                 Ok(_) => {
                     if repoconfig.hooks.checks.is_empty() {
-                        RepoState::Ready
+                        ready_or_awaiting_approval(conn, &current_build)?
                     } else {
                         // Create a check job for each configured check hook
                         let check_jobs = diesel::insert_into(jobs::table)
@@ -242,6 +287,7 @@ impl JobInstance for CommitJobInstance {
                                     .keys()
                                     .map(|name| NewJob {
                                         kind: JobKind::Check.to_db(),
+                                        priority: JobKind::Check.default_priority(),
                                         start_after: None,
                                         repo: None,
                                         contents: json!(CheckJob {
@@ -276,7 +322,7 @@ impl JobInstance for CommitJobInstance {
                             .get_result::<Check>(conn)?;
 
                         // Put the build in the Validating state. The last check job to finish will move the build
-                        // into the Ready or Failed state.
+                        // into the Ready (or AwaitingApproval) or Failed state.
                         RepoState::Validating
                     }
                 }
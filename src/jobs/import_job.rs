@@ -0,0 +1,175 @@
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use log::info;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::Command;
+use walkdir::WalkDir;
+
+use crate::errors::{JobError, JobResult};
+use crate::models::{self, ImportJob, Job, NewBuildRef, RepoState};
+use crate::schema::{build_refs, builds};
+
+use super::job_executor::JobExecutor;
+use super::job_instance::{InvalidJobInstance, JobInstance};
+use super::utils::{check_cancelled, do_command, do_command_with_output, set_job_phase};
+
+/* The name of the remote the refs are pulled from, in the upload repo of the build */
+const IMPORT_REMOTE: &str = "import";
+
+#[derive(Debug)]
+pub struct ImportJobInstance {
+    pub job_id: i32,
+    pub import: ImportJob,
+}
+
+impl ImportJobInstance {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(job: Job) -> Box<dyn JobInstance> {
+        if let Ok(import) = serde_json::from_str::<ImportJob>(&job.contents) {
+            Box::new(ImportJobInstance {
+                job_id: job.id,
+                import,
+            })
+        } else {
+            InvalidJobInstance::new(job, JobError::new("Can't parse import job"))
+        }
+    }
+
+    fn pull_refs(
+        &self,
+        upload_path: &Path,
+        conn: &mut PgConnection,
+    ) -> JobResult<BTreeMap<String, String>> {
+        let repo_arg = format!("--repo={}", upload_path.to_str().unwrap());
+
+        let mut cmd = Command::new("ostree");
+        cmd.arg(&repo_arg)
+            .arg("remote")
+            .arg("add")
+            .arg("--if-not-exists")
+            .arg("--no-gpg-verify");
+        if let Some(collection_id) = &self.import.collection_id {
+            cmd.arg(format!("--collection-id={collection_id}"));
+        }
+        cmd.arg(IMPORT_REMOTE).arg(&self.import.url);
+        do_command(cmd)?;
+
+        check_cancelled(self.job_id, conn)?;
+        set_job_phase(Some("pull"));
+        job_log_and_info!(
+            self.job_id,
+            conn,
+            &format!(
+                "Pulling {} from {}",
+                self.import.refs.join(", "),
+                self.import.url
+            ),
+        );
+        let mut cmd = Command::new("ostree");
+        cmd.arg(&repo_arg)
+            .arg("pull")
+            .arg("--disable-fsync")
+            .arg(IMPORT_REMOTE)
+            .args(&self.import.refs);
+        do_command(cmd)?;
+
+        check_cancelled(self.job_id, conn)?;
+        let mut commits = BTreeMap::new();
+        for ref_name in &self.import.refs {
+            let mut cmd = Command::new("ostree");
+            cmd.arg(&repo_arg)
+                .arg("rev-parse")
+                .arg(format!("{IMPORT_REMOTE}:{ref_name}"));
+            let output = do_command_with_output(&mut cmd)?;
+            if !output.status.success() {
+                return Err(JobError::new(&format!(
+                    "Can't find the pulled commit of {ref_name}: {}",
+                    String::from_utf8_lossy(&output.stderr)
+                )));
+            }
+            let commit = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            commits.insert(ref_name.clone(), commit);
+        }
+        Ok(commits)
+    }
+}
+
+/* The size of the objects in a repo, which counts against the storage quotas like uploads do */
+fn objects_size(repo_path: &Path) -> u64 {
+    WalkDir::new(repo_path.join("objects"))
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| entry.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+impl JobInstance for ImportJobInstance {
+    fn get_job_id(&self) -> i32 {
+        self.job_id
+    }
+
+    fn handle_job(
+        &mut self,
+        executor: &JobExecutor,
+        conn: &mut PgConnection,
+    ) -> JobResult<serde_json::Value> {
+        info!(
+            "#{}: Handling Job Import: build: {}, url: {}, refs: {:?}",
+            &self.job_id, &self.import.build, &self.import.url, &self.import.refs
+        );
+
+        let config = &executor.config;
+        let build_id = self.import.build;
+
+        let build = builds::table
+            .filter(builds::id.eq(build_id))
+            .get_result::<models::Build>(conn)
+            .map_err(|_e| JobError::new("Can't load build"))?;
+        if !RepoState::from_db(build.repo_state, &build.repo_state_reason)
+            .same_state_as(&RepoState::Uploading)
+        {
+            return Err(JobError::new(&format!(
+                "Build {build_id} is not accepting uploads anymore"
+            )));
+        }
+
+        let upload_path = config
+            .build_repo_base
+            .join(build_id.to_string())
+            .join("upload");
+        let size_before = objects_size(&upload_path);
+
+        let commits = self.pull_refs(&upload_path, conn)?;
+
+        let pulled_bytes = objects_size(&upload_path).saturating_sub(size_before);
+        conn.transaction::<_, JobError, _>(|conn| {
+            diesel::insert_into(build_refs::table)
+                .values(
+                    commits
+                        .iter()
+                        .map(|(ref_name, commit)| NewBuildRef {
+                            build_id,
+                            ref_name: ref_name.clone(),
+                            commit: commit.clone(),
+                            build_log_url: None,
+                        })
+                        .collect::<Vec<_>>(),
+                )
+                .execute(conn)?;
+            diesel::update(builds::table)
+                .filter(builds::id.eq(build_id))
+                .set(builds::uploaded_bytes.eq(builds::uploaded_bytes + pulled_bytes as i64))
+                .execute(conn)?;
+            Ok(())
+        })?;
+
+        Ok(json!({
+            "refs": commits,
+            "pulled-bytes": pulled_bytes,
+        }))
+    }
+}
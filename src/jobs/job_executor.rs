@@ -1,25 +1,34 @@
 use actix::prelude::*;
 use actix::{Actor, SyncContext};
-use diesel::pg::PgConnection;
+use diesel::pg::{Pg, PgConnection};
 use diesel::prelude::*;
 use diesel::result::DatabaseErrorKind::SerializationFailure;
 use diesel::result::Error as DieselError;
 use log::{error, info};
 use serde_json::json;
 use std::cell::RefCell;
+use std::cmp::Reverse;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::{Instant, SystemTime};
 
 use crate::config::Config;
 use crate::deltas::DeltaGenerator;
+use crate::errors::JobError;
 use crate::jobs::job_instance::new_job_instance;
 use crate::models;
-use crate::models::{job_dependencies_with_status, JobStatus};
+use crate::models::{job_dependencies_with_status, JobKind, JobOutcome, JobStatus, JobUsage};
 use crate::schema::*;
 use crate::Pool;
 
 use super::job_instance::JobInstance;
 use super::job_queue::{ExecutorInfo, JobQueue};
+use super::purge_builds_job::queue_purge_builds_job;
+use super::schedules::queue_scheduled_jobs;
+use super::utils::{
+    command_usage, reset_command_usage, retry_delay, set_job_phase, set_job_timeout,
+};
+use super::workers::{live_worker_kinds, reclaim_abandoned_jobs, repo_busy_on_worker};
 
 pub struct JobExecutor {
     pub repo: Option<String>,
@@ -32,14 +41,54 @@ impl Actor for JobExecutor {
     type Context = SyncContext<Self>;
 }
 
-fn pick_next_job(
-    executor: &mut JobExecutor,
-    conn: &mut PgConnection,
-) -> Result<Box<dyn JobInstance>, DieselError> {
+/* The priority a job was queued with, unless its repo overrides the priority of its kind */
+fn job_priority(config: &Config, job: &models::Job) -> i32 {
+    let repo_priority = job
+        .repo
+        .as_ref()
+        .and_then(|repo| config.repos.get(repo))
+        .zip(JobKind::from_db(job.kind))
+        .and_then(|(repoconfig, kind)| repoconfig.job_priorities.get(&kind).copied());
+    repo_priority.unwrap_or(job.priority)
+}
+
+/* The jobs that can run now: they are queued, due and don't wait for unfinished jobs, oldest first */
+pub(super) fn ready_jobs<'a>() -> jobs::BoxedQuery<'a, Pg> {
     use diesel::dsl::exists;
     use diesel::dsl::not;
     use diesel::dsl::now;
 
+    jobs::table
+        .filter(jobs::status.eq(JobStatus::New as i16))
+        .filter(jobs::start_after.is_null().or(jobs::start_after.lt(now)))
+        .filter(not(exists(
+            job_dependencies_with_status::table.filter(
+                job_dependencies_with_status::job_id.eq(jobs::id).and(
+                    job_dependencies_with_status::dependant_status.le(JobStatus::Started as i16),
+                ),
+            ),
+        )))
+        .order(jobs::id)
+        .into_boxed()
+}
+
+/* The ready job to run first: by priority, then by the order of its kind */
+pub(super) fn next_job(config: &Config, mut ready_jobs: Vec<models::Job>) -> Option<models::Job> {
+    /* The sort being stable keeps the oldest jobs first */
+    ready_jobs.sort_by_key(|job| {
+        (
+            Reverse(job_priority(config, job)),
+            JobKind::from_db(job.kind).map_or(0, |kind| kind.order()),
+        )
+    });
+    ready_jobs.into_iter().next()
+}
+
+/* Picks the next job, returning it along with its kind. Kinds that external workers run are left to them. */
+fn pick_next_job(
+    executor: &mut JobExecutor,
+    conn: &mut PgConnection,
+) -> Result<(Box<dyn JobInstance>, i16), DieselError> {
     /* Find next job (if any) and mark it started */
 
     let for_repo = executor.repo.clone();
@@ -48,45 +97,37 @@ fn pick_next_job(
         .serializable()
         .deferrable()
         .run(|conn| {
-            let ready_job_filter = jobs::status
-                .eq(JobStatus::New as i16)
-                .and(jobs::start_after.is_null().or(jobs::start_after.lt(now)))
-                .and(not(exists(
-                    job_dependencies_with_status::table.filter(
-                        job_dependencies_with_status::job_id.eq(jobs::id).and(
-                            job_dependencies_with_status::dependant_status
-                                .le(JobStatus::Started as i16),
-                        ),
-                    ),
-                )));
-
-            let mut new_instances: Vec<Box<dyn JobInstance>> = match for_repo {
-                None => jobs::table
-                    .order(jobs::id)
-                    .filter(ready_job_filter.and(jobs::repo.is_null()))
-                    .get_results::<models::Job>(conn)?
-                    .into_iter()
-                    .map(|job| new_job_instance(executor, job))
-                    .collect(),
-                Some(repo) => jobs::table
-                    .order(jobs::id)
-                    .filter(ready_job_filter.and(jobs::repo.eq(repo)))
-                    .get_results::<models::Job>(conn)?
-                    .into_iter()
-                    .map(|job| new_job_instance(executor, job))
-                    .collect(),
+            let worker_kinds = live_worker_kinds(conn)?;
+            let ready_jobs = match &for_repo {
+                None => ready_jobs()
+                    .filter(jobs::repo.is_null())
+                    .filter(jobs::kind.ne_all(worker_kinds))
+                    .get_results::<models::Job>(conn)?,
+                Some(repo) => {
+                    /* The jobs of a repo run one at a time, wherever they run */
+                    if repo_busy_on_worker(conn, repo)? {
+                        return Err(diesel::NotFound);
+                    }
+                    ready_jobs()
+                        .filter(jobs::repo.eq(repo))
+                        .filter(jobs::kind.ne_all(worker_kinds))
+                        .get_results::<models::Job>(conn)?
+                }
             };
 
-            /* Sort by prio */
-            new_instances.sort_by_key(|a| a.order());
-
             /* Handle the first, if any */
-            if let Some(new_instance) = new_instances.into_iter().next() {
+            if let Some(job) = next_job(&executor.config, ready_jobs) {
                 diesel::update(jobs::table)
-                    .filter(jobs::id.eq(new_instance.get_job_id()))
-                    .set((jobs::status.eq(JobStatus::Started as i16),))
+                    .filter(jobs::id.eq(job.id))
+                    .set((
+                        jobs::status.eq(JobStatus::Started as i16),
+                        jobs::attempts.eq(jobs::attempts + 1),
+                        jobs::progress.eq(None::<String>),
+                        jobs::worker_id.eq(None::<i32>),
+                    ))
                     .execute(conn)?;
-                return Ok(new_instance);
+                let kind = job.kind;
+                return Ok((new_job_instance(executor, job), kind));
             }
 
             Err(diesel::NotFound)
@@ -99,33 +140,118 @@ fn pick_next_job(
     }
 }
 
+/* Runs a job that was marked started, returning how it ended. This is shared by the executors of the server and
+ * external workers. */
+pub(super) fn run_job(
+    executor: &JobExecutor,
+    conn: &mut PgConnection,
+    mut instance: Box<dyn JobInstance>,
+    kind: i16,
+) -> JobOutcome {
+    let job_id = instance.get_job_id();
+    let mut retry_after = None;
+    let started = Instant::now();
+    reset_command_usage();
+    set_job_phase(None);
+    set_job_timeout(
+        JobKind::from_db(kind).and_then(|kind| executor.config.job_timeouts.get(&kind).copied()),
+    );
+    let (new_status, new_results) = match instance.handle_job(executor, conn) {
+        Ok(json) => {
+            info!("#{}: Job succeeded", job_id);
+            (JobStatus::Ended, json.to_string())
+        }
+        Err(JobError::Cancelled) => {
+            job_log_and_info!(job_id, conn, "Job was cancelled");
+            (
+                JobStatus::Cancelled,
+                json!({"error-message": JobError::Cancelled.to_string()}).to_string(),
+            )
+        }
+        Err(JobError::TimedOut(timeout_secs)) => {
+            job_log_and_error!(
+                job_id,
+                conn,
+                &format!("Job timed out after {timeout_secs} seconds")
+            );
+            (
+                JobStatus::TimedOut,
+                json!({
+                    "error-message": JobError::TimedOut(timeout_secs).to_string(),
+                    "timeout-secs": timeout_secs,
+                })
+                .to_string(),
+            )
+        }
+        Err(e) => match retry_delay(&executor.config, job_id, conn, &e) {
+            /* Queued again, the attempts so far are in the log */
+            Some(delay) => {
+                job_log_and_error!(
+                    job_id,
+                    conn,
+                    &format!(
+                        "Attempt failed: {e}, retrying in {} seconds",
+                        delay.as_secs()
+                    )
+                );
+                retry_after = Some(SystemTime::now() + delay);
+                (
+                    JobStatus::New,
+                    json!({"error-message": e.to_string()}).to_string(),
+                )
+            }
+            None => {
+                job_log_and_error!(job_id, conn, &format!("Job failed: {e}"));
+                (
+                    JobStatus::Broken,
+                    json!({"error-message": e.to_string()}).to_string(),
+                )
+            }
+        },
+    };
+
+    JobOutcome {
+        status: new_status as i16,
+        results: new_results,
+        retry_after,
+        usage: JobUsage {
+            wall_time_ms: started.elapsed().as_millis() as i64,
+            ..command_usage()
+        },
+    }
+}
+
+/* Stores how an attempt at a job ended */
+pub(super) fn store_job_outcome(
+    conn: &mut PgConnection,
+    job_id: i32,
+    outcome: &JobOutcome,
+) -> Result<usize, DieselError> {
+    diesel::update(jobs::table)
+        .filter(jobs::id.eq(job_id))
+        .set((
+            jobs::status.eq(outcome.status),
+            jobs::results.eq(&outcome.results),
+            outcome
+                .retry_after
+                .map(|time| jobs::start_after.eq(Some(time))),
+            jobs::finished_at.eq(chrono::Utc::now().naive_utc()),
+            jobs::wall_time_ms.eq(outcome.usage.wall_time_ms),
+            jobs::cpu_time_ms.eq(outcome.usage.cpu_time_ms),
+            jobs::peak_rss_kb.eq(outcome.usage.peak_rss_kb),
+            jobs::bytes_written.eq(outcome.usage.bytes_written),
+        ))
+        .execute(conn)
+}
+
 fn process_one_job(executor: &mut JobExecutor, conn: &mut PgConnection) -> bool {
     let new_instance = pick_next_job(executor, conn);
 
     match new_instance {
-        Ok(mut instance) => {
-            let (new_status, new_results) = match instance.handle_job(executor, conn) {
-                Ok(json) => {
-                    info!("#{}: Job succeeded", instance.get_job_id());
-                    (JobStatus::Ended, json.to_string())
-                }
-                Err(e) => {
-                    job_log_and_error!(instance.get_job_id(), conn, &format!("Job failed: {e}"));
-                    (
-                        JobStatus::Broken,
-                        json!({"error-message": e.to_string()}).to_string(),
-                    )
-                }
-            };
-
-            let update_res = diesel::update(jobs::table)
-                .filter(jobs::id.eq(instance.get_job_id()))
-                .set((
-                    jobs::status.eq(new_status as i16),
-                    jobs::results.eq(new_results),
-                ))
-                .execute(conn);
-            if let Err(e) = update_res {
+        Ok((instance, kind)) => {
+            let job_id = instance.get_job_id();
+            let outcome = run_job(executor, conn, instance, kind);
+            if let Err(e) = store_job_outcome(conn, job_id, &outcome) {
                 error!("handle_job: Error updating job {}", e);
             }
             true /* We handled a job */
@@ -170,8 +296,82 @@ impl Handler<ProcessOneJob> for JobExecutor {
     }
 }
 
+pub struct QueuePurgeBuilds();
+
+impl Message for QueuePurgeBuilds {
+    type Result = Result<bool, ()>;
+}
+
+/* Queues a job purging expired builds if any repo has a retention period. Answers whether one was queued. */
+impl Handler<QueuePurgeBuilds> for JobExecutor {
+    type Result = Result<bool, ()>;
+
+    fn handle(&mut self, _msg: QueuePurgeBuilds, _ctx: &mut Self::Context) -> Self::Result {
+        if !self
+            .config
+            .repos
+            .values()
+            .any(|repoconfig| repoconfig.build_retention_days.is_some())
+        {
+            return Ok(false);
+        }
+
+        let mut conn = self.pool.get().map_err(|_e| ())?;
+        queue_purge_builds_job(&mut conn).map_err(|e| {
+            error!("Can't queue purge builds job: {}", e);
+        })
+    }
+}
+
+pub struct QueueScheduledJobs();
+
+impl Message for QueueScheduledJobs {
+    type Result = Result<Vec<String>, ()>;
+}
+
+/* Queues the jobs of the schedules in the config that are due. Answers the repos that jobs were queued for. */
+impl Handler<QueueScheduledJobs> for JobExecutor {
+    type Result = Result<Vec<String>, ()>;
+
+    fn handle(&mut self, _msg: QueueScheduledJobs, _ctx: &mut Self::Context) -> Self::Result {
+        if self
+            .config
+            .repos
+            .values()
+            .all(|repoconfig| repoconfig.schedules.is_empty())
+        {
+            return Ok(vec![]);
+        }
+
+        let mut conn = self.pool.get().map_err(|_e| ())?;
+        queue_scheduled_jobs(&self.config, &mut conn).map_err(|e| {
+            error!("Can't queue scheduled jobs: {}", e);
+        })
+    }
+}
+
+pub struct ReclaimAbandonedJobs();
+
+impl Message for ReclaimAbandonedJobs {
+    type Result = Result<Vec<Option<String>>, ()>;
+}
+
+/* Removes the external workers that went away and queues the jobs they were running again. Answers the repos of the
+ * jobs, so their executors can be kicked. */
+impl Handler<ReclaimAbandonedJobs> for JobExecutor {
+    type Result = Result<Vec<Option<String>>, ()>;
+
+    fn handle(&mut self, _msg: ReclaimAbandonedJobs, _ctx: &mut Self::Context) -> Self::Result {
+        let mut conn = self.pool.get().map_err(|_e| ())?;
+        reclaim_abandoned_jobs(&self.config, &mut conn).map_err(|e| {
+            error!("Can't reclaim the jobs of lost workers: {}", e);
+        })
+    }
+}
+
 fn start_executor(
     repo: &Option<String>,
+    workers: usize,
     config: &Arc<Config>,
     delta_generator: &Addr<DeltaGenerator>,
     pool: &Pool,
@@ -181,13 +381,14 @@ fn start_executor(
     let pool_copy = pool.clone();
     let repo_clone = repo.clone();
     RefCell::new(ExecutorInfo {
-        addr: SyncArbiter::start(1, move || JobExecutor {
+        addr: SyncArbiter::start(workers, move || JobExecutor {
             repo: repo_clone.clone(),
             config: config_copy.clone(),
             delta_generator: delta_generator_copy.clone(),
             pool: pool_copy.clone(),
         }),
-        processing_job: false,
+        workers,
+        processing_jobs: 0,
         job_queued: false,
     })
 }
@@ -200,13 +401,20 @@ pub fn start_job_executor(
     let mut executors = HashMap::new();
     executors.insert(
         None,
-        start_executor(&None, &config, &delta_generator, &pool),
+        start_executor(
+            &None,
+            config.build_job_workers.max(1),
+            &config,
+            &delta_generator,
+            &pool,
+        ),
     );
 
+    /* The jobs of a repo all change it, so they are run one at a time */
     for repo in config.repos.keys() {
         executors.insert(
             Some(repo.clone()),
-            start_executor(&Some(repo.clone()), &config, &delta_generator, &pool),
+            start_executor(&Some(repo.clone()), 1, &config, &delta_generator, &pool),
         );
     }
     JobQueue {
@@ -4,8 +4,11 @@ use diesel::pg::PgConnection;
 
 use super::check_job::CheckJobInstance;
 use super::commit_job::CommitJobInstance;
+use super::import_job::ImportJobInstance;
 use super::job_executor::JobExecutor;
+use super::prune_deltas_job::PruneDeltasJobInstance;
 use super::publish_job::PublishJobInstance;
+use super::purge_builds_job::PurgeBuildsJobInstance;
 use super::republish_job::RepublishJobInstance;
 use super::update_repo_job::UpdateRepoJobInstance;
 
@@ -18,15 +21,15 @@ pub fn new_job_instance(executor: &JobExecutor, job: Job) -> Box<dyn JobInstance
         }
         Some(JobKind::Republish) => RepublishJobInstance::new(job),
         Some(JobKind::Check) => CheckJobInstance::new(job),
+        Some(JobKind::PruneDeltas) => PruneDeltasJobInstance::new(job),
+        Some(JobKind::PurgeBuilds) => PurgeBuildsJobInstance::new(job),
+        Some(JobKind::Import) => ImportJobInstance::new(job),
         _ => InvalidJobInstance::new(job, JobError::new("Unknown job type")),
     }
 }
 
 pub trait JobInstance {
     fn get_job_id(&self) -> i32;
-    fn order(&self) -> i32 {
-        0
-    }
     fn handle_job(
         &mut self,
         executor: &JobExecutor,
@@ -11,6 +11,7 @@ use std::collections::{HashMap, HashSet};
 use std::str;
 use std::time;
 
+use crate::db::insert_job;
 use crate::models::{
     Job, JobDependency, JobKind, JobStatus, NewJob, PublishedState, RepoState, UpdateRepoJob,
 };
@@ -18,16 +19,33 @@ use crate::schema;
 use crate::schema::*;
 use crate::Pool;
 
-use super::job_executor::{JobExecutor, ProcessOneJob, StopJobs};
+use super::job_executor::{
+    JobExecutor, ProcessOneJob, QueuePurgeBuilds, QueueScheduledJobs, ReclaimAbandonedJobs,
+    StopJobs,
+};
+use super::workers::WORKER_TIMEOUT;
+
+/* How often expired builds are looked for */
+const PURGE_BUILDS_INTERVAL: time::Duration = time::Duration::from_secs(60 * 60);
+
+/* How often the schedules in the config are checked for jobs that are due */
+const SCHEDULES_INTERVAL: time::Duration = time::Duration::from_secs(60);
 
 // We have an async JobQueue object that wraps the sync JobExecutor, because
 // that way we can respond to incomming requests immediately and decide in
 // what order to handle them. In particular, we want to prioritize stop
-// operations and exit cleanly with outstanding jobs for next run
+// operations and exit cleanly with outstanding jobs for next run.
+//
+// Each repo has an executor of its own, so the jobs of different repos run in
+// parallel while those of the same repo are serialized. The executor of the
+// build jobs may have several workers, as those jobs only touch their own
+// build; the workers claim jobs in serializable transactions, so no job is
+// picked twice.
 
 pub struct ExecutorInfo {
     pub addr: Addr<JobExecutor>,
-    pub processing_job: bool,
+    pub workers: usize,
+    pub processing_jobs: usize,
     pub job_queued: bool,
 }
 
@@ -49,19 +67,23 @@ impl JobQueue {
         if !self.running {
             return;
         }
-        if info.processing_job {
+        if info.processing_jobs >= info.workers {
             info.job_queued = true;
-        } else {
-            info.processing_job = true;
-            info.job_queued = false;
+            return;
+        }
+        info.job_queued = false;
+
+        /* Start every idle worker; those that find no job to pick just stop again */
+        while info.processing_jobs < info.workers {
+            info.processing_jobs += 1;
 
             let repo = repo.clone();
             ctx.spawn(info.addr.send(ProcessOneJob()).into_actor(self).then(
                 |result, queue, ctx| {
-                    let job_queued = {
+                    let (job_queued, processing_jobs) = {
                         let mut info = queue.executors.get(&repo).unwrap().borrow_mut();
-                        info.processing_job = false;
-                        info.job_queued
+                        info.processing_jobs -= 1;
+                        (info.job_queued, info.processing_jobs)
                     };
 
                     if queue.running {
@@ -77,7 +99,7 @@ impl JobQueue {
                         // If we ran a job, or a job was queued, kick again
                         if job_queued || processed_job {
                             queue.kick(&repo, ctx);
-                        } else {
+                        } else if processing_jobs == 0 {
                             // We send a ProcessJobs message each time we added something to the
                             // db, but case something external modifes the db we have a 10 sec
                             // polling loop here.  Ideally this should be using NOTIFY/LISTEN
@@ -95,6 +117,80 @@ impl JobQueue {
     }
 }
 
+impl JobQueue {
+    /* Expired builds are purged by a job on the build executor. Builds that are being committed when it runs are
+     * left for the next run. */
+    fn queue_purge_builds(&mut self, ctx: &mut Context<Self>) {
+        if !self.running {
+            return;
+        }
+        let addr = match self.executors.get(&None) {
+            Some(executor_info) => executor_info.borrow().addr.clone(),
+            None => return,
+        };
+        ctx.spawn(
+            addr.send(QueuePurgeBuilds())
+                .into_actor(self)
+                .then(|result, queue, ctx| {
+                    if let Ok(Ok(true)) = result {
+                        queue.kick(&None, ctx);
+                    }
+                    actix::fut::ok(())
+                }),
+        );
+    }
+
+    /* Scheduled jobs are queued by the build executor, as it is the only one that isn't tied to a repo */
+    fn queue_scheduled_jobs(&mut self, ctx: &mut Context<Self>) {
+        if !self.running {
+            return;
+        }
+        let addr = match self.executors.get(&None) {
+            Some(executor_info) => executor_info.borrow().addr.clone(),
+            None => return,
+        };
+        ctx.spawn(
+            addr.send(QueueScheduledJobs())
+                .into_actor(self)
+                .then(|result, queue, ctx| {
+                    if let Ok(Ok(repos)) = result {
+                        for repo in repos {
+                            queue.kick(&Some(repo), ctx);
+                        }
+                    }
+                    actix::fut::ok(())
+                }),
+        );
+    }
+}
+
+impl JobQueue {
+    /* Jobs of external workers that went away are queued again by the build executor, like scheduled jobs */
+    fn reclaim_abandoned_jobs(&mut self, ctx: &mut Context<Self>) {
+        if !self.running {
+            return;
+        }
+        let addr = match self.executors.get(&None) {
+            Some(executor_info) => executor_info.borrow().addr.clone(),
+            None => return,
+        };
+        ctx.spawn(
+            addr.send(ReclaimAbandonedJobs())
+                .into_actor(self)
+                .then(|result, queue, ctx| {
+                    if let Ok(Ok(repos)) = result {
+                        for repo in repos.into_iter().collect::<HashSet<_>>() {
+                            if queue.executors.contains_key(&repo) {
+                                queue.kick(&repo, ctx);
+                            }
+                        }
+                    }
+                    actix::fut::ok(())
+                }),
+        );
+    }
+}
+
 impl Actor for JobQueue {
     type Context = Context<Self>;
 
@@ -104,6 +200,16 @@ impl Actor for JobQueue {
         for repo in repos {
             self.kick(&repo, ctx);
         }
+
+        ctx.run_interval(PURGE_BUILDS_INTERVAL, |queue, ctx| {
+            queue.queue_purge_builds(ctx)
+        });
+        ctx.run_interval(SCHEDULES_INTERVAL, |queue, ctx| {
+            queue.queue_scheduled_jobs(ctx)
+        });
+        ctx.run_interval(WORKER_TIMEOUT / 3, |queue, ctx| {
+            queue.reclaim_abandoned_jobs(ctx)
+        });
     }
 }
 
@@ -135,13 +241,15 @@ impl Handler<StopJobQueue> for JobQueue {
         // Stop assigning jobs to executors
         self.running = false;
 
-        // Send each executor a StopJobs message and wait for it to be run. Since the executors are single-threaded,
-        // and we aren't assigning any more jobs, we know that no jobs are in progress on an executor when its StopJobs
-        // message responds.
-        let stop_jobs = self
-            .executors
-            .values()
-            .map(|info| info.borrow().addr.send(StopJobs()).map_err(|_| ()));
+        // Send each worker of each executor a StopJobs message and wait for them to be run. A worker stops after
+        // handling the message, so each worker gets one of them, and since we aren't assigning any more jobs, we
+        // know that no jobs are in progress on an executor when all of its StopJobs messages respond.
+        let stop_jobs = self.executors.values().flat_map(|info| {
+            let info = info.borrow();
+            (0..info.workers)
+                .map(|_| info.addr.send(StopJobs()).map_err(|_| ()))
+                .collect::<Vec<_>>()
+        });
 
         // Wait for all the above futures to resolve
         ActorResponse::r#async(
@@ -152,16 +260,36 @@ impl Handler<StopJobQueue> for JobQueue {
     }
 }
 
+/* Jobs that were running on the server when it stopped are marked broken, and the builds they were working on
+ * failed. Jobs running on external workers are left to them, or to reclaim_abandoned_jobs if they went away too. */
 pub fn cleanup_started_jobs(pool: &Pool) -> Result<(), diesel::result::Error> {
     let mut conn = pool.get().unwrap();
+    let (worker_job_ids, worker_job_kinds): (Vec<i32>, Vec<i16>) = jobs::table
+        .select((jobs::id, jobs::kind))
+        .filter(jobs::status.eq(JobStatus::Started as i16))
+        .filter(jobs::worker_id.is_not_null())
+        .get_results::<(i32, i16)>(&mut conn)?
+        .into_iter()
+        .unzip();
     {
         use schema::builds::dsl::*;
         let (verifying, _) = RepoState::Committing.to_db();
         let (purging, _) = RepoState::Purging.to_db();
         let (failed, failed_reason) =
             RepoState::Failed("Server was restarted during job".to_string()).to_db();
+        /* Builds are purged by a job that isn't tracked in them */
+        let purging_on_worker = worker_job_kinds.contains(&JobKind::PurgeBuilds.to_db());
         let n_updated = diesel::update(builds)
-            .filter(repo_state.eq(verifying).or(repo_state.eq(purging)))
+            .filter(
+                repo_state
+                    .eq(verifying)
+                    .and(
+                        commit_job_id
+                            .is_null()
+                            .or(commit_job_id.ne_all(&worker_job_ids)),
+                    )
+                    .or(repo_state.eq(purging).and(!purging_on_worker)),
+            )
             .set((repo_state.eq(failed), repo_state_reason.eq(failed_reason)))
             .execute(&mut conn)?;
         if n_updated != 0 {
@@ -175,6 +303,11 @@ pub fn cleanup_started_jobs(pool: &Pool) -> Result<(), diesel::result::Error> {
             PublishedState::Failed("Server was restarted during publish".to_string()).to_db();
         let n_updated2 = diesel::update(builds)
             .filter(published_state.eq(publishing))
+            .filter(
+                publish_job_id
+                    .is_null()
+                    .or(publish_job_id.ne_all(&worker_job_ids)),
+            )
             .set((
                 published_state.eq(failed_publish),
                 published_state_reason.eq(failed_publish_reason),
@@ -191,6 +324,7 @@ pub fn cleanup_started_jobs(pool: &Pool) -> Result<(), diesel::result::Error> {
         use schema::jobs::dsl::*;
         let updated = diesel::update(jobs)
             .filter(status.eq(JobStatus::Started as i16))
+            .filter(worker_id.is_null())
             .set((status.eq(JobStatus::Broken as i16),))
             .get_results::<Job>(&mut conn)?;
         if !updated.is_empty() {
@@ -267,16 +401,15 @@ pub fn queue_update_job(
                 None => {
                     /* Create a new job */
                     let new_job =
-                        diesel::insert_into(schema::jobs::table)
-                        .values(NewJob {
+                        insert_job(conn, NewJob {
                             kind: JobKind::UpdateRepo.to_db(),
+                            priority: JobKind::UpdateRepo.default_priority(),
                             repo: Some(repo.to_string()),
                             start_after: Some(time::SystemTime::now() + time::Duration::new(delay_secs, 0)),
                             contents: json!(UpdateRepoJob {
                                 repo: repo.to_string()
                             }).to_string(),
-                        })
-                        .get_result::<Job>(conn)?;
+                        })?;
                     (true, new_job)
                 },
             };
@@ -3,16 +3,26 @@ mod utils;
 
 mod check_job;
 mod commit_job;
+mod import_job;
 mod job_executor;
 mod job_instance;
 mod job_queue;
+mod prune_deltas_job;
 mod publish_job;
+mod purge_builds_job;
 mod republish_job;
+mod schedules;
 mod update_repo_job;
+mod worker_client;
+mod workers;
 
 pub use check_job::update_build_status_after_check;
 pub use job_executor::start_job_executor;
 pub use job_queue::{cleanup_started_jobs, JobQueue, ProcessJobs, StopJobQueue};
+pub use worker_client::{start_job_worker, JobWorkerSettings};
+pub use workers::{
+    claim_job_for_worker, finish_worker_job, register_worker, remove_worker, worker_heartbeat,
+};
 
 /**************************************************************************
  * Job handling - theory of operations.
@@ -25,8 +35,15 @@ pub use job_queue::{cleanup_started_jobs, JobQueue, ProcessJobs, StopJobQueue};
  *   New - queued but not started
  *   Started - set when we start working on a job
  *   Ended - set when the job is done
- *   Broken - set when we get some internal error working on a job,
- *            or if an old job was marked "Started" already on startup.
+ *   Broken - set when we get some internal error working on a job that
+ *            isn't worth retrying (transient errors queue the job as New
+ *            again, with a later start_after),
+ *            or if an old job was marked "Started" already on startup
+ *            (unless it runs on an external worker).
+ *   Cancelled - set when a queued job is cancelled, or when a running
+ *            job that was asked to cancel reaches a checkpoint.
+ *   TimedOut - set when a job runs past the timeout of its kind. Any
+ *            command it was running is killed, along with its children.
  *
  * All jobs are run on single-threaded blocking actors, but we have
  * multiple of those. One per configured repo, and one for the builds.
@@ -37,6 +54,14 @@ pub use job_queue::{cleanup_started_jobs, JobQueue, ProcessJobs, StopJobQueue};
  * handling the queue of jobs and other messages such as StopJobs to
  * shut down things.
  *
+ * Jobs can also run on external workers, processes on other machines
+ * that share the database and repo storage. A worker registers the
+ * kinds of jobs it runs, and claims them one at a time over HTTP,
+ * sending heartbeats while it runs one. The executors leave those kinds
+ * to workers while any of them is alive, and the jobs of a worker that
+ * stops sending heartbeats are queued again (or marked Broken once out
+ * of attempts).
+ *
  * Since job status is changed on multiple thread all the
  * modification/access to that is serialized in the db using serialized
  * transactions.
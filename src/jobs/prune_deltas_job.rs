@@ -0,0 +1,146 @@
+use diesel::pg::PgConnection;
+use log::info;
+use serde_json::json;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use crate::errors::{JobError, JobResult};
+use crate::jobs::utils::schedule_update_job;
+use crate::models::{Job, PruneDeltasJob};
+use crate::ostree;
+
+use super::job_executor::JobExecutor;
+use super::job_instance::{InvalidJobInstance, JobInstance};
+use super::update_repo_job::retire_deltas;
+
+#[derive(Debug)]
+pub struct PruneDeltasJobInstance {
+    pub job_id: i32,
+    pub repo: String,
+    pub older_than_days: Option<u32>,
+    pub keep_commits: Option<u32>,
+}
+
+impl PruneDeltasJobInstance {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(job: Job) -> Box<dyn JobInstance> {
+        if let Ok(prune_job) = serde_json::from_str::<PruneDeltasJob>(&job.contents) {
+            let repo = if let Some(repo) = job.repo {
+                repo
+            } else {
+                return InvalidJobInstance::new(
+                    job,
+                    JobError::new("Prune deltas job requires a repo"),
+                );
+            };
+
+            Box::new(PruneDeltasJobInstance {
+                job_id: job.id,
+                repo,
+                older_than_days: prune_job.older_than_days,
+                keep_commits: prune_job.keep_commits,
+            })
+        } else {
+            InvalidJobInstance::new(job, JobError::new("Can't parse prune deltas job"))
+        }
+    }
+}
+
+/* The latest `count` commits of every ref in the repo */
+fn recent_commits(repo_path: &Path, count: u32) -> HashSet<String> {
+    let mut commits = HashSet::new();
+    for ref_name in ostree::list_refs(repo_path, "") {
+        let mut commit = ostree::parse_ref(repo_path, &ref_name).ok();
+        for _i in 0..count {
+            match commit {
+                Some(current) => {
+                    commit = ostree::get_commit(repo_path, &current)
+                        .ok()
+                        .and_then(|commitinfo| commitinfo.parent);
+                    commits.insert(current);
+                }
+                None => break,
+            }
+        }
+    }
+    commits
+}
+
+/* How long ago the delta was generated or uploaded */
+fn delta_age(repo_path: &Path, delta: &ostree::Delta) -> Option<Duration> {
+    let modified = fs::metadata(delta.delta_path(repo_path).ok()?)
+        .ok()?
+        .modified()
+        .ok()?;
+    SystemTime::now().duration_since(modified).ok()
+}
+
+impl JobInstance for PruneDeltasJobInstance {
+    fn get_job_id(&self) -> i32 {
+        self.job_id
+    }
+
+    fn handle_job(
+        &mut self,
+        executor: &JobExecutor,
+        conn: &mut PgConnection,
+    ) -> JobResult<serde_json::Value> {
+        info!(
+            "#{}: Handling Job PruneDeltas: repo: {}, older than {:?} days, keeping {:?} commits",
+            &self.job_id, &self.repo, &self.older_than_days, &self.keep_commits,
+        );
+
+        // Get repo config
+        let config = &executor.config;
+        let repoconfig = config
+            .get_repoconfig(&self.repo)
+            .map_err(|_e| JobError::new(&format!("Can't find repo {}", &self.repo)))?;
+        let repo_path = repoconfig.get_abs_repo_path();
+
+        let max_age = self
+            .older_than_days
+            .map(|days| Duration::from_secs(days as u64 * 24 * 60 * 60));
+        let recent = self
+            .keep_commits
+            .map(|count| recent_commits(&repo_path, count));
+
+        let to_prune: HashSet<ostree::Delta> = ostree::list_deltas(&repo_path)
+            .into_iter()
+            .filter(|delta| {
+                let too_old = match max_age {
+                    Some(max_age) => {
+                        delta_age(&repo_path, delta).map_or(false, |age| age > max_age)
+                    }
+                    None => false,
+                };
+                let superseded = match &recent {
+                    Some(recent) => !recent.contains(&delta.to),
+                    None => false,
+                };
+                too_old || superseded
+            })
+            .collect();
+
+        job_log_and_info!(
+            self.job_id,
+            conn,
+            &format!("Pruning {} deltas", to_prune.len()),
+        );
+        if to_prune.is_empty() {
+            return Ok(json!({ "pruned-deltas": 0 }));
+        }
+
+        retire_deltas(self.job_id, &to_prune, repoconfig, conn)?;
+
+        /* The summary still lists the pruned deltas. Note that the update also generates any deltas that the repo's
+         * delta depth asks for again. */
+        let update_job = schedule_update_job(config, repoconfig, conn, self.job_id)?;
+
+        Ok(json!({
+            "pruned-deltas": to_prune.len(),
+            "update-repo-job": update_job.id,
+        }))
+    }
+}
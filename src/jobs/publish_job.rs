@@ -19,12 +19,16 @@ use crate::schema::*;
 
 use super::job_executor::JobExecutor;
 use super::job_instance::{InvalidJobInstance, JobInstance};
-use super::utils::{add_gpg_args, do_command, generate_flatpakref, schedule_update_job};
+use super::utils::{
+    add_gpg_args, check_cancelled, do_command, generate_flatpakref, job_progress, retry_delay,
+    schedule_update_job, set_job_phase,
+};
 
 #[derive(Debug)]
 pub struct PublishJobInstance {
     pub job_id: i32,
     pub build_id: i32,
+    pub refs: Option<Vec<String>>,
 }
 
 impl PublishJobInstance {
@@ -34,6 +38,7 @@ impl PublishJobInstance {
             Box::new(PublishJobInstance {
                 job_id: job.id,
                 build_id: publish_job.build,
+                refs: publish_job.refs,
             })
         } else {
             InvalidJobInstance::new(job, JobError::new("Can't parse publish job"))
@@ -44,6 +49,7 @@ impl PublishJobInstance {
         &self,
         build: &models::Build,
         build_refs: &[models::BuildRef],
+        keep_build: bool,
         config: &Config,
         repoconfig: &RepoConfig,
         conn: &mut PgConnection,
@@ -51,6 +57,8 @@ impl PublishJobInstance {
         let build_repo_path = config.build_repo_base.join(self.build_id.to_string());
 
         // Run the publish hook, if any
+        check_cancelled(self.job_id, conn)?;
+        set_job_phase(Some("publish-hook"));
         if let Some(mut hook) = repoconfig
             .hooks
             .publish
@@ -68,7 +76,9 @@ impl PublishJobInstance {
         let mut src_repo_arg = OsString::from("--src-repo=");
         src_repo_arg.push(&build_repo_path);
 
-        // Import commit and modify refs
+        // Import commit and modify refs, the last chance to cancel before the main repo is changed
+        check_cancelled(self.job_id, conn)?;
+        set_job_phase(Some("import"));
 
         let mut cmd = Command::new("flatpak");
         cmd.arg("build-commit-from")
@@ -85,13 +95,19 @@ impl PublishJobInstance {
         }
 
         cmd.arg(&src_repo_arg).arg(&repoconfig.path);
+        if self.refs.is_some() {
+            cmd.args(build_refs.iter().map(|build_ref| &build_ref.ref_name));
+        }
 
         job_log_and_info!(
             self.job_id,
             conn,
             &format!("Importing build to repo {}", repoconfig.name),
         );
+        /* The import is a single command, so there is only its start and end to report */
+        job_progress(self.job_id, conn, 0, 1);
         do_command(cmd)?;
+        job_progress(self.job_id, conn, 1, 1);
 
         let appstream_dir = repoconfig.path.join("appstream");
         fs::create_dir_all(&appstream_dir)?;
@@ -99,6 +115,7 @@ impl PublishJobInstance {
         let media_dir = repoconfig.path.join("media");
         fs::create_dir_all(&media_dir)?;
 
+        set_job_phase(Some("appstream"));
         let mut commits = HashMap::new();
         for build_ref in build_refs.iter() {
             if build_ref.ref_name.starts_with("app/") || build_ref.ref_name.starts_with("runtime/")
@@ -140,6 +157,19 @@ impl PublishJobInstance {
 
         let update_job = schedule_update_job(config, repoconfig, conn, self.job_id)?;
 
+        if keep_build {
+            job_log_and_info!(
+                self.job_id,
+                conn,
+                &format!("Keeping build {}, it has held refs", self.build_id),
+            );
+            return Ok(json!({
+                "refs": commits,
+                "update-repo-job": update_job.id,
+            }));
+        }
+
+        set_job_phase(Some("cleanup"));
         let path = Path::new(&build_repo_path);
         job_log_and_info!(
             self.job_id,
@@ -163,19 +193,14 @@ impl JobInstance for PublishJobInstance {
         self.job_id
     }
 
-    fn order(&self) -> i32 {
-        1 /* Delay publish after commits (and other normal ops). because the
-          commits may generate more publishes. */
-    }
-
     fn handle_job(
         &mut self,
         executor: &JobExecutor,
         conn: &mut PgConnection,
     ) -> JobResult<serde_json::Value> {
         info!(
-            "#{}: Handling Job Publish: build: {}",
-            &self.job_id, &self.build_id
+            "#{}: Handling Job Publish: build: {}, refs: {:?}",
+            &self.job_id, &self.build_id, &self.refs
         );
 
         let config = &executor.config;
@@ -200,8 +225,32 @@ impl JobInstance for PublishJobInstance {
             return Err(JobError::new("No refs in build"));
         }
 
+        // Publish only the selected refs, keeping the build repo around for the held ones
+        let keep_build = build_refs.iter().any(|build_ref| build_ref.held);
+        let build_refs: Vec<models::BuildRef> = match &self.refs {
+            Some(refs) => build_refs
+                .into_iter()
+                .filter(|build_ref| refs.contains(&build_ref.ref_name))
+                .collect(),
+            None => build_refs,
+        };
+
         // Do the actual work
-        let res = self.do_publish(&build_data, &build_refs, config, repoconfig, conn);
+        let res = self.do_publish(
+            &build_data,
+            &build_refs,
+            keep_build,
+            config,
+            repoconfig,
+            conn,
+        );
+
+        // The build stays in the publishing state if the job is going to be retried
+        if let Err(e) = &res {
+            if retry_delay(config, self.job_id, conn, e).is_some() {
+                return res;
+            }
+        }
 
         // Update the publish repo state in db
 
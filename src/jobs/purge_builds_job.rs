@@ -0,0 +1,135 @@
+use chrono::Utc;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use log::info;
+use serde_json::json;
+use std::fs;
+use std::io;
+
+use crate::db::{finish_build_purge, init_build_purge, insert_job};
+use crate::errors::{ApiError, JobError, JobResult};
+use crate::models::{Job, JobKind, JobStatus, NewJob, PublishedState, RepoState};
+use crate::schema;
+
+use super::job_executor::JobExecutor;
+use super::job_instance::JobInstance;
+
+#[derive(Debug)]
+pub struct PurgeBuildsJobInstance {
+    pub job_id: i32,
+}
+
+impl PurgeBuildsJobInstance {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(job: Job) -> Box<dyn JobInstance> {
+        Box::new(PurgeBuildsJobInstance { job_id: job.id })
+    }
+}
+
+/* Queues a job purging expired builds, unless one is already waiting or running. Returns whether a job was
+ * queued. */
+pub fn queue_purge_builds_job(conn: &mut PgConnection) -> Result<bool, diesel::result::Error> {
+    conn.build_transaction().serializable().run(|conn| {
+        use schema::jobs::dsl::*;
+        let pending = jobs
+            .filter(kind.eq(JobKind::PurgeBuilds.to_db()))
+            .filter(status.le(JobStatus::Started as i16))
+            .count()
+            .get_result::<i64>(conn)?;
+        if pending > 0 {
+            return Ok(false);
+        }
+
+        insert_job(
+            conn,
+            NewJob {
+                kind: JobKind::PurgeBuilds.to_db(),
+                priority: JobKind::PurgeBuilds.default_priority(),
+                contents: json!({}).to_string(),
+                start_after: None,
+                repo: None,
+            },
+        )?;
+        Ok(true)
+    })
+}
+
+fn api_to_job_error(e: ApiError) -> JobError {
+    JobError::new(&e.to_string())
+}
+
+impl JobInstance for PurgeBuildsJobInstance {
+    fn get_job_id(&self) -> i32 {
+        self.job_id
+    }
+
+    fn handle_job(
+        &mut self,
+        executor: &JobExecutor,
+        conn: &mut PgConnection,
+    ) -> JobResult<serde_json::Value> {
+        info!("#{}: Handling Job PurgeBuilds", &self.job_id);
+
+        let config = &executor.config;
+        let mut purged = vec![];
+
+        for repoconfig in config.repos.values() {
+            let retention_days = match repoconfig.build_retention_days {
+                Some(retention_days) => retention_days,
+                None => continue,
+            };
+            let cutoff = Utc::now().naive_utc() - chrono::Duration::days(retention_days as i64);
+
+            let expired = {
+                use schema::builds::dsl::*;
+                let (published, _) = PublishedState::Published.to_db();
+                let (purged_state, _) = RepoState::Purged.to_db();
+                builds
+                    .select(id)
+                    .filter(repo.eq(&repoconfig.name))
+                    .filter(pinned.eq(false))
+                    .filter(created_at.lt(cutoff))
+                    .filter(published_state.ne(published))
+                    .filter(repo_state.ne(purged_state))
+                    .order(id)
+                    .get_results::<i32>(conn)?
+            };
+
+            for build_id in expired {
+                /* Builds that are being committed or published are left for the next run */
+                if let Err(e) =
+                    conn.transaction::<_, ApiError, _>(|conn| init_build_purge(conn, build_id))
+                {
+                    job_log_and_info!(
+                        self.job_id,
+                        conn,
+                        &format!("Not purging build {build_id}: {e}"),
+                    );
+                    continue;
+                }
+
+                job_log_and_info!(
+                    self.job_id,
+                    conn,
+                    &format!(
+                        "Purging build {build_id} of repo {}, older than {retention_days} days",
+                        repoconfig.name
+                    ),
+                );
+                let error =
+                    match fs::remove_dir_all(config.build_repo_base.join(build_id.to_string())) {
+                        Ok(()) => None,
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                        Err(e) => Some(e.to_string()),
+                    };
+                conn.transaction::<_, ApiError, _>(|conn| {
+                    finish_build_purge(conn, build_id, error)
+                })
+                .map_err(api_to_job_error)?;
+                purged.push(build_id);
+            }
+        }
+
+        Ok(json!({ "purged-builds": purged }))
+    }
+}
@@ -15,7 +15,7 @@ use crate::ostree::init_ostree_repo;
 
 use super::job_executor::JobExecutor;
 use super::job_instance::{InvalidJobInstance, JobInstance};
-use super::utils::{add_gpg_args, do_command};
+use super::utils::{add_gpg_args, check_cancelled, do_command, job_progress};
 
 #[derive(Debug)]
 pub struct RepublishJobInstance {
@@ -57,10 +57,6 @@ impl JobInstance for RepublishJobInstance {
         self.job_id
     }
 
-    fn order(&self) -> i32 {
-        1 /* Same priority as regular publish jobs */
-    }
-
     fn handle_job(
         &mut self,
         executor: &JobExecutor,
@@ -104,7 +100,10 @@ impl JobInstance for RepublishJobInstance {
             .map_err(|e| JobError::new(&format!("Failed to load repo {}: {}", &self.repo, e)))?;
 
         // Import commits to the temporary repo
-        for (ref_name, _checksum) in refs {
+        let n_refs = refs.len() as u64;
+        for (i, (ref_name, _checksum)) in refs.into_iter().enumerate() {
+            check_cancelled(self.job_id, conn)?;
+            job_progress(self.job_id, conn, i as u64, n_refs);
             job_log_and_info!(self.job_id, conn, &format!("Re-publishing {}", &ref_name));
 
             let mut cmd = Command::new("flatpak");
@@ -126,6 +125,7 @@ impl JobInstance for RepublishJobInstance {
 
             do_command(cmd)?;
         }
+        job_progress(self.job_id, conn, n_refs, n_refs);
 
         // Run the publish hook, if any
         if let Some(mut hook) = repoconfig
@@ -142,6 +142,7 @@ impl JobInstance for RepublishJobInstance {
         }
 
         // Publish the potentially edited commits back to the main repo
+        check_cancelled(self.job_id, conn)?;
         let mut cmd = Command::new("flatpak");
         cmd.arg("build-commit-from").arg("--no-update-summary"); // We update it separately
 
@@ -0,0 +1,135 @@
+use chrono::{NaiveDateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::result::Error as DieselError;
+use log::info;
+use rand::Rng;
+use serde_json::json;
+
+use crate::config::{Config, ScheduleConfig, ScheduledJob};
+use crate::db::insert_job;
+use crate::models::{Job, JobKind, JobSchedule, JobStatus, NewJob, PruneDeltasJob};
+use crate::schema::{job_schedules, jobs};
+
+use super::job_queue::queue_update_job;
+
+/* When a schedule runs after now, with its jitter */
+fn next_run_after(now: NaiveDateTime, schedule: &ScheduleConfig) -> NaiveDateTime {
+    let jitter = if schedule.jitter_secs > 0 {
+        rand::thread_rng().gen_range(0..=schedule.jitter_secs)
+    } else {
+        0
+    };
+    now + chrono::Duration::seconds((schedule.interval_secs + jitter) as i64)
+}
+
+fn queue_scheduled_job(
+    conn: &mut PgConnection,
+    repo: &str,
+    job: &ScheduledJob,
+) -> Result<Job, DieselError> {
+    match job {
+        ScheduledJob::UpdateRepo => Ok(queue_update_job(0, conn, repo, None)?.1),
+        ScheduledJob::PruneDeltas {
+            older_than_days,
+            keep_commits,
+        } => conn.transaction(|conn| {
+            insert_job(
+                conn,
+                NewJob {
+                    kind: JobKind::PruneDeltas.to_db(),
+                    priority: JobKind::PruneDeltas.default_priority(),
+                    start_after: None,
+                    repo: Some(repo.to_string()),
+                    contents: json!(PruneDeltasJob {
+                        older_than_days: *older_than_days,
+                        keep_commits: *keep_commits,
+                    })
+                    .to_string(),
+                },
+            )
+        }),
+    }
+}
+
+/* Queues the jobs of the schedules that are due and works out when they run next. A new schedule first runs one
+ * interval after it shows up in the config, and a run is skipped if the job of the previous one hasn't finished
+ * yet. Returns the repos that jobs were queued for. */
+pub fn queue_scheduled_jobs(
+    config: &Config,
+    conn: &mut PgConnection,
+) -> Result<Vec<String>, DieselError> {
+    let mut queued_repos = vec![];
+
+    for repoconfig in config.repos.values() {
+        let repo = &repoconfig.name;
+        for (name, schedule) in &repoconfig.schedules {
+            let now = Utc::now().naive_utc();
+            let state = job_schedules::table
+                .find((repo, name))
+                .get_result::<JobSchedule>(conn)
+                .optional()?;
+
+            let state = match state {
+                Some(state) => state,
+                None => {
+                    diesel::insert_into(job_schedules::table)
+                        .values(JobSchedule {
+                            repo: repo.clone(),
+                            name: name.clone(),
+                            next_run: next_run_after(now, schedule),
+                            last_job_id: None,
+                        })
+                        .execute(conn)?;
+                    continue;
+                }
+            };
+
+            if state.next_run > now {
+                /* A shorter interval in the config applies right away, not only after the next run */
+                let latest_run = now
+                    + chrono::Duration::seconds(
+                        (schedule.interval_secs + schedule.jitter_secs) as i64,
+                    );
+                if state.next_run > latest_run {
+                    diesel::update(job_schedules::table.find((repo, name)))
+                        .set(job_schedules::next_run.eq(next_run_after(now, schedule)))
+                        .execute(conn)?;
+                }
+                continue;
+            }
+
+            let last_job_running = match state.last_job_id {
+                Some(last_job_id) => {
+                    jobs::table
+                        .find(last_job_id)
+                        .select(jobs::status)
+                        .get_result::<i16>(conn)
+                        .optional()?
+                        .unwrap_or(JobStatus::Ended as i16)
+                        <= JobStatus::Started as i16
+                }
+                None => false,
+            };
+
+            let last_job_id = if last_job_running {
+                info!("Skipping schedule {name} of repo {repo}, its last job is still unfinished");
+                state.last_job_id
+            } else {
+                let job = queue_scheduled_job(conn, repo, &schedule.job)?;
+                info!("Schedule {name} of repo {repo} queued job {}", job.id);
+                queued_repos.push(repo.clone());
+                Some(job.id)
+            };
+
+            diesel::update(job_schedules::table.find((repo, name)))
+                .set((
+                    job_schedules::next_run.eq(next_run_after(now, schedule)),
+                    job_schedules::last_job_id.eq(last_job_id),
+                ))
+                .execute(conn)?;
+        }
+    }
+
+    Ok(queued_repos)
+}
@@ -7,7 +7,7 @@ use std::fs::{self};
 use std::iter::FromIterator;
 use std::path::PathBuf;
 use std::process::Command;
-use std::sync::mpsc;
+use std::sync::mpsc::{self, RecvTimeoutError};
 use std::time;
 use walkdir::WalkDir;
 
@@ -19,7 +19,9 @@ use crate::ostree;
 
 use super::job_executor::JobExecutor;
 use super::job_instance::{InvalidJobInstance, JobInstance};
-use super::utils::{add_gpg_args, do_command};
+use super::utils::{
+    add_gpg_args, check_cancelled, do_command, job_progress, job_time_left, set_job_phase,
+};
 
 #[derive(Debug)]
 pub struct UpdateRepoJobInstance {
@@ -75,6 +77,7 @@ impl UpdateRepoJobInstance {
         repoconfig: &RepoConfig,
         conn: &mut PgConnection,
     ) -> JobResult<()> {
+        set_job_phase(Some("deltas"));
         job_log_and_info!(self.job_id, conn, "Generating deltas");
 
         let (tx, rx) = mpsc::channel();
@@ -94,7 +97,23 @@ impl UpdateRepoJobInstance {
             })
         }
 
-        for (delta, result) in rx.iter().take(deltas.len()) {
+        /* Waiting for the deltas counts against the timeout of the job, if any */
+        let mut received = 0;
+        job_progress(self.job_id, conn, 0, deltas.len() as u64);
+        while received < deltas.len() {
+            let (delta, result) = match job_time_left()? {
+                Some(time_left) => match rx.recv_timeout(time_left) {
+                    Ok(next) => next,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                },
+                None => match rx.recv() {
+                    Ok(next) => next,
+                    Err(_) => break,
+                },
+            };
+            received += 1;
+            job_progress(self.job_id, conn, received as u64, deltas.len() as u64);
             let message = match result {
                 Ok(()) => format!(" {delta}"),
                 Err(e) => format!(" failed to generate {delta}: {e}"),
@@ -107,90 +126,13 @@ impl UpdateRepoJobInstance {
         Ok(())
     }
 
-    fn retire_deltas(
-        &self,
-        deltas: &HashSet<ostree::Delta>,
-        repoconfig: &RepoConfig,
-        conn: &mut PgConnection,
-    ) -> JobResult<()> {
-        job_log_and_info!(self.job_id, conn, "Cleaning out old deltas");
-        let repo_path = repoconfig.get_abs_repo_path();
-        let deltas_dir = repo_path.join("deltas");
-        let tmp_deltas_dir = repo_path.join("deltas/.tmp");
-        fs::create_dir_all(&tmp_deltas_dir)?;
-
-        let now = time::SystemTime::now();
-        let now_filetime = filetime::FileTime::from_system_time(now);
-
-        /* Instead of directly removing the deltas we move them to a different
-         * directory which is *also* used as a source for the delta dir when accessed
-         * via http. This way we avoid them disappearing while possibly in use, yet
-         * ensure they are not picked up for the new summary file */
-
-        for delta in deltas {
-            let src = delta.delta_path(&repo_path)?;
-            let dst = delta.tmp_delta_path(&repo_path)?;
-            let dst_parent = dst.parent().unwrap();
-            fs::create_dir_all(dst_parent)?;
-
-            job_log_and_info!(
-                self.job_id,
-                conn,
-                &format!(
-                    " Queuing delta {:?} for deletion",
-                    src.strip_prefix(&deltas_dir).unwrap()
-                ),
-            );
-
-            if dst.exists() {
-                fs::remove_dir_all(&dst)?;
-            }
-            fs::rename(&src, &dst)?;
-
-            /* Update mtime so we can use it to trigger deletion */
-            filetime::set_file_times(&dst, now_filetime, now_filetime)?;
-        }
-
-        /* Delete all temporary deltas older than one hour */
-        let to_delete = WalkDir::new(&tmp_deltas_dir)
-            .min_depth(2)
-            .max_depth(2)
-            .into_iter()
-            .filter_map(|e| e.ok())
-            .filter(|e| {
-                if let Ok(metadata) = e.metadata() {
-                    if let Ok(mtime) = metadata.modified() {
-                        if let Ok(since) = now.duration_since(mtime) {
-                            return since.as_secs() > 60 * 60;
-                        }
-                    }
-                };
-                false
-            })
-            .map(|e| e.path().to_path_buf())
-            .collect::<Vec<PathBuf>>();
-
-        for dir in to_delete {
-            job_log_and_info!(
-                self.job_id,
-                conn,
-                &format!(
-                    " Deleting old delta {:?}",
-                    dir.strip_prefix(&tmp_deltas_dir).unwrap()
-                ),
-            );
-            fs::remove_dir_all(&dir)?;
-        }
-
-        Ok(())
-    }
-
     fn update_appstream(
         &self,
         config: &Config,
         repoconfig: &RepoConfig,
         conn: &mut PgConnection,
     ) -> JobResult<()> {
+        set_job_phase(Some("appstream"));
         job_log_and_info!(self.job_id, conn, "Regenerating appstream branches");
         let repo_path = repoconfig.get_abs_repo_path();
 
@@ -209,6 +151,7 @@ impl UpdateRepoJobInstance {
         repoconfig: &RepoConfig,
         conn: &mut PgConnection,
     ) -> JobResult<()> {
+        set_job_phase(Some("summary"));
         job_log_and_info!(self.job_id, conn, "Updating summary");
         let repo_path = repoconfig.get_abs_repo_path();
 
@@ -226,6 +169,7 @@ impl UpdateRepoJobInstance {
             let repo_path = repoconfig.get_abs_repo_path();
             let mut cmd = Command::new(post_publish_script);
             cmd.arg(&repoconfig.name).arg(&repo_path);
+            set_job_phase(Some("post-publish"));
             job_log_and_info!(self.job_id, conn, "Running post-publish script");
             do_command(cmd)?;
         };
@@ -233,6 +177,7 @@ impl UpdateRepoJobInstance {
     }
 
     fn extract_appstream(&self, repoconfig: &RepoConfig, conn: &mut PgConnection) -> JobResult<()> {
+        set_job_phase(Some("appstream"));
         job_log_and_info!(self.job_id, conn, "Extracting appstream branches");
         let repo_path = repoconfig.get_abs_repo_path();
         let appstream_dir = repo_path.join("appstream");
@@ -258,10 +203,6 @@ impl JobInstance for UpdateRepoJobInstance {
         self.job_id
     }
 
-    fn order(&self) -> i32 {
-        2 /* Delay updates after publish so they can be chunked. */
-    }
-
     fn handle_job(
         &mut self,
         executor: &JobExecutor,
@@ -280,9 +221,11 @@ impl JobInstance for UpdateRepoJobInstance {
 
         self.update_appstream(config, repoconfig, conn)?;
 
+        /* Deltas take the longest, a cancelled update leaves the summary to the next one */
+        check_cancelled(self.job_id, conn)?;
         let (missing_deltas, unwanted_deltas) = self.calculate_deltas(repoconfig);
         self.generate_deltas(&missing_deltas, repoconfig, conn)?;
-        self.retire_deltas(&unwanted_deltas, repoconfig, conn)?;
+        retire_deltas(self.job_id, &unwanted_deltas, repoconfig, conn)?;
 
         self.extract_appstream(repoconfig, conn)?;
 
@@ -293,3 +236,83 @@ impl JobInstance for UpdateRepoJobInstance {
         Ok(json!({}))
     }
 }
+
+/* Moves deltas out of the repo so they are no longer in the summary, and deletes ones that were moved out long
+ * enough ago that nobody can still be downloading them */
+pub fn retire_deltas(
+    job_id: i32,
+    deltas: &HashSet<ostree::Delta>,
+    repoconfig: &RepoConfig,
+    conn: &mut PgConnection,
+) -> JobResult<()> {
+    job_log_and_info!(job_id, conn, "Cleaning out old deltas");
+    let repo_path = repoconfig.get_abs_repo_path();
+    let deltas_dir = repo_path.join("deltas");
+    let tmp_deltas_dir = repo_path.join("deltas/.tmp");
+    fs::create_dir_all(&tmp_deltas_dir)?;
+
+    let now = time::SystemTime::now();
+    let now_filetime = filetime::FileTime::from_system_time(now);
+
+    /* Instead of directly removing the deltas we move them to a different
+     * directory which is *also* used as a source for the delta dir when accessed
+     * via http. This way we avoid them disappearing while possibly in use, yet
+     * ensure they are not picked up for the new summary file */
+
+    for delta in deltas {
+        let src = delta.delta_path(&repo_path)?;
+        let dst = delta.tmp_delta_path(&repo_path)?;
+        let dst_parent = dst.parent().unwrap();
+        fs::create_dir_all(dst_parent)?;
+
+        job_log_and_info!(
+            job_id,
+            conn,
+            &format!(
+                " Queuing delta {:?} for deletion",
+                src.strip_prefix(&deltas_dir).unwrap()
+            ),
+        );
+
+        if dst.exists() {
+            fs::remove_dir_all(&dst)?;
+        }
+        fs::rename(&src, &dst)?;
+
+        /* Update mtime so we can use it to trigger deletion */
+        filetime::set_file_times(&dst, now_filetime, now_filetime)?;
+    }
+
+    /* Delete all temporary deltas older than one hour */
+    let to_delete = WalkDir::new(&tmp_deltas_dir)
+        .min_depth(2)
+        .max_depth(2)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| {
+            if let Ok(metadata) = e.metadata() {
+                if let Ok(mtime) = metadata.modified() {
+                    if let Ok(since) = now.duration_since(mtime) {
+                        return since.as_secs() > 60 * 60;
+                    }
+                }
+            };
+            false
+        })
+        .map(|e| e.path().to_path_buf())
+        .collect::<Vec<PathBuf>>();
+
+    for dir in to_delete {
+        job_log_and_info!(
+            job_id,
+            conn,
+            &format!(
+                " Deleting old delta {:?}",
+                dir.strip_prefix(&tmp_deltas_dir).unwrap()
+            ),
+        );
+        fs::remove_dir_all(&dir)?;
+    }
+
+    Ok(())
+}
@@ -2,14 +2,19 @@ use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel::result::Error as DieselError;
 use log::{error, info};
+use std::cell::Cell;
 use std::fmt::Write as _;
-use std::os::unix::process::CommandExt;
-use std::process::{Command, Output, Stdio};
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Command, ExitStatus, Output, Stdio};
 use std::str;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::config::{Config, RepoConfig};
 use crate::errors::{JobError, JobResult};
-use crate::models::Job;
+use crate::models::{Job, JobProgress, JobUsage, NewJobLogRecord};
 use crate::schema::*;
 
 use super::job_queue::queue_update_job;
@@ -100,13 +105,86 @@ pub fn add_gpg_args(
     };
 }
 
-pub fn job_log(job_id: i32, conn: &mut PgConnection, output: &str) {
+thread_local! {
+    /* The phase of the job running on this executor thread, which its log records are tagged with */
+    static JOB_PHASE: Cell<Option<&'static str>> = const { Cell::new(None) };
+}
+
+/// Tags the following log records of the job running on this thread with a phase, e.g. "import".
+pub fn set_job_phase(phase: Option<&'static str>) {
+    JOB_PHASE.with(|current| current.set(phase));
+}
+
+thread_local! {
+    /* When the job running on this thread has to be done by, along with its timeout in seconds */
+    static JOB_DEADLINE: Cell<Option<(Instant, u64)>> = const { Cell::new(None) };
+}
+
+/// Limits how long the job running on this thread may take from now, see Config::job_timeouts.
+pub fn set_job_timeout(timeout_secs: Option<u64>) {
+    let deadline = timeout_secs.map(|secs| (Instant::now() + Duration::from_secs(secs), secs));
+    JOB_DEADLINE.with(|current| current.set(deadline));
+}
+
+/// How long the job running on this thread has left, or None if it has no timeout. Fails with JobError::TimedOut
+/// once the time is up.
+pub fn job_time_left() -> JobResult<Option<Duration>> {
+    match JOB_DEADLINE.with(|current| current.get()) {
+        Some((deadline, timeout_secs)) => {
+            let now = Instant::now();
+            if now >= deadline {
+                Err(JobError::TimedOut(timeout_secs))
+            } else {
+                Ok(Some(deadline - now))
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+pub fn job_log(job_id: i32, conn: &mut PgConnection, level: &str, output: &str) {
+    let record = NewJobLogRecord {
+        job_id,
+        level,
+        phase: JOB_PHASE.with(|current| current.get()),
+        message: output,
+    };
+    if let Err(e) = diesel::insert_into(job_log_records::table)
+        .values(&record)
+        .execute(conn)
+    {
+        error!("Error appending to job {} log: {}", job_id, e.to_string());
+    }
+}
+
+thread_local! {
+    /* When the job running on this thread last stored its progress */
+    static PROGRESS_STORED_AT: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/* Progress is stored at most this often, besides at the start and end of a phase */
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reports how far the current phase of a job got, e.g. `done` of `total` deltas generated, for the job API and its
+/// log stream.
+pub fn job_progress(job_id: i32, conn: &mut PgConnection, done: u64, total: u64) {
+    let now = Instant::now();
+    let throttled = matches!(
+        PROGRESS_STORED_AT.with(|current| current.get()),
+        Some(stored_at) if now < stored_at + PROGRESS_INTERVAL
+    );
+    if throttled && done > 0 && done < total {
+        return;
+    }
+    PROGRESS_STORED_AT.with(|current| current.set(Some(now)));
+
+    let progress = JobProgress::new(JOB_PHASE.with(|current| current.get()), done, total);
     if let Err(e) = diesel::update(jobs::table)
         .filter(jobs::id.eq(job_id))
-        .set((jobs::log.eq(jobs::log.concat(&output)),))
+        .set(jobs::progress.eq(serde_json::to_string(&progress).unwrap_or_default()))
         .execute(conn)
     {
-        error!("Error appending to job {} log: {}", job_id, e.to_string());
+        error!("Error storing job {} progress: {}", job_id, e.to_string());
     }
 }
 
@@ -115,7 +193,7 @@ macro_rules! job_log_and_info {
         let job_id = $job_id;
         let output = $output;
         info!("#{}: {}", job_id, output);
-        crate::jobs::utils::job_log(job_id, $conn, &format!("{output}\n"));
+        crate::jobs::utils::job_log(job_id, $conn, "info", output.as_ref());
     }};
 }
 
@@ -124,14 +202,61 @@ macro_rules! job_log_and_error {
         let job_id = $job_id;
         let output = $output;
         error!("#{}: {}", job_id, output);
-        crate::jobs::utils::job_log(job_id, $conn, &format!("{output}\n"));
+        crate::jobs::utils::job_log(job_id, $conn, "error", output.as_ref());
     }};
 }
 
+/// A safe point for a running job to stop at, between two steps that each leave the repos consistent. Fails with
+/// JobError::Cancelled if the job was asked to cancel, or JobError::TimedOut if it ran out of time.
+pub fn check_cancelled(job_id: i32, conn: &mut PgConnection) -> JobResult<()> {
+    job_time_left()?;
+    let cancel_requested = jobs::table
+        .select(jobs::cancel_requested)
+        .filter(jobs::id.eq(job_id))
+        .get_result::<bool>(conn)?;
+    if cancel_requested {
+        return Err(JobError::Cancelled);
+    }
+    Ok(())
+}
+
+/* The longest a job waits before being retried, however many attempts it took */
+const MAX_RETRY_DELAY_SECS: u64 = 3600;
+
+/// How long to wait before trying a failed job again, or None if it shouldn't be retried: because the error isn't
+/// transient, the job has used up its attempts, or it was asked to cancel.
+pub fn retry_delay(
+    config: &Config,
+    job_id: i32,
+    conn: &mut PgConnection,
+    error: &JobError,
+) -> Option<Duration> {
+    if !error.is_transient() {
+        return None;
+    }
+    let (attempts, cancel_requested) = jobs::table
+        .select((jobs::attempts, jobs::cancel_requested))
+        .filter(jobs::id.eq(job_id))
+        .get_result::<(i32, bool)>(conn)
+        .ok()?;
+    if cancel_requested || attempts >= config.job_max_attempts {
+        return None;
+    }
+    let factor = 1u64 << (attempts.max(1) - 1).min(16);
+    Some(Duration::from_secs(
+        config
+            .job_retry_delay_secs
+            .saturating_mul(factor)
+            .min(MAX_RETRY_DELAY_SECS),
+    ))
+}
+
 /// Executes a command and returns its output. A JobError is returned if the command couldn't be executed, but not if
 /// it exits with a status code.
 pub fn do_command_with_output(cmd: &mut Command) -> JobResult<Output> {
-    let output = unsafe {
+    let time_left = job_time_left()?;
+
+    unsafe {
         cmd.stdin(Stdio::null())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
@@ -140,14 +265,114 @@ pub fn do_command_with_output(cmd: &mut Command) -> JobResult<Output> {
                 // child and breaking the graceful shutdown
                 libc::setsid();
                 Ok(())
-            })
-            .output()
-            .map_err(|e| JobError::new(&format!("Failed to run {:?}: {}", &cmd, e)))?
-    };
+            });
+    }
+
+    let (output, usage) = output_with_usage(cmd, time_left.is_some())?;
+    add_command_usage(&usage);
 
     Ok(output)
 }
 
+thread_local! {
+    /* What the commands run for the job running on this thread used so far */
+    static COMMAND_USAGE: Cell<JobUsage> = const {
+        Cell::new(JobUsage {
+            wall_time_ms: 0,
+            cpu_time_ms: 0,
+            peak_rss_kb: 0,
+            bytes_written: 0,
+        })
+    };
+}
+
+/* Linux counts the blocks written by a process in units of 512 bytes, whatever the block size of the disk */
+const WRITTEN_BLOCK_SIZE: i64 = 512;
+
+fn add_command_usage(usage: &libc::rusage) {
+    let cpu_time_ms = (usage.ru_utime.tv_sec + usage.ru_stime.tv_sec) as i64 * 1000
+        + (usage.ru_utime.tv_usec + usage.ru_stime.tv_usec) as i64 / 1000;
+    COMMAND_USAGE.with(|current| {
+        let mut total = current.get();
+        total.cpu_time_ms += cpu_time_ms;
+        total.peak_rss_kb = total.peak_rss_kb.max(usage.ru_maxrss as i64);
+        total.bytes_written += usage.ru_oublock as i64 * WRITTEN_BLOCK_SIZE;
+        current.set(total);
+    });
+}
+
+/// Starts counting what the commands run on this thread use from scratch, for the next job.
+pub fn reset_command_usage() {
+    COMMAND_USAGE.with(|current| current.set(JobUsage::default()));
+}
+
+/// What the commands run on this thread used since reset_command_usage(). The wall time is left for the caller.
+pub fn command_usage() -> JobUsage {
+    COMMAND_USAGE.with(|current| current.get())
+}
+
+/* How often a command running for a job with a timeout is checked on */
+const COMMAND_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+fn read_pipe<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut contents = vec![];
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut contents);
+        }
+        contents
+    })
+}
+
+/* Like Command::output(), but also returns what the command used. With a deadline, the command is killed when the
+ * job runs out of time. */
+fn output_with_usage(cmd: &mut Command, has_deadline: bool) -> JobResult<(Output, libc::rusage)> {
+    let mut child = cmd
+        .spawn()
+        .map_err(|e| JobError::new(&format!("Failed to run {:?}: {}", &cmd, e)))?;
+
+    /* Read the pipes on other threads, so the command can't get stuck on a full one while we wait for it */
+    let stdout = read_pipe(child.stdout.take());
+    let stderr = read_pipe(child.stderr.take());
+
+    let pid = child.id() as libc::pid_t;
+    let (status, usage) = loop {
+        if let Err(e) = job_time_left() {
+            /* The command leads its own session, so this also kills anything it started */
+            unsafe {
+                libc::killpg(pid, libc::SIGKILL);
+            }
+            let _ = child.wait();
+            return Err(e);
+        }
+
+        /* Reaped with wait4() rather than through the Child, to get the resource usage of the command along with
+         * its status. Without a deadline there is nothing to check on, so it is waited for right away. */
+        let options = if has_deadline { libc::WNOHANG } else { 0 };
+        let mut status = 0;
+        let mut usage: libc::rusage = unsafe { mem::zeroed() };
+        match unsafe { libc::wait4(pid, &mut status, options, &mut usage) } {
+            0 => thread::sleep(COMMAND_POLL_INTERVAL),
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err.into());
+                }
+            }
+            _ => break (ExitStatus::from_raw(status), usage),
+        }
+    };
+
+    Ok((
+        Output {
+            status,
+            stdout: stdout.join().unwrap_or_default(),
+            stderr: stderr.join().unwrap_or_default(),
+        },
+        usage,
+    ))
+}
+
 /// Executes a command. A JobError is returned if the command exits with an unsuccessful status code.
 pub fn do_command(mut cmd: Command) -> JobResult<()> {
     let output = do_command_with_output(&mut cmd)?;
@@ -0,0 +1,291 @@
+use actix::prelude::*;
+use diesel::prelude::*;
+use log::{error, info, warn};
+use reqwest::blocking::{Client, RequestBuilder};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::deltas::DeltaGenerator;
+use crate::models::{Job, JobKind, JobOutcome, JobStatus, JobUsage};
+use crate::schema::jobs;
+use crate::Pool;
+
+use super::job_executor::{run_job, JobExecutor};
+use super::job_instance::new_job_instance;
+use super::workers::WORKER_HEARTBEAT_INTERVAL;
+
+/* How long a claim waits on the server for a job to show up */
+const CLAIM_WAIT_SECS: u64 = 30;
+
+/* How long to wait before trying again when the server can't be reached */
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// Where an external job worker finds the server, and which jobs it runs. The worker shares the database and the
+/// repo storage of the server, so it uses the same config otherwise.
+pub struct JobWorkerSettings {
+    pub url: String,
+    pub token: String,
+    pub name: String,
+    /// The kinds of jobs to run, named like in the API, e.g. "update-repo". All kinds if empty.
+    pub kinds: Vec<String>,
+}
+
+/* The kinds of jobs a worker runs, failing on kinds that don't exist */
+fn parse_kinds(kinds: &[String]) -> Result<Vec<JobKind>, String> {
+    if kinds.is_empty() {
+        return Ok((0..).map_while(JobKind::from_db).collect());
+    }
+    kinds
+        .iter()
+        .map(|kind| {
+            serde_json::from_value(json!(kind)).map_err(|_| format!("Unknown job kind {kind}"))
+        })
+        .collect()
+}
+
+/* Both registered workers and claimed jobs come back with more than their id, but that is all the worker needs */
+#[derive(Deserialize)]
+struct Id {
+    id: i32,
+}
+
+struct WorkerClient {
+    client: Client,
+    url: String,
+    token: String,
+}
+
+impl WorkerClient {
+    fn new(settings: &JobWorkerSettings) -> Self {
+        WorkerClient {
+            client: Client::builder()
+                .timeout(Duration::from_secs(CLAIM_WAIT_SECS) + RETRY_DELAY * 3)
+                .build()
+                .expect("Failed to create http client"),
+            url: settings.url.trim_end_matches('/').to_string(),
+            token: settings.token.clone(),
+        }
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> RequestBuilder {
+        self.client
+            .request(method, format!("{}/api/v1/{path}", self.url))
+            .bearer_auth(&self.token)
+    }
+
+    fn register(&self, name: &str, kinds: &[JobKind]) -> reqwest::Result<i32> {
+        let worker = self
+            .request(reqwest::Method::POST, "job_worker")
+            .json(&json!({
+                "name": name,
+                "kinds": kinds,
+            }))
+            .send()?
+            .error_for_status()?
+            .json::<Id>()?;
+        Ok(worker.id)
+    }
+
+    /* Claims the next job, or None if none showed up while the server waited */
+    fn claim(&self, worker_id: i32) -> reqwest::Result<Option<i32>> {
+        let response = self
+            .request(
+                reqwest::Method::POST,
+                &format!("job_worker/{worker_id}/claim?wait={CLAIM_WAIT_SECS}"),
+            )
+            .send()?
+            .error_for_status()?;
+        if response.status() == StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+        Ok(Some(response.json::<Id>()?.id))
+    }
+
+    fn heartbeat(&self, worker_id: i32) -> reqwest::Result<()> {
+        self.request(
+            reqwest::Method::POST,
+            &format!("job_worker/{worker_id}/heartbeat"),
+        )
+        .send()?
+        .error_for_status()?;
+        Ok(())
+    }
+
+    fn finish(&self, worker_id: i32, job_id: i32, outcome: &JobOutcome) -> reqwest::Result<()> {
+        self.request(
+            reqwest::Method::POST,
+            &format!("job_worker/{worker_id}/job/{job_id}/finish"),
+        )
+        .json(outcome)
+        .send()?
+        .error_for_status()?;
+        Ok(())
+    }
+
+    fn unregister(&self, worker_id: i32) -> reqwest::Result<()> {
+        self.request(reqwest::Method::DELETE, &format!("job_worker/{worker_id}"))
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/* Runs a claimed job, sending heartbeats while it runs, and reports how it ended */
+fn run_claimed_job(executor: &JobExecutor, client: &WorkerClient, worker_id: i32, job_id: i32) {
+    let job = executor
+        .pool
+        .get()
+        .map_err(|e| e.to_string())
+        .and_then(|mut conn| {
+            jobs::table
+                .filter(jobs::id.eq(job_id))
+                .get_result::<Job>(&mut conn)
+                .map(|job| (conn, job))
+                .map_err(|e| e.to_string())
+        });
+
+    let outcome = match job {
+        Ok((mut conn, job)) => {
+            info!("#{}: Claimed job", job_id);
+            let kind = job.kind;
+            let instance = new_job_instance(executor, job);
+            let (done_tx, done_rx) = mpsc::channel::<()>();
+            thread::scope(|scope| {
+                scope.spawn(move || {
+                    while let Err(RecvTimeoutError::Timeout) =
+                        done_rx.recv_timeout(WORKER_HEARTBEAT_INTERVAL)
+                    {
+                        if let Err(e) = client.heartbeat(worker_id) {
+                            warn!("#{}: Can't send heartbeat: {}", job_id, e);
+                        }
+                    }
+                });
+                let outcome = run_job(executor, &mut conn, instance, kind);
+                drop(done_tx);
+                outcome
+            })
+        }
+        Err(e) => {
+            error!("#{}: Can't load claimed job: {}", job_id, e);
+            JobOutcome {
+                status: JobStatus::Broken as i16,
+                results: json!({ "error-message": format!("Can't load job: {e}") }).to_string(),
+                retry_after: None,
+                usage: JobUsage::default(),
+            }
+        }
+    };
+
+    /* The outcome is kept until the server has it, unless it took the job back in the meantime */
+    loop {
+        match client.finish(worker_id, job_id, &outcome) {
+            Ok(()) => break,
+            Err(e) if e.status().map_or(false, |status| status.is_client_error()) => {
+                error!("#{}: Server refused the outcome of the job: {}", job_id, e);
+                break;
+            }
+            Err(e) => {
+                error!("#{}: Can't report the outcome of the job: {}", job_id, e);
+                thread::sleep(RETRY_DELAY);
+            }
+        }
+    }
+}
+
+/* Claims and runs jobs until asked to stop, registering again whenever the server forgot about the worker */
+fn run_job_worker(
+    executor: JobExecutor,
+    settings: JobWorkerSettings,
+    kinds: Vec<JobKind>,
+    stopping: &AtomicBool,
+) {
+    let client = WorkerClient::new(&settings);
+    let mut worker_id = None;
+
+    while !stopping.load(Ordering::SeqCst) {
+        let id = match worker_id {
+            Some(id) => id,
+            None => match client.register(&settings.name, &kinds) {
+                Ok(id) => {
+                    info!("Registered as job worker {} for {:?}", id, kinds);
+                    worker_id = Some(id);
+                    id
+                }
+                Err(e) => {
+                    error!("Can't register job worker: {}", e);
+                    thread::sleep(RETRY_DELAY);
+                    continue;
+                }
+            },
+        };
+
+        match client.claim(id) {
+            Ok(Some(job_id)) => run_claimed_job(&executor, &client, id, job_id),
+            Ok(None) => (),
+            Err(e) if e.status() == Some(StatusCode::NOT_FOUND) => {
+                warn!("Job worker {} was removed by the server", id);
+                worker_id = None;
+            }
+            Err(e) => {
+                error!("Can't claim job: {}", e);
+                thread::sleep(RETRY_DELAY);
+            }
+        }
+    }
+
+    if let Some(id) = worker_id {
+        if let Err(e) = client.unregister(id) {
+            error!("Can't unregister job worker {}: {}", id, e);
+        }
+    }
+}
+
+/// Starts a thread that runs jobs for the server as an external worker. It stops once `stopping` is set and the
+/// job it is running, if any, is done, and then stops the system.
+pub fn start_job_worker(
+    config: Arc<Config>,
+    delta_generator: Addr<DeltaGenerator>,
+    pool: Pool,
+    settings: JobWorkerSettings,
+    stopping: Arc<AtomicBool>,
+) -> Result<(), String> {
+    let kinds = parse_kinds(&settings.kinds)?;
+    let executor = JobExecutor {
+        repo: None,
+        config,
+        delta_generator,
+        pool,
+    };
+    let system = System::current();
+    thread::spawn(move || {
+        run_job_worker(executor, settings, kinds, &stopping);
+        info!("Job worker stopped");
+        system.stop();
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_kinds() {
+        assert_eq!(
+            parse_kinds(&["update-repo".to_string(), "prune-deltas".to_string()]),
+            Ok(vec![JobKind::UpdateRepo, JobKind::PruneDeltas])
+        );
+        assert_eq!(parse_kinds(&[]).unwrap().len(), 8);
+        assert_eq!(
+            parse_kinds(&["deltas".to_string()]),
+            Err("Unknown job kind deltas".to_string())
+        );
+    }
+}
@@ -0,0 +1,247 @@
+use chrono::Utc;
+use diesel::dsl::{exists, now};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::result::DatabaseErrorKind::SerializationFailure;
+use diesel::result::Error as DieselError;
+use log::{info, warn};
+use serde_json::json;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::db::fail_abandoned_job;
+use crate::errors::ApiError;
+use crate::models::{Job, JobKind, JobOutcome, JobStatus, JobWorker, NewJobWorker};
+use crate::schema::{checks, job_workers, jobs};
+
+use super::check_job::update_build_status_after_check;
+use super::job_executor::{next_job, ready_jobs, store_job_outcome};
+use super::utils::job_log;
+
+/* How often a worker that is running a job sends a heartbeat. Asking for a job counts as one too. */
+pub const WORKER_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/* A worker that wasn't heard from for this long is considered gone, and the jobs it was running are reclaimed */
+pub const WORKER_TIMEOUT: Duration = Duration::from_secs(90);
+
+fn live_workers_cutoff() -> chrono::NaiveDateTime {
+    Utc::now().naive_utc() - chrono::Duration::from_std(WORKER_TIMEOUT).unwrap()
+}
+
+/* The kinds of jobs that live workers run, which the executors of the server leave to them */
+pub fn live_worker_kinds(conn: &mut PgConnection) -> Result<Vec<i16>, DieselError> {
+    let mut kinds = job_workers::table
+        .select(job_workers::kinds)
+        .filter(job_workers::last_seen.gt(live_workers_cutoff()))
+        .get_results::<Vec<i16>>(conn)?
+        .concat();
+    kinds.sort_unstable();
+    kinds.dedup();
+    Ok(kinds)
+}
+
+/* Whether a worker is running a job of a repo, which keeps the executor of the repo from starting another one */
+pub fn repo_busy_on_worker(conn: &mut PgConnection, repo: &str) -> Result<bool, DieselError> {
+    diesel::select(exists(
+        jobs::table
+            .filter(jobs::repo.eq(repo))
+            .filter(jobs::status.eq(JobStatus::Started as i16))
+            .filter(jobs::worker_id.is_not_null()),
+    ))
+    .get_result(conn)
+}
+
+pub fn register_worker(
+    conn: &mut PgConnection,
+    name: &str,
+    kinds: &[JobKind],
+) -> Result<JobWorker, DieselError> {
+    let worker = diesel::insert_into(job_workers::table)
+        .values(NewJobWorker {
+            name: name.to_string(),
+            kinds: kinds.iter().map(JobKind::to_db).collect(),
+        })
+        .get_result::<JobWorker>(conn)?;
+    info!(
+        "Registered job worker {} ({}) for {:?}",
+        worker.id, worker.name, kinds
+    );
+    Ok(worker)
+}
+
+/* Notes that a worker is still alive. Fails with NotFound for workers that were removed, e.g. after missing their
+ * heartbeats, which have to register again. */
+pub fn worker_heartbeat(conn: &mut PgConnection, worker_id: i32) -> Result<JobWorker, DieselError> {
+    diesel::update(job_workers::table.find(worker_id))
+        .set(job_workers::last_seen.eq(now))
+        .get_result::<JobWorker>(conn)
+}
+
+/* Claims the next job a worker runs, marking it started on that worker. As on the server, the jobs of a repo run one
+ * at a time, so jobs of repos that have a job running anywhere are left for later. */
+pub fn claim_job_for_worker(
+    config: &Config,
+    conn: &mut PgConnection,
+    worker_id: i32,
+) -> Result<Option<Job>, DieselError> {
+    let transaction_result = conn
+        .build_transaction()
+        .serializable()
+        .deferrable()
+        .run(|conn| {
+            let worker = worker_heartbeat(conn, worker_id)?;
+            let busy_repos = jobs::table
+                .select(jobs::repo)
+                .filter(jobs::status.eq(JobStatus::Started as i16))
+                .filter(jobs::repo.is_not_null())
+                .get_results::<Option<String>>(conn)?
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>();
+            let ready_jobs = ready_jobs()
+                .filter(jobs::kind.eq_any(worker.kinds))
+                .filter(jobs::repo.is_null().or(jobs::repo.ne_all(busy_repos)))
+                .get_results::<Job>(conn)?;
+
+            match next_job(config, ready_jobs) {
+                Some(job) => diesel::update(jobs::table)
+                    .filter(jobs::id.eq(job.id))
+                    .set((
+                        jobs::status.eq(JobStatus::Started as i16),
+                        jobs::attempts.eq(jobs::attempts + 1),
+                        jobs::progress.eq(None::<String>),
+                        jobs::worker_id.eq(worker.id),
+                    ))
+                    .get_result::<Job>(conn)
+                    .map(Some),
+                None => Ok(None),
+            }
+        });
+
+    /* Retry on serialization failure */
+    match transaction_result {
+        Err(DieselError::DatabaseError(SerializationFailure, _)) => {
+            claim_job_for_worker(config, conn, worker_id)
+        }
+        _ => transaction_result,
+    }
+}
+
+/* Stores how a job that a worker ran ended. Returns the job, or an error if it isn't running on the worker anymore,
+ * e.g. because it was reclaimed after the worker missed its heartbeats. */
+pub fn finish_worker_job(
+    conn: &mut PgConnection,
+    worker_id: i32,
+    job_id: i32,
+    outcome: &JobOutcome,
+) -> Result<Job, ApiError> {
+    match JobStatus::from_db(outcome.status) {
+        None | Some(JobStatus::Started) => {
+            return Err(ApiError::BadRequest(format!(
+                "Invalid job status {}",
+                outcome.status
+            )))
+        }
+        _ => (),
+    }
+
+    conn.transaction::<_, ApiError, _>(|conn| {
+        worker_heartbeat(conn, worker_id)?;
+        let job = jobs::table
+            .filter(jobs::id.eq(job_id))
+            .for_update()
+            .get_result::<Job>(conn)?;
+        if job.worker_id != Some(worker_id) || job.status != JobStatus::Started as i16 {
+            return Err(ApiError::BadRequest(format!(
+                "Job {job_id} isn't running on worker {worker_id}"
+            )));
+        }
+        store_job_outcome(conn, job_id, outcome)?;
+        Ok(jobs::table.find(job_id).get_result::<Job>(conn)?)
+    })
+}
+
+/* Removes a worker. The jobs it was running are queued again if they have attempts left, otherwise they break like
+ * jobs that were running when the server restarted. Returns the repos of the jobs. */
+pub fn remove_worker(
+    config: &Config,
+    conn: &mut PgConnection,
+    worker_id: i32,
+) -> Result<Vec<Option<String>>, ApiError> {
+    let (abandoned, failed_checks) = conn.transaction::<_, ApiError, _>(|conn| {
+        let abandoned = jobs::table
+            .filter(jobs::worker_id.eq(worker_id))
+            .filter(jobs::status.eq(JobStatus::Started as i16))
+            .for_update()
+            .get_results::<Job>(conn)?;
+        let mut failed_checks = vec![];
+
+        for job in &abandoned {
+            if job.attempts < config.job_max_attempts && !job.cancel_requested {
+                job_log(
+                    job.id,
+                    conn,
+                    "error",
+                    "Worker went away, queueing job again",
+                );
+                diesel::update(jobs::table)
+                    .filter(jobs::id.eq(job.id))
+                    .set((
+                        jobs::status.eq(JobStatus::New as i16),
+                        jobs::progress.eq(None::<String>),
+                    ))
+                    .execute(conn)?;
+            } else {
+                let reason = "Worker went away during job";
+                job_log(job.id, conn, "error", reason);
+                diesel::update(jobs::table)
+                    .filter(jobs::id.eq(job.id))
+                    .set((
+                        jobs::status.eq(JobStatus::Broken as i16),
+                        jobs::results.eq(json!({ "error-message": reason }).to_string()),
+                        jobs::finished_at.eq(Utc::now().naive_utc()),
+                    ))
+                    .execute(conn)?;
+                fail_abandoned_job(conn, job, reason)?;
+                if job.kind == JobKind::Check.to_db() {
+                    failed_checks.push(job.id);
+                }
+            }
+        }
+
+        diesel::delete(job_workers::table.find(worker_id)).execute(conn)?;
+        Ok((abandoned, failed_checks))
+    })?;
+
+    /* A failed check may fail its build */
+    for job_id in failed_checks {
+        let build_id = checks::table
+            .select(checks::build_id)
+            .filter(checks::job_id.eq(job_id))
+            .get_result::<i32>(conn)?;
+        update_build_status_after_check(build_id, conn)
+            .map_err(|e| ApiError::InternalServerError(e.to_string()))?;
+    }
+
+    Ok(abandoned.into_iter().map(|job| job.repo).collect())
+}
+
+/* Removes the workers that stopped sending heartbeats, reclaiming their jobs. Returns the repos of the jobs. */
+pub fn reclaim_abandoned_jobs(
+    config: &Config,
+    conn: &mut PgConnection,
+) -> Result<Vec<Option<String>>, ApiError> {
+    let lost_workers = job_workers::table
+        .filter(job_workers::last_seen.le(live_workers_cutoff()))
+        .get_results::<JobWorker>(conn)?;
+
+    let mut repos = vec![];
+    for worker in lost_workers {
+        warn!(
+            "Job worker {} ({}) wasn't seen since {}, removing it",
+            worker.id, worker.name, worker.last_seen
+        );
+        repos.extend(remove_worker(config, conn, worker.id)?);
+    }
+    Ok(repos)
+}
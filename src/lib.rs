@@ -2,6 +2,8 @@ use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 
 mod api;
 mod app;
+mod appstream;
+mod audit;
 mod config;
 mod db;
 mod delayed;
@@ -9,9 +11,15 @@ mod deltas;
 pub mod errors;
 mod jobs;
 mod logger;
+mod metrics;
 mod models;
+mod net;
+mod oidc;
 pub mod ostree;
+mod ratelimit;
 mod schema;
+#[cfg(any(test, feature = "test-helpers"))]
+pub mod testing;
 mod tokens;
 
 use actix::prelude::*;
@@ -25,12 +33,15 @@ use futures3::FutureExt;
 use jobs::{JobQueue, StopJobQueue};
 use log::info;
 use std::path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio_signal::unix::Signal;
 
 pub use deltas::{RemoteClientMessage, RemoteServerMessage};
 pub use errors::DeltaGenerationError;
+pub use jobs::JobWorkerSettings;
+pub use tokens::{bearer_header, curl_example};
 
 type Pool = diesel::r2d2::Pool<ConnectionManager<PgConnection>>;
 
@@ -147,3 +158,40 @@ pub fn start(config: &Arc<Config>) -> Server {
 
     app
 }
+
+fn handle_job_worker_signals(stopping: Arc<AtomicBool>) {
+    let sigint = Signal::new(tokio_signal::unix::SIGINT).flatten_stream();
+    let sigterm = Signal::new(tokio_signal::unix::SIGTERM).flatten_stream();
+    let sigquit = Signal::new(tokio_signal::unix::SIGQUIT).flatten_stream();
+    let handle_signals = sigint
+        .select(sigterm)
+        .select(sigquit)
+        .for_each(move |_sig| {
+            info!("Signal received, stopping after the current job");
+            stopping.store(true, Ordering::SeqCst);
+            Ok(())
+        })
+        .map_err(|_| ());
+
+    actix::spawn(handle_signals);
+}
+
+/// Runs jobs for the server at `settings.url` instead of serving the API. The worker uses the same database and
+/// repo storage as the server, from the config.
+pub fn start_job_worker(config: &Arc<Config>, settings: JobWorkerSettings) {
+    let pool = connect_to_db(config);
+
+    let delta_generator = start_delta_generator(config);
+
+    let stopping = Arc::new(AtomicBool::new(false));
+    jobs::start_job_worker(
+        config.clone(),
+        delta_generator,
+        pool,
+        settings,
+        stopping.clone(),
+    )
+    .unwrap_or_else(|e| panic!("Failed to start job worker: {e}"));
+
+    handle_job_worker_signals(stopping);
+}
@@ -9,6 +9,7 @@ use futures::{Async, Future, Poll};
 use std::marker::PhantomData;
 use std::rc::Rc;
 
+use crate::net::{request_client_ip, IpNet};
 use crate::tokens::ClaimsValidator;
 
 pub struct Logger(Rc<Inner>);
@@ -26,7 +27,9 @@ struct ResponseData {
     size: usize,
 }
 
-pub struct Inner {}
+pub struct Inner {
+    trusted_proxies: Vec<IpNet>,
+}
 
 impl Inner {
     fn log(&self, req: &RequestData, resp: &ResponseData) {
@@ -46,8 +49,8 @@ impl Inner {
 }
 
 impl Logger {
-    pub fn default() -> Logger {
-        Logger(Rc::new(Inner {}))
+    pub fn new(trusted_proxies: Vec<IpNet>) -> Logger {
+        Logger(Rc::new(Inner { trusted_proxies }))
     }
 }
 
@@ -94,7 +97,10 @@ where
     fn call(&mut self, req: ServiceRequest) -> Self::Future {
         let now = time::now();
 
-        let remote_ip = req.connection_info().remote().unwrap_or("-").to_string();
+        let remote_ip =
+            request_client_ip(req.peer_addr(), req.headers(), &self.inner.trusted_proxies)
+                .map(|ip| ip.to_string())
+                .unwrap_or_else(|| "-".to_string());
 
         let request_line = if req.query_string().is_empty() {
             format!("{} {} {:?}", req.method(), req.path(), req.version())
@@ -0,0 +1,136 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::tokens::Claims;
+
+/// A histogram with fixed bucket bounds, rendered in the Prometheus text format. Values above the last bound only
+/// count towards the implicit "+Inf" bucket, so memory use doesn't depend on what is observed.
+pub struct Histogram {
+    bounds: &'static [u64],
+    counts: Vec<AtomicU64>,
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [u64]) -> Histogram {
+        Histogram {
+            bounds,
+            counts: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: u64) {
+        if let Some(bucket) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The number of observations at or below the given bound, like the `le` buckets in the rendered output.
+    pub fn cumulative_count(&self, le: u64) -> u64 {
+        self.bounds
+            .iter()
+            .zip(&self.counts)
+            .take_while(|(bound, _)| **bound <= le)
+            .map(|(_, count)| count.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    pub fn render(&self, name: &str, help: &str, out: &mut String) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulative}");
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum {}", self.sum.load(Ordering::Relaxed));
+        let _ = writeln!(out, "{name}_count {count}");
+    }
+}
+
+const HOUR: u64 = 60 * 60;
+const DAY: u64 = 24 * HOUR;
+
+/* From an hour up to a year, which covers both short-lived subset tokens and long-lived admin ones */
+const TOKEN_LIFETIME_BUCKETS: &[u64] =
+    &[HOUR, 6 * HOUR, DAY, 7 * DAY, 30 * DAY, 90 * DAY, 365 * DAY];
+
+/// How old validated tokens are and how long they have left, to help pick sensible token lifetimes.
+pub struct TokenMetrics {
+    pub token_age_seconds: Histogram,
+    pub token_remaining_seconds: Histogram,
+}
+
+impl Default for TokenMetrics {
+    fn default() -> TokenMetrics {
+        TokenMetrics {
+            token_age_seconds: Histogram::new(TOKEN_LIFETIME_BUCKETS),
+            token_remaining_seconds: Histogram::new(TOKEN_LIFETIME_BUCKETS),
+        }
+    }
+}
+
+impl TokenMetrics {
+    /// Records a token that was just validated. Tokens minted before the iat claim existed only count towards the
+    /// remaining lifetime.
+    pub fn record(&self, claims: &Claims, now: i64) {
+        if let Some(iat) = claims.iat {
+            self.token_age_seconds
+                .observe(now.saturating_sub(iat).max(0) as u64);
+        }
+        self.token_remaining_seconds
+            .observe(claims.exp.saturating_sub(now).max(0) as u64);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.token_age_seconds.render(
+            "flat_manager_token_age_seconds",
+            "Time since a validated token was issued",
+            &mut out,
+        );
+        self.token_remaining_seconds.render(
+            "flat_manager_token_remaining_seconds",
+            "Time until a validated token expires",
+            &mut out,
+        );
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram::new(&[10, 100]);
+        histogram.observe(5);
+        histogram.observe(10);
+        histogram.observe(50);
+        histogram.observe(1000);
+
+        assert_eq!(histogram.cumulative_count(10), 2);
+        assert_eq!(histogram.cumulative_count(100), 3);
+
+        let mut out = String::new();
+        histogram.render("test", "A test histogram", &mut out);
+        assert_eq!(
+            out,
+            "# HELP test A test histogram\n\
+             # TYPE test histogram\n\
+             test_bucket{le=\"10\"} 2\n\
+             test_bucket{le=\"100\"} 3\n\
+             test_bucket{le=\"+Inf\"} 4\n\
+             test_sum 1065\n\
+             test_count 4\n"
+        );
+    }
+}
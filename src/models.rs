@@ -1,7 +1,11 @@
 /* see https://github.com/rust-lang/rust-clippy/issues/9014 */
 #![allow(clippy::extra_unused_lifetimes)]
 
-use crate::schema::{build_refs, builds, checks, job_dependencies, jobs, tokens};
+use crate::schema::{
+    audit_log, build_approvals, build_comments, build_refs, build_tags, builds, checks,
+    job_dependencies, job_log_records, job_schedules, job_workers, jobs, prefix_owners,
+    revoked_prefixes, revoked_subs, token_usage, tokens,
+};
 use diesel::{Associations, Identifiable, Insertable, Queryable};
 use serde::{Deserialize, Serialize};
 use std::{mem, time};
@@ -16,9 +20,11 @@ pub struct NewBuild {
     pub token_name: Option<String>,
     pub token_type: Option<String>,
     pub token_branches: Option<Vec<String>>,
+    pub token_id: Option<String>,
+    pub token_sub: Option<String>,
 }
 
-#[derive(Identifiable, Serialize, Queryable, Debug, Eq, PartialEq)]
+#[derive(Identifiable, Serialize, Queryable, Clone, Debug, Eq, PartialEq)]
 pub struct Build {
     pub id: i32,
     pub created: chrono::NaiveDateTime,
@@ -40,6 +46,20 @@ pub struct Build {
     pub token_name: Option<String>,
     pub token_type: Option<String>,
     pub token_branches: Option<Vec<String>>,
+    pub pinned: bool,
+    /// How many bytes were uploaded to the build, counted against storage quotas.
+    pub uploaded_bytes: i64,
+    /// How many approvals the build needs before it can be published, set when it is committed.
+    pub required_approvals: i32,
+    /// The job pulling the build's refs from another repository, for builds that are imported.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub import_job_id: Option<i32>,
+    /// The ID of the token that created the build, which may keep working on it for a while after being revoked.
+    #[serde(skip_serializing)]
+    pub token_id: Option<String>,
+    /// The sub of the token that created the build, which storage quotas can be keyed on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_sub: Option<String>,
 }
 
 #[derive(Deserialize, Debug, Eq, PartialEq)]
@@ -55,6 +75,16 @@ impl PublishedState {
         mem::discriminant(self) == mem::discriminant(other)
     }
 
+    /// The name of the state, as used in the API and in errors.
+    pub fn name(&self) -> &'static str {
+        match self {
+            PublishedState::Unpublished => "unpublished",
+            PublishedState::Publishing => "publishing",
+            PublishedState::Published => "published",
+            PublishedState::Failed(_) => "failed",
+        }
+    }
+
     pub fn to_db(&self) -> (i16, Option<String>) {
         match self {
             PublishedState::Unpublished => (0, None),
@@ -90,6 +120,8 @@ pub enum RepoState {
     Validating,
     /// The commit job is done, any checks jobs have passed, and the build can be published.
     Ready,
+    /// The checks have passed, but the build needs more approvals before it can be published.
+    AwaitingApproval,
     /// One of the build's jobs failed.
     Failed(String),
     /// The build repo is currently being deleted.
@@ -103,6 +135,20 @@ impl RepoState {
         mem::discriminant(self) == mem::discriminant(other)
     }
 
+    /// The name of the state, as used in the API and in errors.
+    pub fn name(&self) -> &'static str {
+        match self {
+            RepoState::Uploading => "uploading",
+            RepoState::Committing => "committing",
+            RepoState::Validating => "validating",
+            RepoState::Ready => "ready",
+            RepoState::AwaitingApproval => "awaiting-approval",
+            RepoState::Failed(_) => "failed",
+            RepoState::Purging => "purging",
+            RepoState::Purged => "purged",
+        }
+    }
+
     pub fn to_db(&self) -> (i16, Option<String>) {
         match self {
             RepoState::Uploading => (0, None),
@@ -112,6 +158,7 @@ impl RepoState {
             RepoState::Purging => (4, None),
             RepoState::Purged => (5, None),
             RepoState::Validating => (6, None),
+            RepoState::AwaitingApproval => (7, None),
         }
     }
 
@@ -129,11 +176,88 @@ impl RepoState {
             4 => RepoState::Purging,
             5 => RepoState::Purged,
             6 => RepoState::Validating,
+            7 => RepoState::AwaitingApproval,
             _ => RepoState::Failed("Unknown state".to_string()),
         }
     }
 }
 
+/// The actions that move a build from one state to another, see `POST /build/{id}/transition`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BuildAction {
+    Commit,
+    Approve,
+    Publish,
+    Purge,
+}
+
+impl BuildAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BuildAction::Commit => "commit",
+            BuildAction::Approve => "approve",
+            BuildAction::Publish => "publish",
+            BuildAction::Purge => "purge",
+        }
+    }
+}
+
+/* Mirrors the checks of start_commit_job, approve_build, start_publish_job and init_build_purge. Builds that were
+ * partially published can publish their held refs again. */
+pub fn allowed_build_actions(
+    repo_state: &RepoState,
+    published_state: &PublishedState,
+    has_held_refs: bool,
+) -> Vec<BuildAction> {
+    let mut actions = vec![];
+    match repo_state {
+        RepoState::Uploading => actions.push(BuildAction::Commit),
+        RepoState::AwaitingApproval => actions.push(BuildAction::Approve),
+        RepoState::Ready => match published_state {
+            PublishedState::Unpublished => actions.push(BuildAction::Publish),
+            PublishedState::Published if has_held_refs => actions.push(BuildAction::Publish),
+            _ => (),
+        },
+        _ => (),
+    }
+
+    let in_use = matches!(
+        repo_state,
+        RepoState::Committing | RepoState::Validating | RepoState::Purging | RepoState::Purged
+    ) || matches!(published_state, PublishedState::Publishing);
+    if !in_use {
+        actions.push(BuildAction::Purge);
+    }
+    actions
+}
+
+/// The states of a build, and the actions that are currently allowed on it.
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct BuildState {
+    pub repo_state: &'static str,
+    pub published_state: &'static str,
+    pub allowed_actions: Vec<BuildAction>,
+}
+
+impl BuildState {
+    pub fn new(build: &Build, build_refs: &[BuildRef]) -> Self {
+        let repo_state = RepoState::from_db(build.repo_state, &build.repo_state_reason);
+        let published_state =
+            PublishedState::from_db(build.published_state, &build.published_state_reason);
+        let has_held_refs = build_refs.iter().any(|build_ref| build_ref.held);
+        BuildState {
+            repo_state: repo_state.name(),
+            published_state: published_state.name(),
+            allowed_actions: allowed_build_actions(&repo_state, &published_state, has_held_refs),
+        }
+    }
+
+    pub fn allows(&self, action: BuildAction) -> bool {
+        self.allowed_actions.contains(&action)
+    }
+}
+
 #[derive(Deserialize, Insertable, Debug)]
 #[diesel(table_name =  build_refs)]
 pub struct NewBuildRef {
@@ -151,6 +275,8 @@ pub struct BuildRef {
     pub ref_name: String,
     pub commit: String,
     pub build_log_url: Option<String>,
+    /// Whether the ref was left out when publishing part of the build, and can still be published
+    pub held: bool,
 }
 
 diesel::table! {
@@ -173,6 +299,10 @@ pub enum JobStatus {
     Ended,
     /// The job encountered an error, or flat-manager was shut down before it could finish.
     Broken,
+    /// The job was cancelled, either while it was queued or at a checkpoint while it was running.
+    Cancelled,
+    /// The job ran longer than the timeout configured for its kind, and was stopped.
+    TimedOut,
 }
 
 impl JobStatus {
@@ -182,18 +312,30 @@ impl JobStatus {
             1 => Some(JobStatus::Started),
             2 => Some(JobStatus::Ended),
             3 => Some(JobStatus::Broken),
+            4 => Some(JobStatus::Cancelled),
+            5 => Some(JobStatus::TimedOut),
             _ => None,
         }
     }
+
+    /// Whether the job failed for good, and stays that way until an operator requeues it. These are the jobs in the
+    /// dead-letter queue.
+    pub fn is_dead(&self) -> bool {
+        matches!(self, JobStatus::Broken | JobStatus::TimedOut)
+    }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[serde(rename_all = "kebab-case")]
 pub enum JobKind {
     Commit,
     Publish,
     UpdateRepo,
     Republish,
     Check,
+    PruneDeltas,
+    PurgeBuilds,
+    Import,
 }
 
 impl JobKind {
@@ -204,6 +346,9 @@ impl JobKind {
             JobKind::UpdateRepo => 2,
             JobKind::Republish => 3,
             JobKind::Check => 4,
+            JobKind::PruneDeltas => 5,
+            JobKind::PurgeBuilds => 6,
+            JobKind::Import => 7,
         }
     }
 
@@ -214,9 +359,45 @@ impl JobKind {
             2 => Some(JobKind::UpdateRepo),
             3 => Some(JobKind::Republish),
             4 => Some(JobKind::Check),
+            5 => Some(JobKind::PruneDeltas),
+            6 => Some(JobKind::PurgeBuilds),
+            7 => Some(JobKind::Import),
             _ => None,
         }
     }
+
+    /// Jobs with a higher priority are run first, then the oldest ones. Publishing is the most urgent, as it can be
+    /// a security fix, while generating and pruning deltas can wait. Repos can override these with `job-priorities`.
+    pub fn default_priority(&self) -> i32 {
+        match self {
+            JobKind::Publish | JobKind::Republish => 30,
+            JobKind::Commit | JobKind::UpdateRepo | JobKind::Check | JobKind::Import => 20,
+            JobKind::PruneDeltas => 10,
+            JobKind::PurgeBuilds => 0,
+        }
+    }
+
+    /// The kinds of unfinished jobs of the same repo that a new job of this kind waits for, whatever their
+    /// priorities. A republish takes the current commits from the repo, so it waits for pending updates to it, and
+    /// pruning deltas waits for updates that may still be generating them. Update jobs are chained to the previous
+    /// one when queued instead, see queue_update_job.
+    pub fn dependencies(&self) -> &'static [JobKind] {
+        match self {
+            JobKind::Republish | JobKind::PruneDeltas => &[JobKind::UpdateRepo],
+            _ => &[],
+        }
+    }
+
+    /// Among ready jobs of the same priority, those of a lower order are run first. Publishing waits for commits
+    /// (and other normal ops), because the commits may generate more publishes, and updates wait for publishing so
+    /// they can be chunked.
+    pub fn order(&self) -> i32 {
+        match self {
+            JobKind::Publish | JobKind::Republish | JobKind::Check => 1,
+            JobKind::UpdateRepo => 2,
+            _ => 0,
+        }
+    }
 }
 
 #[derive(Deserialize, Insertable, Debug)]
@@ -226,6 +407,7 @@ pub struct NewJob {
     pub contents: String,
     pub start_after: Option<time::SystemTime>,
     pub repo: Option<String>,
+    pub priority: i32,
 }
 
 #[derive(Identifiable, Serialize, Queryable, Debug, Eq, PartialEq)]
@@ -239,6 +421,26 @@ pub struct Job {
     pub log: String,
     pub start_after: Option<time::SystemTime>,
     pub repo: Option<String>,
+    pub priority: i32,
+    pub cancel_requested: bool,
+    pub attempts: i32,
+    /* A JobProgress, while the job reports it */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress: Option<String>,
+    /* When the last attempt at the job finished, and what it used, see JobUsage */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<chrono::NaiveDateTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wall_time_ms: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_time_ms: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peak_rss_kb: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_written: Option<i64>,
+    /* The external worker the job was last claimed by, see JobWorker */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub worker_id: Option<i32>,
 }
 
 impl Job {
@@ -251,6 +453,145 @@ impl Job {
         }
         self
     }
+
+    /// Drops everything but the job's status and metadata, since the contents, results and log can include paths
+    /// or other details not meant for every viewer.
+    pub fn metadata_only(mut self) -> Self {
+        self.contents = String::new();
+        self.results = None;
+        self.log = String::new();
+        self
+    }
+}
+
+/// How far the current phase of a running job got, e.g. 3 of 10 deltas generated.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct JobProgress {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phase: Option<String>,
+    pub done: u64,
+    pub total: u64,
+    pub percent: u32,
+}
+
+impl JobProgress {
+    pub fn new(phase: Option<&str>, done: u64, total: u64) -> Self {
+        let percent = if total == 0 {
+            100
+        } else {
+            (done.min(total) * 100 / total) as u32
+        };
+        JobProgress {
+            phase: phase.map(str::to_string),
+            done,
+            total,
+            percent,
+        }
+    }
+}
+
+/// What a job used while it ran. Besides the time the job took, this counts the commands it ran: their CPU time, the
+/// peak memory of the biggest one, and what they wrote to disk.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct JobUsage {
+    pub wall_time_ms: i64,
+    pub cpu_time_ms: i64,
+    pub peak_rss_kb: i64,
+    pub bytes_written: i64,
+}
+
+/// The usage of the jobs of a kind, added up.
+#[derive(Serialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct JobUsageStats {
+    pub jobs: i64,
+    pub total_wall_time_ms: i64,
+    pub max_wall_time_ms: i64,
+    pub total_cpu_time_ms: i64,
+    pub max_cpu_time_ms: i64,
+    pub max_peak_rss_kb: i64,
+    pub total_bytes_written: i64,
+}
+
+impl JobUsageStats {
+    pub fn add(&mut self, usage: &JobUsage) {
+        self.jobs += 1;
+        self.total_wall_time_ms += usage.wall_time_ms;
+        self.max_wall_time_ms = self.max_wall_time_ms.max(usage.wall_time_ms);
+        self.total_cpu_time_ms += usage.cpu_time_ms;
+        self.max_cpu_time_ms = self.max_cpu_time_ms.max(usage.cpu_time_ms);
+        self.max_peak_rss_kb = self.max_peak_rss_kb.max(usage.peak_rss_kb);
+        self.total_bytes_written += usage.bytes_written;
+    }
+}
+
+/// One line of a job's log. Ids only ever grow, so the id of the last record seen works as a cursor for getting the
+/// ones after it.
+#[derive(Queryable, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct JobLogRecord {
+    pub id: i32,
+    #[serde(skip)]
+    pub job_id: i32,
+    pub created_at: chrono::NaiveDateTime,
+    pub level: String,
+    /// The step of the job the record was logged in, e.g. "import"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phase: Option<String>,
+    pub message: String,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = job_log_records)]
+pub struct NewJobLogRecord<'a> {
+    pub job_id: i32,
+    pub level: &'a str,
+    pub phase: Option<&'a str>,
+    pub message: &'a str,
+}
+
+/// How an attempt at a job ended, as stored in the job. External workers report this when they finish a job.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct JobOutcome {
+    pub status: i16,
+    pub results: String,
+    /* Set when a failed attempt is retried, see JobStatus::New */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<time::SystemTime>,
+    pub usage: JobUsage,
+}
+
+/// A process that claims and runs jobs of some kinds on behalf of the server, sharing its database and repo
+/// storage. Workers that stop sending heartbeats are removed, and the jobs they were running are queued again.
+#[derive(Queryable, Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct JobWorker {
+    pub id: i32,
+    pub name: String,
+    pub kinds: Vec<i16>,
+    pub registered_at: chrono::NaiveDateTime,
+    pub last_seen: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = job_workers)]
+pub struct NewJobWorker {
+    pub name: String,
+    pub kinds: Vec<i16>,
+}
+
+/// When a schedule of a repo queues its next job, see RepoConfig::schedules.
+#[derive(Insertable, Queryable, Debug)]
+#[diesel(table_name = job_schedules)]
+pub struct JobSchedule {
+    pub repo: String,
+    pub name: String,
+    pub next_run: chrono::NaiveDateTime,
+    /* The job queued by the last run, which is skipped while that job is still unfinished */
+    pub last_job_id: Option<i32>,
 }
 
 #[derive(Insertable, Debug, Queryable, Identifiable, Associations)]
@@ -342,9 +683,22 @@ pub struct CommitJob {
     pub token_type: Option<i32>,
 }
 
+/// Pulls refs from a remote ostree repository into the upload repo of a build.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImportJob {
+    pub build: i32,
+    pub url: String,
+    pub refs: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collection_id: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PublishJob {
     pub build: i32,
+    /// The refs to publish, if not all of them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refs: Option<Vec<String>>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -354,6 +708,13 @@ pub struct RepublishJob {
     pub endoflife_rebase: Option<String>,
 }
 
+/* Deltas matching either condition are pruned */
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct PruneDeltasJob {
+    pub older_than_days: Option<u32>,
+    pub keep_commits: Option<u32>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct UpdateRepoJob {
     pub repo: String,
@@ -372,6 +733,15 @@ pub struct Token {
     pub expires: Option<chrono::NaiveDateTime>,
     pub last_used: Option<chrono::NaiveDateTime>,
     pub revoked_at: Option<chrono::NaiveDateTime>,
+    pub claims_hash: Option<String>,
+    pub parent_id: Option<String>,
+    /* The token a reference token stands for, which mustn't leak through the tokens API */
+    #[serde(skip)]
+    pub full_token: Option<String>,
+    pub last_used_ip: Option<String>,
+    pub use_count: i64,
+    /* When a single-use token was used */
+    pub consumed_at: Option<chrono::NaiveDateTime>,
 }
 
 #[derive(Insertable, Debug)]
@@ -380,6 +750,18 @@ pub struct NewToken {
     pub token_id: String,
     pub expires: chrono::NaiveDateTime,
     pub last_used: chrono::NaiveDateTime,
+    pub last_used_ip: Option<String>,
+    pub use_count: i64,
+    pub consumed_at: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = tokens)]
+pub struct NewMintedToken {
+    pub token_id: String,
+    pub expires: chrono::NaiveDateTime,
+    pub claims_hash: String,
+    pub parent_id: Option<String>,
 }
 
 #[derive(Insertable, Debug)]
@@ -388,3 +770,287 @@ pub struct NewRevokedToken {
     pub token_id: String,
     pub revoked_at: chrono::NaiveDateTime,
 }
+
+/// How much of its budgets a token has used, see the byte_budget and build_budget claims.
+#[derive(Queryable, Insertable, Debug, Default, Serialize)]
+#[diesel(table_name = token_usage)]
+pub struct TokenUsage {
+    pub token_id: String,
+    pub uploaded_bytes: i64,
+    pub created_builds: i64,
+}
+
+/// A state-changing API call, see the audit module. The outcome is the HTTP status of the response.
+#[derive(Queryable, Debug, Serialize)]
+pub struct AuditEntry {
+    pub id: i32,
+    pub created_at: chrono::NaiveDateTime,
+    pub token_sub: Option<String>,
+    pub token_jti: Option<String>,
+    pub scopes: Vec<String>,
+    pub client_ip: Option<String>,
+    pub action: String,
+    pub path: String,
+    pub outcome: i16,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = audit_log)]
+pub struct NewAuditEntry {
+    pub token_sub: Option<String>,
+    pub token_jti: Option<String>,
+    pub scopes: Vec<String>,
+    pub client_ip: Option<String>,
+    pub action: String,
+    pub path: String,
+    pub outcome: i16,
+}
+
+/// Builds with an app ID are only listed when asked for, as they weren't before there were app IDs.
+#[derive(Debug, Default)]
+pub enum AppIdFilter {
+    #[default]
+    None,
+    Is(String),
+    Any,
+}
+
+/// Which builds to list. Every given field has to match, and purged builds are left out unless asked for.
+#[derive(Debug, Default)]
+pub struct BuildFilter {
+    pub repo: Option<String>,
+    pub app_id: AppIdFilter,
+    pub repo_state: Option<i16>,
+    pub published_state: Option<i16>,
+    pub created_since: Option<chrono::NaiveDateTime>,
+    pub created_until: Option<chrono::NaiveDateTime>,
+    pub token_name: Option<String>,
+    pub tag: Option<(String, Option<String>)>,
+    /* Only builds with a higher ID, for paging */
+    pub after: Option<i32>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Default)]
+pub struct JobFilter {
+    pub statuses: Option<Vec<i16>>,
+    pub kind: Option<i16>,
+    /* Only jobs with a higher ID, for paging */
+    pub after: Option<i32>,
+    pub limit: Option<i64>,
+}
+
+/// The builds counted against a storage quota, and the bytes uploaded to them.
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub builds: u64,
+    pub bytes: u64,
+}
+
+/// Which audit log entries to list, newest first. Every given field has to match.
+#[derive(Deserialize, Debug, Default)]
+pub struct AuditLogFilter {
+    pub sub: Option<String>,
+    pub jti: Option<String>,
+    pub action: Option<String>,
+    pub path: Option<String>,
+    pub since: Option<chrono::NaiveDateTime>,
+    pub until: Option<chrono::NaiveDateTime>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Queryable, Insertable, Debug, Serialize)]
+#[diesel(table_name = revoked_prefixes)]
+pub struct RevokedPrefix {
+    pub prefix: String,
+    pub revoked_at: chrono::NaiveDateTime,
+}
+
+/// A label on a build, such as the CI pipeline that made it.
+#[derive(Queryable, Insertable, Debug, Serialize)]
+#[diesel(table_name = build_tags)]
+pub struct BuildTag {
+    pub build_id: i32,
+    pub key: String,
+    pub value: String,
+}
+
+/// A sign-off on a build, given by a token with the approve scope.
+#[derive(Queryable, Insertable, Debug, Serialize)]
+#[diesel(table_name = build_approvals)]
+pub struct BuildApproval {
+    pub build_id: i32,
+    pub approver: String,
+    pub comment: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+/// A free-text note on a build, for example from a reviewer or a check bot.
+#[derive(Queryable, Debug, Serialize)]
+pub struct BuildComment {
+    pub id: i32,
+    pub build_id: i32,
+    pub author: String,
+    pub body: String,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = build_comments)]
+pub struct NewBuildComment {
+    pub build_id: i32,
+    pub author: String,
+    pub body: String,
+}
+
+/// Tokens with the owner group in their groups claim may use app IDs inside the prefix.
+#[derive(Queryable, Insertable, Debug, Serialize)]
+#[diesel(table_name = prefix_owners)]
+pub struct PrefixOwner {
+    pub prefix: String,
+    pub owner_group: String,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+/// Tokens with a sub inside the sub prefix that were issued before the revocation time are revoked.
+#[derive(Queryable, Insertable, Debug, Serialize)]
+#[diesel(table_name = revoked_subs)]
+pub struct RevokedSub {
+    pub sub_prefix: String,
+    pub revoked_at: chrono::NaiveDateTime,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed_build_actions() {
+        use BuildAction::*;
+
+        let unpublished = PublishedState::Unpublished;
+        assert_eq!(
+            allowed_build_actions(&RepoState::Uploading, &unpublished, false),
+            vec![Commit, Purge]
+        );
+        assert_eq!(
+            allowed_build_actions(&RepoState::Committing, &unpublished, false),
+            vec![]
+        );
+        assert_eq!(
+            allowed_build_actions(&RepoState::Validating, &unpublished, false),
+            vec![]
+        );
+        assert_eq!(
+            allowed_build_actions(&RepoState::AwaitingApproval, &unpublished, false),
+            vec![Approve, Purge]
+        );
+        assert_eq!(
+            allowed_build_actions(&RepoState::Ready, &unpublished, false),
+            vec![Publish, Purge]
+        );
+        assert_eq!(
+            allowed_build_actions(&RepoState::Failed("oops".to_string()), &unpublished, false),
+            vec![Purge]
+        );
+        assert_eq!(
+            allowed_build_actions(&RepoState::Purged, &unpublished, false),
+            vec![]
+        );
+
+        assert_eq!(
+            allowed_build_actions(&RepoState::Ready, &PublishedState::Publishing, false),
+            vec![]
+        );
+        assert_eq!(
+            allowed_build_actions(&RepoState::Ready, &PublishedState::Published, false),
+            vec![Purge]
+        );
+        assert_eq!(
+            allowed_build_actions(&RepoState::Ready, &PublishedState::Published, true),
+            vec![Publish, Purge]
+        );
+        assert_eq!(
+            allowed_build_actions(
+                &RepoState::Ready,
+                &PublishedState::Failed("oops".to_string()),
+                false
+            ),
+            vec![Purge]
+        );
+    }
+
+    #[test]
+    fn test_job_kind_dependencies() {
+        assert_eq!(JobKind::Republish.dependencies(), &[JobKind::UpdateRepo]);
+        assert_eq!(JobKind::PruneDeltas.dependencies(), &[JobKind::UpdateRepo]);
+        for kind in [
+            JobKind::Commit,
+            JobKind::Publish,
+            JobKind::UpdateRepo,
+            JobKind::Check,
+        ] {
+            assert!(kind.dependencies().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_job_outcome() {
+        let outcome = JobOutcome {
+            status: JobStatus::New as i16,
+            results: r#"{"error-message":"Connection reset"}"#.to_string(),
+            retry_after: Some(time::UNIX_EPOCH + time::Duration::from_secs(1_792_000_000)),
+            usage: JobUsage {
+                wall_time_ms: 1200,
+                cpu_time_ms: 800,
+                peak_rss_kb: 51200,
+                bytes_written: 4096,
+            },
+        };
+        let json = serde_json::to_string(&outcome).unwrap();
+        assert!(json.contains(r#""usage":{"wall-time-ms":1200,"cpu-time-ms":800,"#));
+        assert_eq!(serde_json::from_str::<JobOutcome>(&json).unwrap(), outcome);
+    }
+
+    #[test]
+    fn test_job_progress() {
+        let progress = JobProgress::new(Some("deltas"), 3, 12);
+        assert_eq!(progress.percent, 25);
+        assert_eq!(
+            serde_json::to_string(&progress).unwrap(),
+            r#"{"phase":"deltas","done":3,"total":12,"percent":25}"#
+        );
+        assert_eq!(JobProgress::new(None, 0, 0).percent, 100);
+        assert_eq!(JobProgress::new(None, 5, 4).percent, 100);
+        assert_eq!(JobProgress::new(None, 2, 3).percent, 66);
+    }
+
+    #[test]
+    fn test_job_usage_stats() {
+        let mut stats = JobUsageStats::default();
+        stats.add(&JobUsage {
+            wall_time_ms: 1000,
+            cpu_time_ms: 300,
+            peak_rss_kb: 2048,
+            bytes_written: 4096,
+        });
+        stats.add(&JobUsage {
+            wall_time_ms: 500,
+            cpu_time_ms: 400,
+            peak_rss_kb: 1024,
+            bytes_written: 0,
+        });
+        assert_eq!(
+            stats,
+            JobUsageStats {
+                jobs: 2,
+                total_wall_time_ms: 1500,
+                max_wall_time_ms: 1000,
+                total_cpu_time_ms: 700,
+                max_cpu_time_ms: 400,
+                max_peak_rss_kb: 2048,
+                total_bytes_written: 4096,
+            }
+        );
+    }
+}
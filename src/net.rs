@@ -0,0 +1,254 @@
+use actix_web::http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// An IP network in CIDR notation, such as "10.0.0.0/8" or "fd00::/8". A bare address is a network containing just
+/// that address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        /* Peers connecting over IPv6 sockets show up as IPv4-mapped addresses */
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(*ip),
+            IpAddr::V4(_) => *ip,
+        };
+
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<IpNet, String> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| format!("Invalid address in network '{s}'"))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| format!("Invalid prefix length in network '{s}'"))?,
+            None => max_len,
+        };
+
+        Ok(IpNet { addr, prefix_len })
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl Serialize for IpNet {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpNet {
+    fn deserialize<D>(deserializer: D) -> Result<IpNet, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+        String::deserialize(deserializer).and_then(|s| s.parse().map_err(Error::custom))
+    }
+}
+
+pub fn is_trusted_proxy(ip: &IpAddr, trusted_proxies: &[IpNet]) -> bool {
+    trusted_proxies.iter().any(|net| net.contains(ip))
+}
+
+/* Only this many X-Forwarded-For hops are looked at, so a forged chain can't be made arbitrarily
+ * long to cost us time */
+const MAX_FORWARDED_HOPS: usize = 16;
+
+/// Finds the real client address given the peer address and the X-Forwarded-For chain. Each hop in the chain was
+/// added by the hop after it, so the chain is walked from the right and only for as long as the address it came
+/// from is a trusted proxy; anything further left could have been made up by the client. With no trusted proxies,
+/// the header is ignored entirely.
+pub fn client_ip(
+    peer: Option<IpAddr>,
+    forwarded_for: &[&str],
+    trusted_proxies: &[IpNet],
+) -> Option<IpAddr> {
+    let mut client = peer?;
+
+    let hops = forwarded_for
+        .iter()
+        .rev()
+        .flat_map(|header| header.rsplit(','))
+        .map(str::trim)
+        .take(MAX_FORWARDED_HOPS);
+    for hop in hops {
+        if !is_trusted_proxy(&client, trusted_proxies) {
+            break;
+        }
+        match hop.parse::<IpAddr>() {
+            Ok(ip) => client = ip,
+            Err(_) => {
+                log::warn!("Ignoring malformed X-Forwarded-For hop from trusted proxy {client}");
+                break;
+            }
+        }
+    }
+
+    Some(client)
+}
+
+/// The real client address of a request, see client_ip().
+pub fn request_client_ip(
+    peer_addr: Option<SocketAddr>,
+    headers: &HeaderMap,
+    trusted_proxies: &[IpNet],
+) -> Option<IpAddr> {
+    let forwarded_for: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    client_ip(
+        peer_addr.map(|addr| addr.ip()),
+        &forwarded_for,
+        trusted_proxies,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_ipnet_contains() {
+        let net: IpNet = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(&ip("10.1.2.3")));
+        assert!(!net.contains(&ip("10.2.0.1")));
+        assert!(net.contains(&ip("::ffff:10.1.0.1")));
+        assert!(!net.contains(&ip("fd00::1")));
+
+        let net: IpNet = "fd00::/8".parse().unwrap();
+        assert!(net.contains(&ip("fd12::1")));
+        assert!(!net.contains(&ip("fe80::1")));
+
+        let single: IpNet = "127.0.0.1".parse().unwrap();
+        assert!(single.contains(&ip("127.0.0.1")));
+        assert!(!single.contains(&ip("127.0.0.2")));
+
+        let all: IpNet = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(&ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+
+        /* Client -> proxy 10.0.0.5 -> proxy 10.0.0.2 -> us */
+        assert_eq!(
+            client_ip(Some(ip("10.0.0.2")), &["198.51.100.7, 10.0.0.5"], &trusted),
+            Some(ip("198.51.100.7"))
+        );
+
+        /* The same chain split over several headers */
+        assert_eq!(
+            client_ip(
+                Some(ip("10.0.0.2")),
+                &["198.51.100.7", "10.0.0.5"],
+                &trusted
+            ),
+            Some(ip("198.51.100.7"))
+        );
+
+        /* The client prepended a fake hop, which sits left of its real address and is ignored */
+        assert_eq!(
+            client_ip(
+                Some(ip("10.0.0.2")),
+                &["10.0.0.9, 1.2.3.4, 198.51.100.7"],
+                &trusted
+            ),
+            Some(ip("198.51.100.7"))
+        );
+
+        /* Headers sent directly by an untrusted peer are ignored */
+        assert_eq!(
+            client_ip(Some(ip("198.51.100.7")), &["1.2.3.4"], &trusted),
+            Some(ip("198.51.100.7"))
+        );
+        assert_eq!(
+            client_ip(Some(ip("10.0.0.2")), &["1.2.3.4"], &[]),
+            Some(ip("10.0.0.2"))
+        );
+
+        /* Garbage stops the walk at the last proxy */
+        assert_eq!(
+            client_ip(Some(ip("10.0.0.2")), &["1.2.3.4, nonsense"], &trusted),
+            Some(ip("10.0.0.2"))
+        );
+
+        /* Overlong chains are cut off */
+        let long_chain = vec!["10.0.0.1"; 100].join(",");
+        assert_eq!(
+            client_ip(
+                Some(ip("10.0.0.2")),
+                &[&format!("1.2.3.4,{long_chain}")],
+                &trusted
+            ),
+            Some(ip("10.0.0.1"))
+        );
+
+        assert_eq!(client_ip(None, &["1.2.3.4"], &trusted), None);
+    }
+
+    #[test]
+    fn test_ipnet_display() {
+        let net: IpNet = "10.1.0.0/16".parse().unwrap();
+        assert_eq!(net.to_string(), "10.1.0.0/16");
+        let single: IpNet = "fd00::1".parse().unwrap();
+        assert_eq!(single.to_string(), "fd00::1/128");
+        assert_eq!(single.to_string().parse::<IpNet>().unwrap(), single);
+    }
+
+    #[test]
+    fn test_ipnet_parse_errors() {
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("::/129".parse::<IpNet>().is_err());
+        assert!("10.0.0/8".parse::<IpNet>().is_err());
+        assert!("10.0.0.0/x".parse::<IpNet>().is_err());
+    }
+}
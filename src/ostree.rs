@@ -4,6 +4,7 @@ use futures::future;
 use futures::future::Either;
 use futures::Future;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::io::{Read, Write};
 use std::num::NonZeroUsize;
 use std::os::unix::process::CommandExt as UnixCommandExt;
@@ -50,6 +51,14 @@ pub struct OstreeCommit {
     pub root_metadata: String,
 }
 
+#[derive(Debug, Default)]
+pub struct OstreeDirTree {
+    /// The files in the directory, as (name, content checksum)
+    pub files: Vec<(String, String)>,
+    /// The subdirectories, as (name, dirtree checksum, dirmeta checksum)
+    pub dirs: Vec<(String, String, String)>,
+}
+
 #[derive(Debug)]
 pub struct OstreeDeltaSuperblock {
     pub metadata: HashMap<String, Variant>,
@@ -645,6 +654,126 @@ pub fn get_commit(repo_path: &path::Path, commit: &str) -> OstreeResult<OstreeCo
     load_commit_file(&path)
 }
 
+/* Objects that are missing from a repo may be in its parent repo, which is where build and upload repos
+ * find everything that was already in the main repo */
+pub fn find_object_path(
+    repo_path: &path::Path,
+    object: &str,
+    object_type: &str,
+) -> Option<path::PathBuf> {
+    if object.len() < 3 || !object.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    [repo_path.to_path_buf(), repo_path.join("parent")]
+        .iter()
+        .map(|repo| get_object_path(repo, object, object_type))
+        .find(|path| path.is_file())
+}
+
+fn parse_dirtree(variant: &SubVariant) -> OstreeResult<OstreeDirTree> {
+    let dirtree_fields = vec![
+        // 0 - a(say) - files
+        VariantFieldInfo {
+            size: VariantSize::Variable,
+            alignment: 0,
+        },
+        // 1 - a(sayay) - subdirectories
+        VariantFieldInfo {
+            size: VariantSize::Variable,
+            alignment: 0,
+        },
+    ];
+    let file_fields = vec![
+        // 0 - s - name
+        VariantFieldInfo {
+            size: VariantSize::Variable,
+            alignment: 0,
+        },
+        // 1 - ay - content checksum
+        VariantFieldInfo {
+            size: VariantSize::Variable,
+            alignment: 0,
+        },
+    ];
+    let dir_fields = vec![
+        // 0 - s - name
+        VariantFieldInfo {
+            size: VariantSize::Variable,
+            alignment: 0,
+        },
+        // 1 - ay - dirtree checksum
+        VariantFieldInfo {
+            size: VariantSize::Variable,
+            alignment: 0,
+        },
+        // 2 - ay - dirmeta checksum
+        VariantFieldInfo {
+            size: VariantSize::Variable,
+            alignment: 0,
+        },
+    ];
+
+    let dirtree = variant.parse_as_tuple(&dirtree_fields)?;
+
+    let mut files = vec![];
+    for file in dirtree[0].parse_as_variable_width_array(0)? {
+        let file = file.parse_as_tuple(&file_fields)?;
+        files.push((
+            file[0].parse_as_string()?,
+            bytes_to_object(file[1].parse_as_bytes()),
+        ));
+    }
+
+    let mut dirs = vec![];
+    for dir in dirtree[1].parse_as_variable_width_array(0)? {
+        let dir = dir.parse_as_tuple(&dir_fields)?;
+        dirs.push((
+            dir[0].parse_as_string()?,
+            bytes_to_object(dir[1].parse_as_bytes()),
+            bytes_to_object(dir[2].parse_as_bytes()),
+        ));
+    }
+
+    Ok(OstreeDirTree { files, dirs })
+}
+
+pub fn get_dirtree(repo_path: &path::Path, dirtree: &str) -> OstreeResult<OstreeDirTree> {
+    let path = find_object_path(repo_path, dirtree, "dirtree")
+        .ok_or_else(|| OstreeError::NoSuchObject(format!("{dirtree}.dirtree")))?;
+    let contents = fs::read(&path).map_err(|_e| {
+        OstreeError::InternalError(format!("Invalid dirtree {}", get_dir_and_basename(&path)))
+    })?;
+
+    let variant = Variant::new("(a(say)a(sayay))".to_string(), contents)?;
+
+    parse_dirtree(&variant.root())
+}
+
+/// Lists all files in a commit, mapping their path to the checksum of their content object. The commit and its
+/// objects may be in the repo or its parent.
+pub fn list_commit_files(
+    repo_path: &path::Path,
+    commit: &str,
+) -> OstreeResult<BTreeMap<String, String>> {
+    let commit_path = find_object_path(repo_path, commit, "commit")
+        .ok_or_else(|| OstreeError::NoSuchCommit(commit.to_string()))?;
+    let commit = load_commit_file(&commit_path)?;
+
+    let mut files = BTreeMap::new();
+    let mut to_visit = vec![(String::new(), commit.root_tree)];
+    while let Some((dir_path, dirtree)) = to_visit.pop() {
+        let dirtree = get_dirtree(repo_path, &dirtree)?;
+        for (name, checksum) in dirtree.files {
+            files.insert(format!("{dir_path}{name}"), checksum);
+        }
+        for (name, checksum, _meta) in dirtree.dirs {
+            to_visit.push((format!("{dir_path}{name}/"), checksum));
+        }
+    }
+
+    Ok(files)
+}
+
 pub fn load_delta_superblock_file(path: &path::Path) -> OstreeResult<OstreeDeltaSuperblock> {
     let mut fp =
         fs::File::open(path).map_err(|_e| OstreeError::NoSuchObject(get_dir_and_basename(path)))?;
@@ -1071,6 +1200,42 @@ min-free-space-size=500MB
     Ok(())
 }
 
+/* Removes the file contents, dirtrees and deltas of the repos in a build dir, keeping the commit objects and
+ * the refs so that it still records what was built. The parent symlinks of the repos are not followed. */
+pub fn remove_content_objects(build_repo_path: &path::Path) -> io::Result<()> {
+    let mut to_remove = vec![];
+    for entry in WalkDir::new(build_repo_path) {
+        let entry = entry.map_err(io::Error::from)?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let path = entry.path();
+        /* The path inside the build repo or its upload repo */
+        let relative = path.strip_prefix(build_repo_path).unwrap_or(path);
+        let relative = relative.strip_prefix("upload").unwrap_or(relative);
+        let top_dir = relative
+            .components()
+            .next()
+            .and_then(|c| c.as_os_str().to_str());
+        let is_commit_metadata = matches!(
+            path.extension().and_then(|e| e.to_str()),
+            Some("commit") | Some("commitmeta")
+        );
+        let remove = match top_dir {
+            Some("objects") => !is_commit_metadata,
+            Some("deltas") | Some("tmp") => true,
+            _ => false,
+        };
+        if remove {
+            to_remove.push(path.to_path_buf());
+        }
+    }
+    for path in to_remove {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
@@ -1092,6 +1257,22 @@ mod tests {
         assert_eq!(type_string_split("a{vv}as"), None);
     }
 
+    #[test]
+    fn test_parse_dirtree() {
+        /* (a(say)a(sayay)) holding the file "a" and no subdirectories */
+        let mut file = b"a\0".to_vec();
+        file.extend_from_slice(&[0x11; 32]);
+        file.push(2);
+        let mut data = file.clone();
+        data.push(file.len() as u8);
+        data.push(data.len() as u8);
+
+        let variant = Variant::new("(a(say)a(sayay))".to_string(), data).unwrap();
+        let dirtree = parse_dirtree(&variant.root()).unwrap();
+        assert_eq!(dirtree.files, vec![("a".to_string(), "11".repeat(32))]);
+        assert!(dirtree.dirs.is_empty());
+    }
+
     #[test]
     fn test_delta_name() {
         assert_eq!(
@@ -1112,4 +1293,40 @@ mod tests {
         assert_eq!(Delta::from_name("OkiocD9GLq_Nt660BvWyrH8G62dAvtLv7RPqngWqf5c-3dpOrJG4MNyKHDDGXHpH_zd9NXugnexr5jpvSFQ77S4"),
                    Ok(Delta { from: Some("3a48a8703f462eafcdb7aeb406f5b2ac7f06eb6740bed2efed13ea9e05aa7f97".to_string()), to: "ddda4eac91b830dc8a1c30c65c7a47ff377d357ba09dec6be63a6f48543bed2e".to_string() }));
     }
+
+    #[test]
+    fn test_remove_content_objects() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let build_repo = dir.path();
+        let kept = [
+            "config",
+            "refs/heads/app/org.example.App/x86_64/stable",
+            "objects/11/22.commit",
+            "objects/11/22.commitmeta",
+            "upload/objects/33/44.commit",
+            "refs/heads/app/org.example.App/x86_64/tmp",
+        ];
+        let removed = [
+            "objects/11/33.dirtree",
+            "objects/11/44.filez",
+            "deltas/Ok/iocD9/superblock",
+            "tmp/cache/summaries/x",
+            "upload/objects/33/55.dirmeta",
+            "upload/deltas/.partial/44.filez",
+        ];
+        for name in kept.iter().chain(removed.iter()) {
+            let path = build_repo.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"x").unwrap();
+        }
+
+        remove_content_objects(build_repo).unwrap();
+
+        for name in kept {
+            assert!(build_repo.join(name).exists(), "{name} was removed");
+        }
+        for name in removed {
+            assert!(!build_repo.join(name).exists(), "{name} was kept");
+        }
+    }
 }
@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::errors::ApiError;
+
+/* Above this many tracked keys, stale windows are dropped before adding a new one */
+const MAX_IDLE_KEYS: usize = 10_000;
+
+/// A fixed-window request counter shared by all workers. Each key (usually a repo and token id) gets its own
+/// window, which starts at the first request made with it.
+pub struct RateLimiter {
+    window: Duration,
+    counters: Mutex<HashMap<String, (Instant, u32)>>,
+}
+
+impl RateLimiter {
+    pub fn new(window: Duration) -> RateLimiter {
+        RateLimiter {
+            window,
+            counters: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a request against `key`, failing with TooManyRequests once `limit` requests have already been made in
+    /// the current window.
+    pub fn check(&self, key: &str, limit: u32) -> Result<(), ApiError> {
+        let now = Instant::now();
+        let mut counters = self.counters.lock().unwrap();
+
+        if counters.len() > MAX_IDLE_KEYS {
+            let window = self.window;
+            counters.retain(|_, (started, _)| now.duration_since(*started) < window);
+        }
+
+        let (started, count) = counters.entry(key.to_string()).or_insert((now, 0));
+        if now.duration_since(*started) >= self.window {
+            *started = now;
+            *count = 0;
+        }
+
+        if *count >= limit {
+            let remaining = self.window.saturating_sub(now.duration_since(*started));
+            return Err(ApiError::TooManyRequests(
+                format!("Rate limit of {limit} requests exceeded"),
+                remaining.as_secs() + 1,
+            ));
+        }
+
+        *count += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(Duration::from_secs(60));
+
+        assert!(limiter.check("stable:a", 2).is_ok());
+        assert!(limiter.check("stable:a", 2).is_ok());
+        match limiter.check("stable:a", 2) {
+            Err(ApiError::TooManyRequests(_, retry_after)) => {
+                assert!(retry_after > 0 && retry_after <= 60)
+            }
+            _ => panic!("Expected the third request to be rate limited"),
+        }
+
+        /* Other keys have their own budget */
+        assert!(limiter.check("stable:b", 2).is_ok());
+        assert!(limiter.check("beta:a", 2).is_ok());
+    }
+
+    #[test]
+    fn test_rate_limiter_window_resets() {
+        let limiter = RateLimiter::new(Duration::from_secs(0));
+
+        assert!(limiter.check("key", 1).is_ok());
+        assert!(limiter.check("key", 1).is_ok());
+    }
+}
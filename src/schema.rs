@@ -1,5 +1,38 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    audit_log (id) {
+        id -> Int4,
+        created_at -> Timestamp,
+        token_sub -> Nullable<Text>,
+        token_jti -> Nullable<Text>,
+        scopes -> Array<Text>,
+        client_ip -> Nullable<Text>,
+        action -> Text,
+        path -> Text,
+        outcome -> Int2,
+    }
+}
+
+diesel::table! {
+    build_approvals (build_id, approver) {
+        build_id -> Int4,
+        approver -> Text,
+        comment -> Nullable<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    build_comments (id) {
+        id -> Int4,
+        build_id -> Int4,
+        author -> Text,
+        body -> Text,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     build_refs (id) {
         id -> Int4,
@@ -7,6 +40,15 @@ diesel::table! {
         ref_name -> Text,
         commit -> Text,
         build_log_url -> Nullable<Text>,
+        held -> Bool,
+    }
+}
+
+diesel::table! {
+    build_tags (build_id, key) {
+        build_id -> Int4,
+        key -> Text,
+        value -> Text,
     }
 }
 
@@ -28,6 +70,12 @@ diesel::table! {
         token_name -> Nullable<Text>,
         token_type -> Nullable<Text>,
         token_branches -> Nullable<Array<Text>>,
+        pinned -> Bool,
+        uploaded_bytes -> Int8,
+        required_approvals -> Int4,
+        import_job_id -> Nullable<Int4>,
+        token_id -> Nullable<Text>,
+        token_sub -> Nullable<Text>,
     }
 }
 
@@ -49,6 +97,36 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    job_log_records (id) {
+        id -> Int4,
+        job_id -> Int4,
+        created_at -> Timestamp,
+        level -> Text,
+        phase -> Nullable<Text>,
+        message -> Text,
+    }
+}
+
+diesel::table! {
+    job_schedules (repo, name) {
+        repo -> Text,
+        name -> Text,
+        next_run -> Timestamp,
+        last_job_id -> Nullable<Int4>,
+    }
+}
+
+diesel::table! {
+    job_workers (id) {
+        id -> Int4,
+        name -> Text,
+        kinds -> Array<Int2>,
+        registered_at -> Timestamp,
+        last_seen -> Timestamp,
+    }
+}
+
 diesel::table! {
     jobs (id) {
         id -> Int4,
@@ -59,6 +137,24 @@ diesel::table! {
         log -> Text,
         start_after -> Nullable<Timestamp>,
         repo -> Nullable<Text>,
+        priority -> Int4,
+        cancel_requested -> Bool,
+        attempts -> Int4,
+        progress -> Nullable<Text>,
+        finished_at -> Nullable<Timestamp>,
+        wall_time_ms -> Nullable<Int8>,
+        cpu_time_ms -> Nullable<Int8>,
+        peak_rss_kb -> Nullable<Int8>,
+        bytes_written -> Nullable<Int8>,
+        worker_id -> Nullable<Int4>,
+    }
+}
+
+diesel::table! {
+    prefix_owners (prefix) {
+        prefix -> Text,
+        owner_group -> Text,
+        updated_at -> Timestamp,
     }
 }
 
@@ -71,26 +167,71 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    revoked_prefixes (prefix) {
+        prefix -> Text,
+        revoked_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    revoked_subs (sub_prefix) {
+        sub_prefix -> Text,
+        revoked_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    token_usage (token_id) {
+        token_id -> Text,
+        uploaded_bytes -> Int8,
+        created_builds -> Int8,
+    }
+}
+
 diesel::table! {
     tokens (token_id) {
         token_id -> Text,
         expires -> Nullable<Timestamp>,
         last_used -> Nullable<Timestamp>,
         revoked_at -> Nullable<Timestamp>,
+        claims_hash -> Nullable<Text>,
+        parent_id -> Nullable<Text>,
+        full_token -> Nullable<Text>,
+        last_used_ip -> Nullable<Text>,
+        use_count -> Int8,
+        consumed_at -> Nullable<Timestamp>,
     }
 }
 
+diesel::joinable!(build_approvals -> builds (build_id));
+diesel::joinable!(build_comments -> builds (build_id));
 diesel::joinable!(build_refs -> builds (build_id));
+diesel::joinable!(build_tags -> builds (build_id));
 diesel::joinable!(checks -> builds (build_id));
 diesel::joinable!(checks -> jobs (job_id));
+diesel::joinable!(job_log_records -> jobs (job_id));
+diesel::joinable!(job_schedules -> jobs (last_job_id));
+diesel::joinable!(jobs -> job_workers (worker_id));
 diesel::joinable!(published_refs -> builds (build_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    audit_log,
+    build_approvals,
+    build_comments,
     build_refs,
+    build_tags,
     builds,
     checks,
     job_dependencies,
+    job_log_records,
+    job_schedules,
+    job_workers,
     jobs,
+    prefix_owners,
     published_refs,
+    revoked_prefixes,
+    revoked_subs,
+    token_usage,
     tokens,
 );
@@ -0,0 +1,118 @@
+//! Helpers for tests that need authenticated requests. Tokens minted here are signed and shaped exactly like real
+//! ones, so they go through the same validation as any other token.
+use chrono::Utc;
+
+pub use crate::tokens::{Claims, ClaimsScope};
+
+/// Signs `claims` with `secret`, the way the server expects its tokens.
+pub fn mint_test_token(secret: &[u8], claims: &Claims) -> String {
+    jwt::encode(
+        &jwt::Header::default(),
+        claims,
+        &jwt::EncodingKey::from_secret(secret),
+    )
+    .expect("Failed to encode test token")
+}
+
+/// Builds claims for a test token. By default the token is for "build", valid for an hour, and allows every repo
+/// and branch, but has no scopes.
+pub struct TestTokenBuilder {
+    claims: Claims,
+}
+
+impl Default for TestTokenBuilder {
+    fn default() -> Self {
+        TestTokenBuilder::new()
+    }
+}
+
+impl TestTokenBuilder {
+    pub fn new() -> TestTokenBuilder {
+        TestTokenBuilder {
+            claims: Claims {
+                name: Some("test".to_string()),
+                sub: "build".to_string(),
+                exp: Utc::now().timestamp() + 3600,
+                iat: Some(Utc::now().timestamp()),
+                jti: None,
+                ver: 0,
+                scope: vec![],
+                prefixes: vec![],
+                apps: vec![],
+                repos: vec!["".to_string()],
+                branches: vec![],
+                token_type: None,
+                max_upload_bytes: None,
+                max_requests_per_minute: None,
+                allowed_hours: vec![],
+                byte_budget: None,
+                build_budget: None,
+                jobs_metadata_only: false,
+                purpose: None,
+                allowed_ips: vec![],
+                single_use: false,
+                groups: vec![],
+            },
+        }
+    }
+
+    pub fn sub(mut self, sub: &str) -> Self {
+        self.claims.sub = sub.to_string();
+        self
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.claims.name = Some(name.to_string());
+        self
+    }
+
+    pub fn scope(mut self, scope: ClaimsScope) -> Self {
+        self.claims.scope.push(scope);
+        self
+    }
+
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.claims.prefixes.push(prefix.to_string());
+        self
+    }
+
+    pub fn app(mut self, app: &str) -> Self {
+        self.claims.apps.push(app.to_string());
+        self
+    }
+
+    /// Limits the token to the given repos, replacing the default of all repos.
+    pub fn repos(mut self, repos: &[&str]) -> Self {
+        self.claims.repos = repos.iter().map(|repo| repo.to_string()).collect();
+        self
+    }
+
+    pub fn branch(mut self, branch: &str) -> Self {
+        self.claims.branches.push(branch.to_string());
+        self
+    }
+
+    pub fn jti(mut self, jti: &str) -> Self {
+        self.claims.jti = Some(jti.to_string());
+        self
+    }
+
+    pub fn ver(mut self, ver: u32) -> Self {
+        self.claims.ver = ver;
+        self
+    }
+
+    /// Sets the expiry, as a UNIX timestamp. Use a past time to test expired tokens.
+    pub fn exp(mut self, exp: i64) -> Self {
+        self.claims.exp = exp;
+        self
+    }
+
+    pub fn claims(self) -> Claims {
+        self.claims
+    }
+
+    pub fn mint(self, secret: &[u8]) -> String {
+        mint_test_token(secret, &self.claims)
+    }
+}